@@ -0,0 +1,277 @@
+//! Terminal editor for joining Rustpad documents without a browser.
+
+use std::io::{self, Write};
+use std::panic;
+
+use anyhow::Result;
+use clap::Parser;
+use crossterm::event::{Event as TermEvent, EventStream};
+use crossterm::style::{Attribute, Color, Print, SetAttribute, SetBackgroundColor};
+use crossterm::style::{ResetColor, SetForegroundColor};
+use crossterm::{cursor, execute, queue, terminal};
+use futures::prelude::*;
+use rand::Rng;
+use rustpad_client::editor::{char_width, Editor};
+use rustpad_client::{socket_url, Client};
+use rustpad_core::protocol::{CursorData, UserInfo};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Edit a Rustpad document in the terminal.
+#[derive(Parser)]
+#[command(name = "rustpad-tui", version)]
+struct Args {
+    /// Base URL of the Rustpad server.
+    #[arg(long, env = "RUSTPAD_SERVER", default_value = "http://localhost:3030")]
+    server: String,
+
+    /// Name displayed to other users.
+    #[arg(long, env = "USER", default_value = "Anonymous Terminal")]
+    name: String,
+
+    /// Hue of this user's cursor, from 0 to 359 (random by default).
+    #[arg(long)]
+    hue: Option<u32>,
+
+    /// ID of the document.
+    id: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let client = Client::connect(&socket_url(&args.server, &args.id)?).await?;
+    client.set_info(UserInfo {
+        name: args.name,
+        hue: args
+            .hue
+            .unwrap_or_else(|| rand::thread_rng().gen_range(0..360)),
+    });
+
+    // Put the terminal back the way it was before printing a panic message,
+    // or the message would be lost along with the alternate screen.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_terminal().ok();
+        hook(info);
+    }));
+
+    terminal::enable_raw_mode()?;
+    execute!(io::stdout(), terminal::EnterAlternateScreen)?;
+    let mut view = View::new(client, args.id);
+    let result = view.run().await;
+    restore_terminal()?;
+    result
+}
+
+/// Leave the alternate screen and raw mode.
+fn restore_terminal() -> io::Result<()> {
+    execute!(io::stdout(), terminal::LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()
+}
+
+/// A terminal view of a document, layered on top of a connection to it.
+struct View {
+    client: Client,
+    editor: Editor,
+    id: String,
+    /// First line of the document that is visible.
+    scroll_row: usize,
+    /// First screen column of the document that is visible.
+    scroll_col: usize,
+}
+
+impl View {
+    fn new(client: Client, id: String) -> Self {
+        Self {
+            editor: Editor::new(&client),
+            client,
+            id,
+            scroll_row: 0,
+            scroll_col: 0,
+        }
+    }
+
+    /// Process terminal input and document updates until the user quits.
+    async fn run(&mut self) -> Result<()> {
+        let mut events = self.client.subscribe();
+        let mut input = EventStream::new();
+        self.render()?;
+        loop {
+            tokio::select! {
+                // The editor moves its cursor through remote edits by itself,
+                // so these only need to redraw the screen.
+                event = events.recv() => match event {
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                },
+                event = input.next() => match event {
+                    Some(Ok(TermEvent::Key(key))) => {
+                        let page = terminal::size()?.1.saturating_sub(1).max(1);
+                        if !self.editor.handle_key(&self.client, key, page.into())? {
+                            break;
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                    None => break,
+                },
+            }
+            self.render()?;
+        }
+        Ok(())
+    }
+
+    /// Draw the visible part of the document and a status bar.
+    fn render(&mut self) -> Result<()> {
+        self.editor.sync(&self.client);
+        let chars: Vec<char> = self.editor.text().chars().collect();
+        let lines = self.editor.lines();
+        let (width, height) = terminal::size()?;
+        let (width, rows) = (width as usize, height.saturating_sub(1) as usize);
+
+        // Scroll so that the cursor is always visible.
+        let (row, col) = self.editor.screen_position(self.editor.cursor());
+        let row = row as usize;
+        self.scroll_row = self
+            .scroll_row
+            .clamp(row.saturating_sub(rows.max(1) - 1), row);
+        self.scroll_col = self
+            .scroll_col
+            .clamp(col.saturating_sub(width.max(1) - 1), col);
+
+        let users = self.client.users();
+        let remote: Vec<(Color, CursorData)> = self
+            .client
+            .cursors()
+            .into_iter()
+            .filter_map(|(id, data)| Some((users.get(&id)?.hue, data)))
+            .map(|(hue, data)| (hsl(hue, 0.9, 0.3), data))
+            .collect();
+        let selection = self.editor.selection();
+
+        let mut stdout = io::stdout().lock();
+        queue!(stdout, cursor::Hide)?;
+        for screen_row in 0..rows {
+            queue!(stdout, cursor::MoveTo(0, screen_row as u16))?;
+            let line = (self.scroll_row + screen_row) as u32;
+            let (Some(start), end) = (lines.line_start(line), lines.offset(line, u32::MAX)) else {
+                queue!(stdout, terminal::Clear(terminal::ClearType::UntilNewLine))?;
+                continue;
+            };
+            // Screen column of the next character, from the start of the line.
+            let mut x = 0;
+            for index in start..=end {
+                let background = if selection.0 <= index && index < selection.1 {
+                    Some(Color::DarkGrey)
+                } else {
+                    remote_color(&remote, index)
+                };
+                let (ch, w) = match chars.get(index as usize) {
+                    Some('\n') | None if background.is_none() => break,
+                    Some('\n') | None => (' ', 1),
+                    Some(&c) => (c, char_width(c, x)),
+                };
+                // Part of the character that is on screen.
+                let (left, right) = (x.max(self.scroll_col), (x + w).min(self.scroll_col + width));
+                let fits = (left, right) == (x, x + w);
+                x += w;
+                if left >= self.scroll_col + width {
+                    break;
+                }
+                if right < left || (right == left && w > 0) {
+                    continue;
+                }
+                // Characters cut off at the edges of the screen, tabs and
+                // control characters are drawn as spaces.
+                let visible = if fits && ch != '\t' && !ch.is_control() {
+                    ch.to_string()
+                } else {
+                    " ".repeat(right - left)
+                };
+                match background {
+                    Some(color) => queue!(
+                        stdout,
+                        SetBackgroundColor(color),
+                        Print(visible),
+                        ResetColor
+                    )?,
+                    None => queue!(stdout, Print(visible))?,
+                }
+            }
+            queue!(stdout, terminal::Clear(terminal::ClearType::UntilNewLine))?;
+        }
+
+        // Status bar with the document ID and the names of other users.
+        queue!(
+            stdout,
+            cursor::MoveTo(0, rows as u16),
+            SetAttribute(Attribute::Reverse),
+            Print(format!(" {} ", self.id)),
+            SetAttribute(Attribute::Reset),
+        )?;
+        if !self.client.connected() {
+            match self.client.close_reason() {
+                Some(reason) => queue!(stdout, Print(format!(" disconnected: {}", reason)))?,
+                None => queue!(stdout, Print(" disconnected"))?,
+            }
+        }
+        let away = self.client.away();
+        let mut users: Vec<_> = users.into_iter().collect();
+        users.sort_by_key(|(id, _)| *id);
+        for (id, info) in users {
+            let lightness = if away.contains(&id) { 0.4 } else { 0.7 };
+            queue!(
+                stdout,
+                Print(" "),
+                SetForegroundColor(hsl(info.hue, 0.9, lightness)),
+                Print(&info.name),
+                ResetColor,
+            )?;
+        }
+        queue!(
+            stdout,
+            terminal::Clear(terminal::ClearType::UntilNewLine),
+            cursor::MoveTo(
+                (col - self.scroll_col) as u16,
+                (row - self.scroll_row) as u16
+            ),
+            cursor::Show,
+        )?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// Returns the highlight color of a position covered by another user.
+fn remote_color(remote: &[(Color, CursorData)], index: u32) -> Option<Color> {
+    remote.iter().find_map(|(color, data)| {
+        let covered = data.cursors.contains(&index)
+            || data
+                .selections
+                .iter()
+                .any(|&(start, end)| start <= index && index < end);
+        covered.then_some(*color)
+    })
+}
+
+/// Convert a color in HSL space to a terminal color.
+fn hsl(hue: u32, saturation: f32, lightness: f32) -> Color {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = (hue % 360) as f32 / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let scale = |v: f32| ((v + m) * 255.0).round() as u8;
+    Color::Rgb {
+        r: scale(r),
+        g: scale(g),
+        b: scale(b),
+    }
+}
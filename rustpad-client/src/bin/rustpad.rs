@@ -0,0 +1,204 @@
+//! Command-line tool for reading and editing documents on a Rustpad server.
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use hyper::client::HttpConnector;
+use hyper::{body, Body, Method, Request, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use rustpad_client::{
+    socket_url,
+    sync::{sync_file, Keep},
+    Client, Event,
+};
+use rustpad_core::protocol::UserOperation;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Work with Rustpad documents from the shell.
+#[derive(Parser)]
+#[command(name = "rustpad", version)]
+struct Args {
+    /// Base URL of the Rustpad server.
+    #[arg(
+        long,
+        env = "RUSTPAD_SERVER",
+        default_value = "http://localhost:3030",
+        global = true
+    )]
+    server: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the text of a document.
+    Cat {
+        /// ID of the document.
+        id: String,
+    },
+    /// Replace the text of a document with standard input, as a minimal edit.
+    Push {
+        /// ID of the document.
+        id: String,
+    },
+    /// Stream changes to a document, one JSON operation per line.
+    Watch {
+        /// ID of the document.
+        id: String,
+        /// Print the full text after each change, instead of operations.
+        #[arg(long)]
+        text: bool,
+    },
+    /// Keep a local file in sync with a document, in both directions.
+    Sync {
+        /// ID of the document.
+        id: String,
+        /// Path to the file, which is created if it does not exist.
+        file: PathBuf,
+        /// Which text to keep if the file exists and differs from the document.
+        #[arg(long, value_enum)]
+        keep: Option<Keep>,
+    },
+    /// Create a new document from standard input and print its URL.
+    New,
+    /// Set the syntax highlighting language of a document.
+    SetLanguage {
+        /// ID of the document.
+        id: String,
+        /// Name of the language, such as `rust` or `plaintext`.
+        language: String,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    pretty_env_logger::init();
+    let args = Args::parse();
+    let server = args.server.trim_end_matches('/');
+
+    match args.command {
+        Command::Cat { id } => {
+            let text = get_text(server, &id).await?;
+            io::stdout().write_all(text.as_bytes())?;
+        }
+        Command::Push { id } => {
+            let text = read_stdin()?;
+            let client = connect(server, &id).await?;
+            client.replace(&text)?;
+            client.flush().await?;
+        }
+        Command::Watch { id, text } => {
+            let client = connect(server, &id).await?;
+            let mut events = client.subscribe();
+            if text {
+                print_text(&client.text())?;
+            }
+            loop {
+                match events.recv().await {
+                    Ok(Event::Edit { .. }) if text => print_text(&client.text())?,
+                    Ok(Event::Edit { id, operation }) => {
+                        let line = serde_json::to_string(&UserOperation { id, operation })?;
+                        let mut stdout = io::stdout().lock();
+                        writeln!(stdout, "{}", line)?;
+                        stdout.flush()?;
+                    }
+                    Ok(Event::Disconnected) | Err(RecvError::Closed) => {
+                        match client.close_reason() {
+                            Some(reason) => bail!("disconnected from server: {}", reason),
+                            None => bail!("disconnected from server"),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => bail!("fell behind on document changes"),
+                }
+            }
+        }
+        Command::Sync { id, file, keep } => {
+            let client = connect(server, &id).await?;
+            sync_file(&client, &file, keep).await?;
+        }
+        Command::New => {
+            let id = new_text(server, read_stdin()?).await?;
+            println!("{}/#{}", server, id);
+        }
+        Command::SetLanguage { id, language } => {
+            let client = connect(server, &id).await?;
+            let mut events = client.subscribe();
+            client.set_language(&language);
+            // The server echoes language changes to every user, including us.
+            while client.language().as_deref() != Some(&language) {
+                if let Ok(Event::Disconnected) | Err(RecvError::Closed) = events.recv().await {
+                    bail!("disconnected before language was set");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read all of standard input as a string.
+fn read_stdin() -> Result<String> {
+    let mut text = String::new();
+    io::stdin()
+        .read_to_string(&mut text)
+        .context("failed to read standard input")?;
+    Ok(text)
+}
+
+/// Write a full snapshot of the document to standard output.
+fn print_text(text: &str) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(text.as_bytes())?;
+    if !text.ends_with('\n') {
+        writeln!(stdout)?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Connect to the WebSocket endpoint of a document.
+async fn connect(server: &str, id: &str) -> Result<Client> {
+    Client::connect(&socket_url(server, id)?).await
+}
+
+/// Returns an HTTP client for servers at both `http://` and `https://` URLs.
+fn http_client() -> hyper::Client<HttpsConnector<HttpConnector>> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    hyper::Client::builder().build(connector)
+}
+
+/// Fetch the text of a document from the `/api/text/{id}` endpoint.
+async fn get_text(server: &str, id: &str) -> Result<String> {
+    let uri = format!("{}/api/text/{}", server, id);
+    let resp = http_client().get(uri.parse()?).await?;
+    if resp.status() != StatusCode::OK {
+        bail!("server responded with status {}", resp.status());
+    }
+    let bytes = body::to_bytes(resp.into_body()).await?;
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+/// Create a document through the `/api/text` endpoint, which picks an unused
+/// ID so that existing documents are never overwritten.
+async fn new_text(server: &str, text: String) -> Result<String> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("{}/api/text", server))
+        .body(Body::from(text))?;
+    let resp = http_client().request(req).await?;
+    if resp.status() != StatusCode::OK {
+        bail!("server responded with status {}", resp.status());
+    }
+    let bytes = body::to_bytes(resp.into_body()).await?;
+    let created: serde_json::Value = serde_json::from_slice(&bytes)?;
+    let id = created["id"].as_str().context("missing id in response")?;
+    Ok(id.into())
+}
@@ -0,0 +1,251 @@
+//! Cursor and selection handling for interactive editors built on a client,
+//! such as the `rustpad-tui` terminal editor.
+
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use operational_transform::OperationSeq;
+use rustpad_core::lines::LineIndex;
+use rustpad_core::ot::{diff, transform_index_with_affinity};
+use rustpad_core::protocol::{Affinity, CursorData};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use unicode_width::UnicodeWidthChar;
+
+use crate::{Client, Event};
+
+/// Number of columns between tab stops.
+pub const TAB_WIDTH: usize = 4;
+
+/// A user's cursor and selection in a document.
+///
+/// This keeps its own copy of the text that the cursor refers to, and moves
+/// the cursor through each remote edit before reading the text of the client
+/// again, so that keys are always handled at the position the user sees.
+pub struct Editor {
+    /// Character index of the cursor.
+    cursor: u32,
+    /// Character index of the other end of the selection.
+    anchor: u32,
+    /// Text of the document that the cursor and anchor refer to.
+    text: String,
+    /// Line breaks in `text`.
+    lines: LineIndex,
+    /// Edits to the document that have not yet been applied to `text`.
+    events: broadcast::Receiver<Event>,
+}
+
+impl Editor {
+    /// Start editing a document, with the cursor at its start.
+    pub fn new(client: &Client) -> Self {
+        let (text, events) = client.read_with(|text| (text.to_owned(), client.subscribe()));
+        Self {
+            cursor: 0,
+            anchor: 0,
+            lines: LineIndex::new(&text),
+            text,
+            events,
+        }
+    }
+
+    /// Returns the character index of the cursor.
+    pub fn cursor(&self) -> u32 {
+        self.cursor
+    }
+
+    /// Returns the start and end of the selection, which may be empty.
+    pub fn selection(&self) -> (u32, u32) {
+        (self.cursor.min(self.anchor), self.cursor.max(self.anchor))
+    }
+
+    /// Returns the text of the document, as of the last call to
+    /// [`Editor::sync`] or [`Editor::handle_key`].
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the line breaks in the text.
+    pub fn lines(&self) -> &LineIndex {
+        &self.lines
+    }
+
+    /// Returns the line of a character index, and its column on screen.
+    pub fn screen_position(&self, index: u32) -> (u32, usize) {
+        let (line, column) = self.lines.position(index);
+        let start = self.lines.line_start(line).unwrap_or_default();
+        let prefix = self.text.chars().skip(start as usize).take(column as usize);
+        (line, prefix.fold(0, |x, c| x + char_width(c, x)))
+    }
+
+    /// Bring the text and cursor up to date with the client.
+    pub fn sync(&mut self, client: &Client) {
+        client.read_with(|text| self.catch_up(text));
+    }
+
+    /// Handle a key press, returning `false` if the editor should exit.
+    pub fn handle_key(&mut self, client: &Client, key: KeyEvent, page: u32) -> Result<bool> {
+        // Some terminals, such as the one on Windows, also report key releases.
+        if key.kind == KeyEventKind::Release {
+            return Ok(true);
+        }
+        let mut running = true;
+        let mut moved = false;
+        client.edit_with(|text| {
+            self.catch_up(text);
+            let before = (self.cursor, self.anchor);
+            let operation = match self.edit_for_key(key, page) {
+                Some(operation) => operation,
+                None => {
+                    running = false;
+                    OperationSeq::default()
+                }
+            };
+            if !operation.is_noop() {
+                self.text = operation.apply(&self.text)?;
+                self.lines.apply(&operation);
+            }
+            moved = (self.cursor, self.anchor) != before;
+            Ok(operation)
+        })?;
+
+        if moved {
+            let (start, end) = self.selection();
+            let mut data = CursorData {
+                cursors: vec![self.cursor],
+                ..Default::default()
+            };
+            if start < end {
+                // Keep the selection from growing when others type at its edges.
+                data.selections.push((start, end));
+                data.selection_affinities
+                    .push((Affinity::Right, Affinity::Left));
+            }
+            client.set_cursors(data);
+        }
+        Ok(running)
+    }
+
+    /// Move the cursor through edits received since the text was last read,
+    /// so that it refers to `current`, the text of the client.
+    fn catch_up(&mut self, current: &str) {
+        // Once an edit is missed, the rest are skipped and the texts are
+        // compared instead.
+        let mut missed = false;
+        loop {
+            match self.events.try_recv() {
+                Ok(Event::Edit { operation, .. }) if !missed => match operation.apply(&self.text) {
+                    Ok(text) => {
+                        self.transform(&operation);
+                        self.text = text;
+                        self.lines.apply(&operation);
+                    }
+                    Err(_) => missed = true,
+                },
+                Ok(_) => {}
+                Err(TryRecvError::Lagged(_)) => missed = true,
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+        if self.text != current {
+            self.transform(&diff(&self.text, current));
+            self.text = current.to_owned();
+            self.lines = LineIndex::new(current);
+        }
+    }
+
+    /// Move the cursor and selection through an edit to the text.
+    fn transform(&mut self, operation: &OperationSeq) {
+        // Keep the selection from growing when text is inserted at its edges.
+        let affinities = match self.cursor.cmp(&self.anchor) {
+            Ordering::Less => (Affinity::Right, Affinity::Left),
+            Ordering::Equal => (Affinity::Right, Affinity::Right),
+            Ordering::Greater => (Affinity::Left, Affinity::Right),
+        };
+        self.cursor = transform_index_with_affinity(operation, self.cursor, affinities.0);
+        self.anchor = transform_index_with_affinity(operation, self.anchor, affinities.1);
+    }
+
+    /// Returns the edit made by a key, after moving the cursor for it, or
+    /// `None` if the key exits the editor.
+    fn edit_for_key(&mut self, key: KeyEvent, page: u32) -> Option<OperationSeq> {
+        let len = self.lines.len_chars();
+        self.cursor = self.cursor.min(len);
+        self.anchor = self.anchor.min(len);
+        let cursor = self.cursor;
+        let (line, _) = self.lines.position(cursor);
+
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        match key.code {
+            KeyCode::Char('q' | 'c') if ctrl => return None,
+            KeyCode::Esc => return None,
+            KeyCode::Char('a') if ctrl => {
+                self.anchor = 0;
+                self.cursor = len;
+            }
+            KeyCode::Char(c) if !ctrl => return Some(self.replace(&c.to_string())),
+            KeyCode::Enter => return Some(self.replace("\n")),
+            KeyCode::Tab => return Some(self.replace("    ")),
+            KeyCode::Backspace if cursor == self.anchor && cursor > 0 => {
+                self.anchor = cursor - 1;
+                return Some(self.replace(""));
+            }
+            KeyCode::Delete if cursor == self.anchor && cursor < len => {
+                self.anchor = cursor + 1;
+                return Some(self.replace(""));
+            }
+            KeyCode::Backspace | KeyCode::Delete => return Some(self.replace("")),
+            KeyCode::Left => self.move_to(cursor.saturating_sub(1), shift),
+            KeyCode::Right => self.move_to((cursor + 1).min(len), shift),
+            KeyCode::Up => self.move_lines(-1, shift),
+            KeyCode::Down => self.move_lines(1, shift),
+            KeyCode::PageUp => self.move_lines(-(page as i64), shift),
+            KeyCode::PageDown => self.move_lines(page as i64, shift),
+            KeyCode::Home => self.move_to(self.lines.offset(line, 0), shift),
+            KeyCode::End => self.move_to(self.lines.offset(line, u32::MAX), shift),
+            _ => {}
+        }
+        Some(OperationSeq::default())
+    }
+
+    /// Returns an edit replacing the selection with some text, and moves the
+    /// cursor to the end of it.
+    fn replace(&mut self, text: &str) -> OperationSeq {
+        let (start, end) = self.selection();
+        let mut operation = OperationSeq::default();
+        operation.retain(start.into());
+        operation.delete((end - start).into());
+        operation.insert(text);
+        operation.retain((self.lines.len_chars() - end).into());
+        self.cursor = start + text.chars().count() as u32;
+        self.anchor = self.cursor;
+        operation
+    }
+
+    /// Move the cursor, optionally extending the selection.
+    fn move_to(&mut self, index: u32, extend: bool) {
+        self.cursor = index;
+        if !extend {
+            self.anchor = index;
+        }
+    }
+
+    /// Move the cursor up or down by some number of lines, keeping its column
+    /// where the line is long enough.
+    fn move_lines(&mut self, delta: i64, extend: bool) {
+        let (line, column) = self.lines.position(self.cursor);
+        let last = self.lines.len_lines() - 1;
+        let line = (line as i64 + delta).clamp(0, last as i64) as u32;
+        self.move_to(self.lines.offset(line, column), extend);
+    }
+}
+
+/// Returns the number of columns a character takes up on screen, if it starts
+/// at column `x`. Tabs reach the next tab stop, and control characters are
+/// shown as a single space.
+pub fn char_width(c: char, x: usize) -> usize {
+    match c {
+        '\t' => TAB_WIDTH - x % TAB_WIDTH,
+        c => c.width().unwrap_or(1),
+    }
+}
@@ -0,0 +1,508 @@
+//! Native client for the Rustpad collaborative text editor.
+//!
+//! This speaks the same WebSocket protocol as the browser frontend, including
+//! the client side of operational transformation, so that bots and other tools
+//! can read and edit documents programmatically.
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use futures::prelude::*;
+use log::warn;
+use operational_transform::OperationSeq;
+use parking_lot::Mutex;
+use rustpad_core::client::{Action, OtClient};
+use rustpad_core::ot::{diff, transform_cursors};
+use rustpad_core::protocol::{
+    ClientMsg, CloseReason, CursorData, Engine, ServerMsg, Status, UserCursor, UserInfo,
+    UserOperation,
+};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+pub mod editor;
+pub mod sync;
+
+/// An update received from the server, after it has been applied locally.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A remote edit was applied to the local text, transformed against any
+    /// local edits that the server has not yet acknowledged.
+    Edit {
+        /// ID of the user who made the edit.
+        id: u64,
+        /// The operation that was applied to the local text.
+        operation: OperationSeq,
+    },
+    /// The server acknowledged one of our own edits.
+    Ack,
+    /// The language of the document changed.
+    Language(String),
+    /// A user's information changed, or `None` if they disconnected.
+    UserInfo {
+        /// ID of the user.
+        id: u64,
+        /// Information about the user, if they are still connected.
+        info: Option<UserInfo>,
+    },
+    /// A user's cursor and selections moved.
+    UserCursor {
+        /// ID of the user.
+        id: u64,
+        /// Cursor and selection positions of the user.
+        data: CursorData,
+    },
+    /// A user became active or away.
+    UserStatus {
+        /// ID of the user.
+        id: u64,
+        /// Current status of the user.
+        status: Status,
+    },
+    /// The server has sent everything before our last `Sync` message.
+    Synced,
+    /// The server is closing the connection, for the given reason.
+    Closing(CloseReason),
+    /// The connection to the server was closed.
+    Disconnected,
+}
+
+/// A connection to a single document on a Rustpad server.
+pub struct Client {
+    /// State shared with the background connection task.
+    shared: Arc<Shared>,
+    /// Task that reads and writes messages on the WebSocket.
+    task: JoinHandle<()>,
+}
+
+/// Shared state between the client handle and its connection task.
+struct Shared {
+    /// Document and synchronization state, protected by a lock.
+    state: Mutex<State>,
+    /// Used to queue messages for sending to the server.
+    outgoing: mpsc::UnboundedSender<ClientMsg>,
+    /// Used to inform subscribers of updates from the server.
+    events: broadcast::Sender<Event>,
+}
+
+/// Client-side document state.
+#[derive(Default)]
+struct State {
+    ot: OtClient,
+    text: String,
+    language: Option<String>,
+    users: HashMap<u64, UserInfo>,
+    cursors: HashMap<u64, CursorData>,
+    away: HashSet<u64>,
+    connected: bool,
+    close_reason: Option<CloseReason>,
+}
+
+impl Client {
+    /// Connect to a document, given the URL of its WebSocket endpoint.
+    ///
+    /// This resolves after the initial state of the document has been received
+    /// from the server, so the text is immediately up to date.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (socket, _) = tokio_tungstenite::connect_async(url)
+            .await
+            .context("failed to connect to server")?;
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(256);
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                connected: true,
+                ..State::default()
+            }),
+            outgoing,
+            events,
+        });
+
+        // The server sends the full initial state before reading from the
+        // socket, so its reply to a sync marks the end of it.
+        let mut events = shared.events.subscribe();
+        shared.send(ClientMsg::Sync);
+        let task = tokio::spawn(Arc::clone(&shared).run(socket, outgoing_rx));
+        let client = Self { shared, task };
+        loop {
+            match events.recv().await {
+                Ok(Event::Synced) => break,
+                Ok(Event::Disconnected) | Err(broadcast::error::RecvError::Closed) => {
+                    bail!("disconnected before receiving initial state")
+                }
+                _ => {}
+            }
+        }
+        Ok(client)
+    }
+
+    /// Returns the unique ID of this connection.
+    pub fn id(&self) -> Option<u64> {
+        self.shared.state.lock().ot.id()
+    }
+
+    /// Returns the number of operations received from the server.
+    pub fn revision(&self) -> usize {
+        self.shared.state.lock().ot.revision()
+    }
+
+    /// Returns the current local text of the document.
+    pub fn text(&self) -> String {
+        self.shared.state.lock().text.clone()
+    }
+
+    /// Call a function with the current local text of the document.
+    ///
+    /// The text cannot change while `f` runs, and every [`Event::Edit`] that
+    /// led to it has already been sent to subscribers.
+    pub fn read_with<T>(&self, f: impl FnOnce(&str) -> T) -> T {
+        f(&self.shared.state.lock().text)
+    }
+
+    /// Returns the current language of the document.
+    pub fn language(&self) -> Option<String> {
+        self.shared.state.lock().language.clone()
+    }
+
+    /// Returns information about other users connected to the document.
+    pub fn users(&self) -> HashMap<u64, UserInfo> {
+        let state = self.shared.state.lock();
+        let mut users = state.users.clone();
+        users.retain(|id, _| Some(*id) != state.ot.id());
+        users
+    }
+
+    /// Returns the cursors of other users, relative to the local text.
+    pub fn cursors(&self) -> HashMap<u64, CursorData> {
+        let state = self.shared.state.lock();
+        let mut cursors = state.cursors.clone();
+        cursors.retain(|id, _| Some(*id) != state.ot.id());
+        cursors
+    }
+
+    /// Returns the IDs of other users who are away.
+    pub fn away(&self) -> HashSet<u64> {
+        let state = self.shared.state.lock();
+        let mut away = state.away.clone();
+        away.retain(|id| Some(*id) != state.ot.id());
+        away
+    }
+
+    /// Returns why the server closed the connection, if it gave a reason.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.shared.state.lock().close_reason
+    }
+
+    /// Returns if the connection to the server is still open.
+    pub fn connected(&self) -> bool {
+        self.shared.state.lock().connected
+    }
+
+    /// Returns if there are local edits not yet acknowledged by the server.
+    pub fn pending(&self) -> bool {
+        self.shared.state.lock().ot.outstanding().is_some()
+    }
+
+    /// Subscribe to updates from the server.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.shared.events.subscribe()
+    }
+
+    /// Apply a local edit to the document and send it to the server.
+    pub fn edit(&self, operation: OperationSeq) -> Result<()> {
+        self.edit_with(|_| Ok(operation))
+    }
+
+    /// Insert text at a position in the document.
+    pub fn insert(&self, index: u32, text: &str) -> Result<()> {
+        self.edit_with(|current| {
+            let mut operation = OperationSeq::default();
+            operation.retain(index.into());
+            operation.insert(text);
+            operation.retain(num_chars(current).saturating_sub(index.into()));
+            Ok(operation)
+        })
+    }
+
+    /// Delete a number of characters from a position in the document.
+    pub fn delete(&self, index: u32, count: u32) -> Result<()> {
+        self.edit_with(|current| {
+            let mut operation = OperationSeq::default();
+            let end = index
+                .checked_add(count)
+                .context("deleted range overflows")?;
+            operation.retain(index.into());
+            operation.delete(count.into());
+            operation.retain(num_chars(current).saturating_sub(end.into()));
+            Ok(operation)
+        })
+    }
+
+    /// Replace the text of the document, as a minimal edit.
+    pub fn replace(&self, text: &str) -> Result<()> {
+        self.edit_with(|current| Ok(diff(current, text)))
+    }
+
+    /// Apply changes made to an earlier version of the document.
+    ///
+    /// The edits from `base` to `text` are transformed against any changes
+    /// to the document since `base`, and the merged text is returned.
+    pub fn rebase(&self, base: &str, text: &str) -> Result<String> {
+        let mut merged = String::new();
+        self.edit_with(|current| {
+            let (operation, _) = diff(base, text).transform(&diff(base, current))?;
+            merged = operation.apply(current)?;
+            Ok(operation)
+        })?;
+        Ok(merged)
+    }
+
+    /// Apply a local edit constructed from the current text.
+    ///
+    /// As with [`Client::read_with`], the text cannot change while `f` runs,
+    /// so the edit is always made against the text that `f` sees. Edits that
+    /// change nothing succeed even after the client has disconnected.
+    pub fn edit_with(&self, f: impl FnOnce(&str) -> Result<OperationSeq>) -> Result<()> {
+        let mut state = self.shared.state.lock();
+        let operation = f(&state.text)?;
+        if operation.is_noop() {
+            return Ok(());
+        }
+        if !state.connected {
+            bail!("client is disconnected");
+        }
+        state.text = operation.apply(&state.text)?;
+        for data in state.cursors.values_mut() {
+            transform_cursors(&operation, data);
+        }
+        if let Some(msg) = state.ot.apply_client(operation)? {
+            self.shared.send(msg);
+        }
+        Ok(())
+    }
+
+    /// Set the language of the document.
+    pub fn set_language(&self, language: &str) {
+        self.shared.send(ClientMsg::SetLanguage(language.into()));
+    }
+
+    /// Set the information displayed to other users.
+    pub fn set_info(&self, info: UserInfo) {
+        self.shared.send(ClientMsg::ClientInfo(info));
+    }
+
+    /// Set the positions of this user's cursors and selections.
+    pub fn set_cursors(&self, data: CursorData) {
+        self.shared.send(ClientMsg::CursorData(data));
+    }
+
+    /// Wait until all local edits have been acknowledged by the server.
+    pub async fn flush(&self) -> Result<()> {
+        let mut events = self.subscribe();
+        while self.pending() {
+            match events.recv().await {
+                Ok(Event::Disconnected) | Err(broadcast::error::RecvError::Closed) => {
+                    bail!("disconnected with edits pending")
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Shared {
+    /// Queue a message to be sent to the server.
+    fn send(&self, msg: ClientMsg) {
+        // This only fails if the connection task has exited.
+        self.outgoing.send(msg).ok();
+    }
+
+    /// Publish an event to subscribers, if there are any.
+    fn publish(&self, event: Event) {
+        self.events.send(event).ok();
+    }
+
+    async fn run(
+        self: Arc<Self>,
+        socket: impl Stream<Item = tokio_tungstenite::tungstenite::Result<Message>>
+            + Sink<Message, Error = tokio_tungstenite::tungstenite::Error>,
+        mut outgoing: mpsc::UnboundedReceiver<ClientMsg>,
+    ) {
+        if let Err(e) = self.handle_connection(socket, &mut outgoing).await {
+            warn!("connection terminated early: {}", e);
+        }
+        self.state.lock().connected = false;
+        self.publish(Event::Disconnected);
+    }
+
+    async fn handle_connection(
+        &self,
+        socket: impl Stream<Item = tokio_tungstenite::tungstenite::Result<Message>>
+            + Sink<Message, Error = tokio_tungstenite::tungstenite::Error>,
+        outgoing: &mut mpsc::UnboundedReceiver<ClientMsg>,
+    ) -> Result<()> {
+        let (mut sink, mut stream) = socket.split();
+        loop {
+            tokio::select! {
+                msg = outgoing.recv() => match msg {
+                    Some(msg) => {
+                        let serialized = serde_json::to_string(&msg)?;
+                        sink.send(Message::Text(serialized)).await?;
+                    }
+                    None => break,
+                },
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        let msg = serde_json::from_str(&text)
+                            .context("failed to deserialize message")?;
+                        self.handle_message(msg)?;
+                    }
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => {} // Ignore non-text messages
+                    Some(Err(e)) => return Err(e.into()),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message(&self, msg: ServerMsg) -> Result<()> {
+        match msg {
+            ServerMsg::Identity {
+                id, epoch, engine, ..
+            } => {
+                if engine != Engine::Ot {
+                    bail!("documents that use the {} engine are not supported", engine);
+                }
+                let mut state = self.state.lock();
+                let actions = state.ot.apply_identity(id, epoch);
+                self.apply_actions(&mut state, actions)?;
+            }
+            ServerMsg::History { start, operations } => {
+                let mut state = self.state.lock();
+                let actions = state.ot.apply_history(start, operations)?;
+                self.apply_actions(&mut state, actions)?;
+            }
+            ServerMsg::Catchup {
+                start,
+                end,
+                operation,
+            } => {
+                let mut state = self.state.lock();
+                let actions = state.ot.apply_catchup(start, end, operation)?;
+                self.apply_actions(&mut state, actions)?;
+            }
+            ServerMsg::Checksum { revision, checksum } => {
+                let state = self.state.lock();
+                if let Some(msg) = state.ot.check(revision, checksum, &state.text) {
+                    warn!("text diverged from server at revision {}", revision);
+                    self.send(msg);
+                }
+            }
+            ServerMsg::Snapshot { revision, text } => {
+                let mut state = self.state.lock();
+                let actions = state.ot.apply_snapshot(revision, &text)?;
+                self.apply_actions(&mut state, actions)?;
+            }
+            ServerMsg::Language(language) => {
+                self.state.lock().language = Some(language.clone());
+                self.publish(Event::Language(language));
+            }
+            ServerMsg::UserInfo { id, info } => {
+                let mut state = self.state.lock();
+                match &info {
+                    Some(info) => {
+                        state.users.insert(id, info.clone());
+                    }
+                    None => {
+                        state.users.remove(&id);
+                        state.cursors.remove(&id);
+                        state.away.remove(&id);
+                    }
+                }
+                self.publish(Event::UserInfo { id, info });
+            }
+            ServerMsg::UserCursor { id, data } => {
+                self.state.lock().cursors.insert(id, data.clone());
+                self.publish(Event::UserCursor { id, data });
+            }
+            ServerMsg::UserCursors(cursors) => {
+                for UserCursor { id, data } in cursors {
+                    self.state.lock().cursors.insert(id, data.clone());
+                    self.publish(Event::UserCursor { id, data });
+                }
+            }
+            ServerMsg::UserStatus { id, status } => {
+                let mut state = self.state.lock();
+                match status {
+                    Status::Active => state.away.remove(&id),
+                    Status::Away => state.away.insert(id),
+                };
+                self.publish(Event::UserStatus { id, status });
+            }
+            ServerMsg::Synced => self.publish(Event::Synced),
+            ServerMsg::Closing { reason } => {
+                self.state.lock().close_reason = Some(reason);
+                self.publish(Event::Closing(reason));
+            }
+            ServerMsg::CrdtEdit { .. } => bail!("received CRDT edit in an OT document"),
+        }
+        Ok(())
+    }
+
+    /// Carry out the actions from processing history sent by the server.
+    fn apply_actions(&self, state: &mut State, actions: Vec<Action>) -> Result<()> {
+        for action in actions {
+            let (id, operation) = match action {
+                Action::Apply(UserOperation { id, operation }) => (id, operation),
+                Action::Reset => {
+                    // Clear the text, before it is rebuilt from the server.
+                    let mut operation = OperationSeq::default();
+                    operation.delete(num_chars(&state.text));
+                    (u64::MAX, operation)
+                }
+                Action::Ack => {
+                    self.publish(Event::Ack);
+                    continue;
+                }
+                Action::Send(msg) => {
+                    self.send(msg);
+                    continue;
+                }
+            };
+            state.text = operation.apply(&state.text)?;
+            for data in state.cursors.values_mut() {
+                transform_cursors(&operation, data);
+            }
+            self.publish(Event::Edit { id, operation });
+        }
+        Ok(())
+    }
+}
+
+/// Returns the WebSocket URL of a document, given the HTTP base URL of a server.
+pub fn socket_url(server: &str, id: &str) -> Result<String> {
+    let base = match server.trim_end_matches('/').split_once("://") {
+        Some(("http", rest)) => format!("ws://{}", rest),
+        Some(("https", rest)) => format!("wss://{}", rest),
+        _ => bail!("server URL must start with http:// or https://"),
+    };
+    Ok(format!("{}/api/socket/{}", base, id))
+}
+
+/// Returns the number of Unicode code points in a string.
+fn num_chars(text: &str) -> u64 {
+    text.chars().count() as u64
+}
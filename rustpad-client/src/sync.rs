@@ -0,0 +1,130 @@
+//! Bidirectional synchronization between a document and a local file.
+
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use log::info;
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio::{fs, time};
+
+use crate::{Client, Event};
+
+/// Which text to keep when a file and a document start out different.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Keep {
+    /// Replace the text of the document with the contents of the file.
+    Local,
+    /// Replace the contents of the file with the text of the document.
+    Remote,
+}
+
+/// Mirror a document to a file on disk, until the connection is closed.
+///
+/// Local changes to the file are sent to the server as minimal edits, and
+/// remote edits are written back to the file. When both sides change at once,
+/// the local changes are transformed against the remote ones before merging.
+///
+/// If the file already exists and is different from the document, `keep`
+/// decides which one is kept. It may be `None` if either one is empty, and
+/// otherwise this fails rather than lose text.
+pub async fn sync_file(client: &Client, path: &Path, keep: Option<Keep>) -> Result<()> {
+    let mut events = client.subscribe();
+
+    // Watch the parent directory, since editors often save files by renaming a
+    // temporary file over the original, which would end a watch on the file.
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().context("path is not a file")?.to_owned();
+    let (tx, mut changes) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            if event.paths.iter().any(|p| p.file_name() == Some(&name)) {
+                tx.send(()).ok();
+            }
+        }
+    })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    // Text of the document that was last written to or read from the file.
+    let remote = client.text();
+    let mut last = match fs::read_to_string(path).await {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            fs::write(path, &remote).await?;
+            remote
+        }
+        Err(e) => return Err(e.into()),
+        // Pretending the file was last synced with the document makes its
+        // differences into local changes, and the other way around.
+        Ok(local) => match keep {
+            _ if local == remote => remote,
+            Some(Keep::Local) => remote,
+            Some(Keep::Remote) => local,
+            None if remote.is_empty() => remote,
+            None if local.is_empty() => local,
+            None => bail!(
+                "{} and the document have different text, so one must be chosen to keep",
+                path.display()
+            ),
+        },
+    };
+    sync_once(client, path, &mut last).await?;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(Event::Edit { .. }) | Err(RecvError::Lagged(_)) => {}
+                Ok(Event::Disconnected) | Err(RecvError::Closed) => break,
+                Ok(_) => continue,
+            },
+            Some(()) = changes.recv() => {
+                // Wait for the write to settle, then skip redundant events.
+                time::sleep(Duration::from_millis(20)).await;
+                while changes.try_recv().is_ok() {}
+            }
+        }
+        sync_once(client, path, &mut last).await?;
+    }
+    match client.close_reason() {
+        Some(reason) => bail!("disconnected from server: {}", reason),
+        None => bail!("disconnected from server"),
+    }
+}
+
+/// Merge local changes from the file with remote changes from the document.
+async fn sync_once(client: &Client, path: &Path, last: &mut String) -> Result<()> {
+    loop {
+        let local = read_file(path, last).await?;
+        let merged = client.rebase(last, &local)?;
+        if merged == local {
+            *last = merged;
+            return Ok(());
+        }
+        // The file may have been saved again while merging, and writing over
+        // it would lose those changes. The document already has the changes
+        // that were read, so merge again from there.
+        if read_file(path, last).await? != local {
+            *last = local;
+            continue;
+        }
+        info!("writing remote changes to {}", path.display());
+        fs::write(path, &merged).await?;
+        *last = merged;
+        return Ok(());
+    }
+}
+
+/// Read the contents of the file, or `last` if it is missing.
+async fn read_file(path: &Path, last: &str) -> Result<String> {
+    match fs::read_to_string(path).await {
+        Ok(text) => Ok(text),
+        // The file may be briefly missing while an editor replaces it.
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(last.to_owned()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,118 @@
+//! Tests for the `rustpad` command-line tool.
+
+pub mod common;
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use common::spawn_server;
+use operational_transform::Operation;
+use rustpad_client::{socket_url, Client};
+use rustpad_core::protocol::UserOperation;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::time;
+
+/// Run the command-line tool with the given arguments and standard input.
+async fn rustpad(server: &str, args: &[&str], input: &str) -> Result<String> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rustpad"))
+        .arg("--server")
+        .arg(server)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().context("missing stdin")?;
+    stdin.write_all(input.as_bytes()).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    ensure!(output.status.success(), "command failed: {:?}", args);
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Start the command-line tool watching a document, returning the process
+/// and its output. The process is killed when it is dropped.
+fn watch(server: &str, args: &[&str]) -> Result<(Child, Lines<BufReader<ChildStdout>>)> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rustpad"))
+        .arg("--server")
+        .arg(server)
+        .arg("watch")
+        .args(args)
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = child.stdout.take().context("missing stdout")?;
+    Ok((child, BufReader::new(stdout).lines()))
+}
+
+#[tokio::test]
+async fn test_cli_new_cat_push() -> Result<()> {
+    let server = spawn_server();
+
+    let url = rustpad(&server, &["new"], "hello world\n").await?;
+    let id = url
+        .trim_end()
+        .strip_prefix(&format!("{}/#", server))
+        .context("unexpected document URL")?
+        .to_owned();
+    assert_eq!(id.len(), 6);
+    assert_eq!(rustpad(&server, &["cat", &id], "").await?, "hello world\n");
+
+    rustpad(&server, &["push", &id], "hello there, world\n").await?;
+    assert_eq!(
+        rustpad(&server, &["cat", &id], "").await?,
+        "hello there, world\n"
+    );
+
+    // The push should be a single edit on top of the initial text.
+    let client = Client::connect(&socket_url(&server, &id)?).await?;
+    assert_eq!(client.revision(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cli_set_language() -> Result<()> {
+    let server = spawn_server();
+
+    rustpad(&server, &["set-language", "foobar", "rust"], "").await?;
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    assert_eq!(client.language().as_deref(), Some("rust"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cli_watch() -> Result<()> {
+    let server = spawn_server();
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    client.insert(0, "hello")?;
+    client.flush().await?;
+
+    // With `--text`, the current text is printed as soon as it connects.
+    let (_watcher, mut text) = watch(&server, &["foobar", "--text"])?;
+    assert_eq!(text.next_line().await?.as_deref(), Some("hello"));
+    client.insert(5, " world")?;
+    assert_eq!(text.next_line().await?.as_deref(), Some("hello world"));
+
+    // Otherwise nothing is printed until an edit arrives, so keep editing
+    // until the watcher has connected and sees one.
+    let (_watcher, mut ops) = watch(&server, &["foobar"])?;
+    let line = loop {
+        client.insert(0, ">")?;
+        tokio::select! {
+            line = ops.next_line() => break line?.context("watcher exited")?,
+            _ = time::sleep(Duration::from_millis(100)) => {}
+        }
+    };
+    let op: UserOperation = serde_json::from_str(&line)?;
+    assert_eq!(Some(op.id), client.id());
+    // Edits made while an earlier one is in flight are sent together.
+    assert!(matches!(
+        op.operation.ops(),
+        [Operation::Insert(s), Operation::Retain(_)] if s.chars().all(|c| c == '>')
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Tests for the native client against an in-process server.
+
+pub mod common;
+
+use anyhow::Result;
+use common::{spawn_server, wait_for};
+use operational_transform::OperationSeq;
+use rustpad_client::{socket_url, Client, Event};
+use rustpad_core::protocol::{CursorData, UserInfo};
+
+#[tokio::test]
+async fn test_single_client() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let server = spawn_server();
+
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    assert_eq!(client.id(), Some(0));
+    assert_eq!(client.text(), "");
+
+    client.insert(0, "hello")?;
+    client.insert(5, " world")?;
+    client.delete(0, 1)?;
+    client.insert(0, "H")?;
+    assert_eq!(client.text(), "Hello world");
+    client.flush().await?;
+    assert_eq!(client.revision(), 2);
+
+    let client2 = Client::connect(&socket_url(&server, "foobar")?).await?;
+    assert_eq!(client2.text(), "Hello world");
+    assert_eq!(client2.revision(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_edits() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let server = spawn_server();
+
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let client2 = Client::connect(&socket_url(&server, "foobar")?).await?;
+
+    client.insert(0, "hello")?;
+    client2.insert(0, "world")?;
+    client.flush().await?;
+    client2.flush().await?;
+
+    wait_for(&client, |c| c.revision() == 2).await?;
+    wait_for(&client2, |c| c.revision() == 2).await?;
+    assert_eq!(client.text(), client2.text());
+    assert_eq!(client.text().len(), 10);
+
+    // Edit concurrently many times before any acknowledgements arrive.
+    for i in 0..50 {
+        client.insert(0, "a")?;
+        client2.insert(i % 3, "b")?;
+        if i % 5 == 0 {
+            client2.delete(0, 1)?;
+        }
+    }
+    client.replace(&client.text().replace("hello", "HELLO"))?;
+    client.flush().await?;
+    client2.flush().await?;
+
+    let revision = client.revision().max(client2.revision());
+    wait_for(&client, |c| c.revision() >= revision && !c.pending()).await?;
+    wait_for(&client2, |c| c.revision() >= revision && !c.pending()).await?;
+    assert_eq!(client.text(), client2.text());
+    assert_eq!(client.text().matches('a').count(), 50);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_events() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let server = spawn_server();
+
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let client2 = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let mut events = client.subscribe();
+
+    let mut operation = OperationSeq::default();
+    operation.insert("abc");
+    client2.edit(operation.clone())?;
+    loop {
+        if let Event::Edit { id, operation: op } = events.recv().await? {
+            assert_eq!(id, 1);
+            assert_eq!(op, operation);
+            break;
+        }
+    }
+    assert_eq!(client.text(), "abc");
+
+    client2.set_language("rust");
+    wait_for(&client, |c| c.language().as_deref() == Some("rust")).await?;
+
+    let info = UserInfo {
+        name: "Bob".into(),
+        hue: 96,
+    };
+    client2.set_info(info.clone());
+    wait_for(&client, |c| c.users().get(&1) == Some(&info)).await?;
+    assert!(!client.users().contains_key(&0));
+
+    drop(client2);
+    wait_for(&client, |c| c.users().is_empty()).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_remote_cursors() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let server = spawn_server();
+
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    client.insert(0, "hello world")?;
+    client.flush().await?;
+
+    let client2 = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let data = CursorData {
+        cursors: vec![6],
+        selections: vec![(6, 11)],
+        ..Default::default()
+    };
+    client2.set_cursors(data.clone());
+    wait_for(&client, |c| c.cursors().get(&1) == Some(&data)).await?;
+
+    // Local edits should transform remote cursors immediately.
+    client.insert(0, ">> ")?;
+    let expected = CursorData {
+        cursors: vec![9],
+        selections: vec![(9, 14)],
+        ..Default::default()
+    };
+    assert_eq!(client.cursors().get(&1), Some(&expected));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connect_quietly() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let server = spawn_server();
+
+    // Connecting doesn't show a cursor to other users before one is set.
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let client2 = Client::connect(&socket_url(&server, "foobar")?).await?;
+    assert!(client2.cursors().is_empty());
+    client.insert(0, "hi")?;
+    client.flush().await?;
+    wait_for(&client2, |c| c.text() == "hi").await?;
+    assert!(client.cursors().is_empty() && client2.cursors().is_empty());
+
+    // Ranges past the end of the number type are rejected.
+    assert!(client.delete(1, u32::MAX).is_err());
+    assert_eq!(client.text(), "hi");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crdt_unsupported() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let server = spawn_server();
+
+    // This client only speaks OT, so it refuses documents that use CRDTs.
+    assert!(Client::connect(&socket_url(&server, "crdt?engine=crdt")?)
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_socket_url() -> Result<()> {
+    assert_eq!(
+        socket_url("http://localhost:3030", "abc")?,
+        "ws://localhost:3030/api/socket/abc"
+    );
+    assert_eq!(
+        socket_url("https://rustpad.io/", "abc")?,
+        "wss://rustpad.io/api/socket/abc"
+    );
+    assert!(socket_url("ftp://rustpad.io", "abc").is_err());
+    assert!(socket_url("rustpad.io", "abc").is_err());
+    Ok(())
+}
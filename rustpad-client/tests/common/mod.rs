@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use rustpad_client::Client;
+use rustpad_server::{server, ServerConfig};
+use tokio::time;
+
+/// Start a server on an ephemeral port, returning its HTTP base URL.
+pub fn spawn_server() -> String {
+    let (addr, fut) =
+        warp::serve(server(ServerConfig::default())).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(fut);
+    format!("http://{}", addr)
+}
+
+/// Wait until a condition on the client holds, failing after a timeout.
+pub async fn wait_for(client: &Client, f: impl Fn(&Client) -> bool) -> Result<()> {
+    let mut events = client.subscribe();
+    let deadline = time::sleep(Duration::from_secs(5));
+    tokio::pin!(deadline);
+    while !f(client) {
+        tokio::select! {
+            _ = events.recv() => {}
+            _ = &mut deadline => bail!("timed out waiting for condition"),
+        }
+    }
+    Ok(())
+}
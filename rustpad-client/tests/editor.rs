@@ -0,0 +1,139 @@
+//! Tests for the cursor and editing logic of the terminal editor.
+
+pub mod common;
+
+use anyhow::Result;
+use common::{spawn_server, wait_for};
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use rustpad_client::editor::{char_width, Editor};
+use rustpad_client::{socket_url, Client};
+
+/// Press a sequence of keys, without modifiers.
+fn press(editor: &mut Editor, client: &Client, keys: &[KeyCode]) -> Result<()> {
+    for &code in keys {
+        assert!(editor.handle_key(client, KeyEvent::from(code), 10)?);
+    }
+    Ok(())
+}
+
+/// Type some text, one character at a time.
+fn type_text(editor: &mut Editor, client: &Client, text: &str) -> Result<()> {
+    let keys: Vec<_> = text.chars().map(KeyCode::Char).collect();
+    press(editor, client, &keys)
+}
+
+#[tokio::test]
+async fn test_editor_keys() -> Result<()> {
+    let server = spawn_server();
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let mut editor = Editor::new(&client);
+
+    type_text(&mut editor, &client, "hello")?;
+    press(&mut editor, &client, &[KeyCode::Enter])?;
+    type_text(&mut editor, &client, "wd")?;
+    assert_eq!(client.text(), "hello\nwd");
+    assert_eq!(editor.cursor(), 8);
+
+    press(&mut editor, &client, &[KeyCode::Left])?;
+    type_text(&mut editor, &client, "orl")?;
+    press(&mut editor, &client, &[KeyCode::Up, KeyCode::End])?;
+    assert_eq!(editor.cursor(), 5);
+    press(&mut editor, &client, &[KeyCode::Backspace, KeyCode::Down])?;
+    assert_eq!(client.text(), "hell\nworld");
+    assert_eq!(editor.cursor(), 9);
+    press(&mut editor, &client, &[KeyCode::Home, KeyCode::Delete])?;
+    assert_eq!(client.text(), "hell\norld");
+
+    // Shift extends the selection, and typing replaces it.
+    let shift_right = KeyEvent::new(KeyCode::Right, KeyModifiers::SHIFT);
+    editor.handle_key(&client, shift_right, 10)?;
+    editor.handle_key(&client, shift_right, 10)?;
+    assert_eq!(editor.selection(), (5, 7));
+    type_text(&mut editor, &client, "W")?;
+    assert_eq!(client.text(), "hell\nWld");
+
+    // Releasing a key does nothing, so keys are not repeated on terminals
+    // that report releases.
+    let mut release = KeyEvent::from(KeyCode::Char('x'));
+    release.kind = KeyEventKind::Release;
+    assert!(editor.handle_key(&client, release, 10)?);
+    assert_eq!(client.text(), "hell\nWld");
+
+    assert!(!editor.handle_key(&client, KeyEvent::from(KeyCode::Esc), 10)?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_editor_remote_edits() -> Result<()> {
+    let server = spawn_server();
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let client2 = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let mut editor = Editor::new(&client);
+    type_text(&mut editor, &client, "world")?;
+    client.flush().await?;
+
+    // The remote edit reaches the client's text before the editor has seen
+    // it, and the key should still go where the user's cursor is.
+    client2.insert(0, "hello ")?;
+    wait_for(&client, |client| client.text() == "hello world").await?;
+    type_text(&mut editor, &client, "!")?;
+    assert_eq!(client.text(), "hello world!");
+    assert_eq!(editor.cursor(), 12);
+
+    // Text inserted at the edges of a selection stays outside of it.
+    press(&mut editor, &client, &[KeyCode::Home])?;
+    let shift_end = KeyEvent::new(KeyCode::End, KeyModifiers::SHIFT);
+    editor.handle_key(&client, shift_end, 10)?;
+    client.flush().await?;
+    client2.insert(12, "?")?;
+    client2.insert(0, ">")?;
+    wait_for(&client, |client| client.text() == ">hello world!?").await?;
+    editor.sync(&client);
+    assert_eq!(editor.selection(), (1, 13));
+    assert_eq!(editor.text(), ">hello world!?");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_editor_lagged() -> Result<()> {
+    let server = spawn_server();
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let client2 = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let mut editor = Editor::new(&client);
+    type_text(&mut editor, &client, "end")?;
+    client.flush().await?;
+
+    // Make more edits than the client keeps for subscribers, so that the
+    // editor misses some of them.
+    for _ in 0..300 {
+        client2.insert(0, "x")?;
+        client2.flush().await?;
+    }
+    let expected = format!("{}end", "x".repeat(300));
+    wait_for(&client, |client| client.text() == expected).await?;
+    editor.sync(&client);
+    assert_eq!(editor.text(), expected);
+    assert_eq!(editor.cursor(), 303);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_editor_screen_position() -> Result<()> {
+    let server = spawn_server();
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    client.insert(0, "a\tb\n中文x\n")?;
+    let editor = Editor::new(&client);
+
+    assert_eq!(editor.screen_position(2), (0, 4));
+    assert_eq!(editor.screen_position(3), (0, 5));
+    assert_eq!(editor.screen_position(6), (1, 4));
+    assert_eq!(editor.screen_position(9), (2, 0));
+
+    assert_eq!(char_width('\t', 0), 4);
+    assert_eq!(char_width('\t', 6), 2);
+    assert_eq!(char_width('中', 0), 2);
+    assert_eq!(char_width('\u{7}', 0), 1);
+    Ok(())
+}
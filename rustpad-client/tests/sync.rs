@@ -0,0 +1,148 @@
+//! Tests for synchronizing documents with local files.
+
+pub mod common;
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use common::spawn_server;
+use rustpad_client::{
+    socket_url,
+    sync::{sync_file, Keep},
+    Client,
+};
+use tempfile::TempDir;
+use tokio::{fs, time};
+
+/// Poll until a file has the expected contents, failing after a timeout.
+async fn wait_for_file(path: &Path, expected: &str) -> Result<()> {
+    for _ in 0..500 {
+        if fs::read_to_string(path).await.ok().as_deref() == Some(expected) {
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    bail!("timed out waiting for file contents {:?}", expected)
+}
+
+/// Poll until a client has the expected text, failing after a timeout.
+async fn wait_for_text(client: &Client, expected: &str) -> Result<()> {
+    for _ in 0..500 {
+        if client.text() == expected {
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    bail!("timed out waiting for text {:?}", expected)
+}
+
+#[tokio::test]
+async fn test_rebase() -> Result<()> {
+    let server = spawn_server();
+
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let client2 = Client::connect(&socket_url(&server, "foobar")?).await?;
+    client.insert(0, "hello world")?;
+    wait_for_text(&client2, "hello world").await?;
+
+    client.replace("hello there world")?;
+    wait_for_text(&client2, "hello there world").await?;
+    let merged = client2.rebase("hello world", "hello world!")?;
+    assert_eq!(merged, "hello there world!");
+    wait_for_text(&client, "hello there world!").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_file() -> Result<()> {
+    let server = spawn_server();
+    let dir = TempDir::new()?;
+    let path = dir.path().join("notes.txt");
+
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    client.insert(0, "hello\n")?;
+    client.flush().await?;
+    let syncer = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let task = {
+        let path = path.clone();
+        tokio::spawn(async move { sync_file(&syncer, &path, None).await })
+    };
+    wait_for_file(&path, "hello\n").await?;
+
+    client.insert(6, "world\n")?;
+    wait_for_file(&path, "hello\nworld\n").await?;
+
+    fs::write(&path, "hello, world\n").await?;
+    wait_for_text(&client, "hello, world\n").await?;
+
+    // Save the file by renaming over it, like many editors do.
+    let temp = dir.path().join("notes.txt.swp");
+    fs::write(&temp, "hello, world!\n").await?;
+    fs::rename(&temp, &path).await?;
+    wait_for_text(&client, "hello, world!\n").await?;
+
+    task.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_existing_file() -> Result<()> {
+    let server = spawn_server();
+    let dir = TempDir::new()?;
+    let path = dir.path().join("main.rs");
+    fs::write(&path, "fn main() {}\n").await?;
+
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    client.insert(0, "old text")?;
+    client.flush().await?;
+    let syncer = Client::connect(&socket_url(&server, "foobar")?).await?;
+
+    // Neither side is overwritten unless one is chosen.
+    assert!(sync_file(&syncer, &path, None).await.is_err());
+    assert_eq!(fs::read_to_string(&path).await?, "fn main() {}\n");
+    assert_eq!(client.text(), "old text");
+
+    let task = {
+        let path = path.clone();
+        tokio::spawn(async move { sync_file(&syncer, &path, Some(Keep::Local)).await })
+    };
+    wait_for_text(&client, "fn main() {}\n").await?;
+    assert_eq!(fs::read_to_string(&path).await?, "fn main() {}\n");
+
+    task.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_keep_remote() -> Result<()> {
+    let server = spawn_server();
+    let dir = TempDir::new()?;
+    let path = dir.path().join("main.rs");
+    fs::write(&path, "fn main() {}\n").await?;
+
+    let client = Client::connect(&socket_url(&server, "foobar")?).await?;
+    client.insert(0, "remote text\n")?;
+    client.flush().await?;
+    let syncer = Client::connect(&socket_url(&server, "foobar")?).await?;
+    let task = {
+        let path = path.clone();
+        tokio::spawn(async move { sync_file(&syncer, &path, Some(Keep::Remote)).await })
+    };
+    wait_for_file(&path, "remote text\n").await?;
+    assert_eq!(client.text(), "remote text\n");
+    task.abort();
+
+    // An empty document takes the text of the file without being asked.
+    let empty = Client::connect(&socket_url(&server, "empty")?).await?;
+    let task2 = {
+        let path = path.clone();
+        tokio::spawn(async move { sync_file(&empty, &path, None).await })
+    };
+    let other = Client::connect(&socket_url(&server, "empty")?).await?;
+    wait_for_text(&other, "remote text\n").await?;
+
+    task2.abort();
+    Ok(())
+}
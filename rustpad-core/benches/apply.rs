@@ -0,0 +1,42 @@
+//! Benchmarks comparing string and rope storage for applying edits.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use operational_transform::OperationSeq;
+use ropey::Rope;
+use rustpad_core::ot::apply_rope;
+
+/// Build a document of about `size` bytes, split into lines.
+fn document(size: usize) -> String {
+    "The quick brown fox jumps over the lazy dog.\n".repeat(size / 45)
+}
+
+/// An operation typing one character in the middle of a document.
+fn keystroke(len: usize) -> OperationSeq {
+    let mut operation = OperationSeq::default();
+    operation.retain(len as u64 / 2);
+    operation.insert("x");
+    operation.retain((len - len / 2) as u64);
+    operation
+}
+
+fn bench_apply(c: &mut Criterion) {
+    for size in [4 * 1024, 64 * 1024, 256 * 1024] {
+        let text = document(size);
+        let operation = keystroke(text.chars().count());
+        let mut group = c.benchmark_group(format!("keystroke/{}KiB", size / 1024));
+        group.bench_function("string", |b| {
+            b.iter(|| operation.apply(&text).unwrap());
+        });
+        group.bench_function("rope", |b| {
+            b.iter_batched_ref(
+                || Rope::from_str(&text),
+                |rope| apply_rope(&operation, rope).unwrap(),
+                BatchSize::SmallInput,
+            );
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_apply);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+//! Cheap checksums of document text, for detecting clients that diverged.
+
+/// Computes a 32-bit FNV-1a hash of a text given in chunks, such as the
+/// chunks of a rope.
+///
+/// This is not collision resistant, but it is fast and simple to compute the
+/// same way on every client. The hash only depends on the concatenated text,
+/// not on how it is split into chunks.
+pub fn checksum<'a>(chunks: impl IntoIterator<Item = &'a str>) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for chunk in chunks {
+        for &byte in chunk.as_bytes() {
+            hash ^= u32::from(byte);
+            hash = hash.wrapping_mul(0x01000193);
+        }
+    }
+    hash
+}
@@ -0,0 +1,256 @@
+//! Client side of the operational transformation protocol.
+
+use anyhow::{bail, Result};
+use operational_transform::OperationSeq;
+
+use crate::checksum::checksum;
+use crate::protocol::{ClientMsg, UserOperation};
+
+/// State machine for synchronizing a client's local edits with the server.
+///
+/// A client has at most one operation in flight at a time. Local edits made
+/// while waiting for an acknowledgement are composed into a buffer, and remote
+/// operations are transformed against both before being applied locally. This
+/// does not own the text itself, so it can sit beside any kind of editor.
+#[derive(Clone, Debug, Default)]
+pub struct OtClient {
+    id: Option<u64>,
+    /// Epoch of the document that the revision refers to, once identified.
+    epoch: Option<String>,
+    revision: usize,
+    /// Local edit that was sent to the server, but not yet acknowledged.
+    outstanding: Option<OperationSeq>,
+    /// Local edits made while another edit is outstanding, composed together.
+    buffer: Option<OperationSeq>,
+    /// Set when an edit was sent to the server but then removed from the local
+    /// text by a snapshot, so the server's version of it is applied once it
+    /// is acknowledged.
+    dropped: bool,
+}
+
+/// Something the client should do after receiving history from the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Apply a remote operation to the local text. This has already been
+    /// transformed against any local edits that are not yet acknowledged.
+    Apply(UserOperation),
+    /// The server acknowledged one of our own operations.
+    Ack,
+    /// Clear the local text, which is rebuilt by the actions and history that
+    /// follow.
+    Reset,
+    /// Send a message to the server.
+    Send(ClientMsg),
+}
+
+impl OtClient {
+    /// Construct a new client, before it has received any history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the ID assigned to this client by the server, if any.
+    pub fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    /// Returns the epoch of the document, once identified by the server.
+    pub fn epoch(&self) -> Option<&str> {
+        self.epoch.as_deref()
+    }
+
+    /// Returns the number of operations received from the server.
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
+
+    /// Returns the local edit waiting for acknowledgement, if any.
+    pub fn outstanding(&self) -> Option<&OperationSeq> {
+        self.outstanding.as_ref()
+    }
+
+    /// Returns the local edits waiting to be sent, if any.
+    pub fn buffer(&self) -> Option<&OperationSeq> {
+        self.buffer.as_ref()
+    }
+
+    /// Record an edit made to the local text.
+    ///
+    /// Returns a message to send to the server, if no other edit is in flight.
+    pub fn apply_client(&mut self, operation: OperationSeq) -> Result<Option<ClientMsg>> {
+        if self.outstanding.is_none() {
+            let msg = self.edit_msg(&operation);
+            self.outstanding = Some(operation);
+            return Ok(Some(msg));
+        }
+        self.buffer = Some(match self.buffer.take() {
+            Some(buffer) => buffer.compose(&operation)?,
+            None => operation,
+        });
+        Ok(None)
+    }
+
+    /// Returns the message to resend the outstanding edit after reconnecting.
+    pub fn resend(&self) -> Option<ClientMsg> {
+        Some(self.edit_msg(self.outstanding.as_ref()?))
+    }
+
+    /// Process an `Identity` message from the server, which starts each
+    /// connection.
+    ///
+    /// If the epoch changed since the last connection, the server has lost the
+    /// history that this client was synchronized with, so the client starts
+    /// over from an empty document. Any local edits that were not acknowledged
+    /// are discarded.
+    pub fn apply_identity(&mut self, id: u64, epoch: String) -> Vec<Action> {
+        self.id = Some(id);
+        // Edits from the last connection come back under another ID.
+        self.dropped = false;
+        match self.epoch.replace(epoch) {
+            // Edits sent before the first identity had no epoch to refer to.
+            None => self.resend().map(Action::Send).into_iter().collect(),
+            Some(old) if self.epoch.as_ref() != Some(&old) => {
+                self.revision = 0;
+                self.outstanding = None;
+                self.buffer = None;
+                vec![Action::Reset]
+            }
+            Some(_) => Vec::new(),
+        }
+    }
+
+    /// Process a `History` message from the server.
+    pub fn apply_history(
+        &mut self,
+        start: usize,
+        operations: Vec<UserOperation>,
+    ) -> Result<Vec<Action>> {
+        if start > self.revision {
+            bail!(
+                "history start {} is after revision {}",
+                start,
+                self.revision
+            );
+        }
+        let skip = self.revision - start;
+        let mut actions = Vec::new();
+        for UserOperation { id, operation } in operations.into_iter().skip(skip) {
+            self.revision += 1;
+            if Some(id) == self.id && self.dropped {
+                self.dropped = false;
+                let operation = self.apply_server(operation)?;
+                actions.push(Action::Apply(UserOperation { id, operation }));
+            } else if Some(id) == self.id {
+                actions.push(Action::Ack);
+                if let Some(msg) = self.server_ack()? {
+                    actions.push(Action::Send(msg));
+                }
+            } else {
+                let operation = self.apply_server(operation)?;
+                actions.push(Action::Apply(UserOperation { id, operation }));
+            }
+        }
+        Ok(actions)
+    }
+
+    /// Process a `Catchup` message from the server, which replaces revisions
+    /// `start` up to `end` with one composed operation.
+    pub fn apply_catchup(
+        &mut self,
+        start: usize,
+        end: usize,
+        operation: OperationSeq,
+    ) -> Result<Vec<Action>> {
+        if end <= self.revision {
+            return Ok(Vec::new());
+        }
+        if start != self.revision {
+            bail!(
+                "catchup from {} does not match revision {}",
+                start,
+                self.revision
+            );
+        }
+        self.revision = end;
+        let operation = self.apply_server(operation)?;
+        Ok(vec![Action::Apply(UserOperation {
+            id: u64::MAX,
+            operation,
+        })])
+    }
+
+    /// Process a `Checksum` message from the server, given the local text.
+    ///
+    /// Returns a message to report a mismatch to the server, if the local text
+    /// differs from the text that the server had at the same revision. This
+    /// can only be checked while there are no local edits in flight.
+    pub fn check(&self, revision: usize, expected: u32, text: &str) -> Option<ClientMsg> {
+        if revision != self.revision || self.outstanding.is_some() || self.dropped {
+            return None;
+        }
+        (checksum([text]) != expected).then_some(ClientMsg::ChecksumMismatch { revision })
+    }
+
+    /// Process a `Snapshot` message from the server, which replaces the local
+    /// text after it diverged.
+    ///
+    /// Local edits that were not acknowledged refer to the diverged text, so
+    /// they can't be placed in the new text and are dropped. The server still
+    /// applies the edit that was already sent, and its version of that edit
+    /// is applied locally once it is acknowledged.
+    pub fn apply_snapshot(&mut self, revision: usize, text: &str) -> Result<Vec<Action>> {
+        if revision != self.revision {
+            bail!(
+                "snapshot at revision {} does not match revision {}",
+                revision,
+                self.revision
+            );
+        }
+        self.dropped = self.outstanding.take().is_some() || self.dropped;
+        self.buffer = None;
+        let mut operation = OperationSeq::default();
+        operation.insert(text);
+        Ok(vec![
+            Action::Reset,
+            Action::Apply(UserOperation {
+                id: u64::MAX,
+                operation,
+            }),
+        ])
+    }
+
+    /// Handle the server acknowledging our outstanding operation.
+    ///
+    /// Returns a message to send the buffered edits, if there are any.
+    fn server_ack(&mut self) -> Result<Option<ClientMsg>> {
+        if self.outstanding.is_none() {
+            bail!("received acknowledgement with no outstanding operation");
+        }
+        self.outstanding = self.buffer.take();
+        Ok(self.resend())
+    }
+
+    /// Transform a remote operation against any local edits that have not been
+    /// acknowledged yet, returning the operation to apply to the local text.
+    fn apply_server(&mut self, mut operation: OperationSeq) -> Result<OperationSeq> {
+        if let Some(outstanding) = &self.outstanding {
+            let (outstanding, op) = outstanding.transform(&operation)?;
+            self.outstanding = Some(outstanding);
+            operation = op;
+            if let Some(buffer) = &self.buffer {
+                let (buffer, op) = buffer.transform(&operation)?;
+                self.buffer = Some(buffer);
+                operation = op;
+            }
+        }
+        Ok(operation)
+    }
+
+    fn edit_msg(&self, operation: &OperationSeq) -> ClientMsg {
+        ClientMsg::Edit {
+            epoch: self.epoch.clone().unwrap_or_default(),
+            revision: self.revision,
+            operation: operation.clone(),
+        }
+    }
+}
@@ -0,0 +1,734 @@
+//! Sequence CRDT for merging edits without a central server to order them.
+//!
+//! This is a variant of the Replicated Growable Array (RGA). Every character
+//! gets a unique ID when it is inserted, and remembers the character it was
+//! inserted after. Deleted characters are kept as tombstones, so that later
+//! insertions can still refer to them. Replicas that have seen the same set of
+//! operations have the same text, in whatever order the operations arrived.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use operational_transform::{Operation, OperationSeq};
+use serde::{Deserialize, Serialize};
+
+use crate::client::Action;
+use crate::protocol::{ClientMsg, UserOperation};
+
+/// Unique ID of a character, ordered by Lamport clock and then by site.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ItemId {
+    /// Lamport clock of the site when the character was inserted, which is
+    /// greater than the clock of every character it could have seen.
+    pub clock: u64,
+    /// ID of the site that inserted the character.
+    pub site: u64,
+}
+
+impl ItemId {
+    /// Returns the ID of the character `n` places later in the same run.
+    fn offset(self, n: u64) -> Self {
+        Self {
+            clock: self.clock + n,
+            site: self.site,
+        }
+    }
+}
+
+/// An operation on a sequence, which can be applied by any replica.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrdtOp {
+    /// Inserts a run of characters. The first character goes after `origin`,
+    /// or at the start of the text if `None`, and each of the others goes
+    /// after the one before it, with the next clock at the same site.
+    Insert {
+        /// ID of the first character.
+        id: ItemId,
+        /// ID of the character that the run was inserted after.
+        origin: Option<ItemId>,
+        /// Text of the run.
+        text: String,
+    },
+    /// Deletes a run of characters with consecutive clocks at the same site.
+    Delete {
+        /// ID of the first character.
+        id: ItemId,
+        /// Number of characters in the run.
+        len: u64,
+    },
+}
+
+impl CrdtOp {
+    /// Check that the IDs in an operation are well formed, so that applying
+    /// it cannot fail halfway through.
+    fn validate(&self) -> Result<()> {
+        let (id, len) = match self {
+            CrdtOp::Insert { id, origin, text } => {
+                if let Some(origin) = origin {
+                    if origin.clock >= id.clock {
+                        bail!("insertion at {:?} is not after its origin", id);
+                    }
+                }
+                (id, text.chars().count() as u64)
+            }
+            CrdtOp::Delete { id, len } => (id, *len),
+        };
+        id.clock
+            .checked_add(len)
+            .context("clock overflowed in operation")?;
+        Ok(())
+    }
+}
+
+/// A character in the sequence, which may have been deleted.
+#[derive(Clone, Debug)]
+struct Item {
+    id: ItemId,
+    origin: Option<ItemId>,
+    ch: char,
+    deleted: bool,
+}
+
+/// Largest number of characters in a chunk, which is split in half when it
+/// grows past this.
+const CHUNK_SIZE: usize = 512;
+
+/// A run of neighboring characters in the sequence, so that finding a
+/// character only scans the chunks and then one chunk's characters.
+#[derive(Clone, Debug)]
+struct Chunk {
+    /// Key of the chunk in [`Sequence::ids`], which stays the same when other
+    /// chunks are split.
+    key: usize,
+    items: Vec<Item>,
+    /// Number of characters in `items` that are not deleted.
+    visible: usize,
+}
+
+/// Position of a character, as the index of its chunk and its index within
+/// that chunk. The end of the sequence is `(chunks.len(), 0)`.
+type Position = (usize, usize);
+
+/// Result of merging remote operations into a sequence.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Merged {
+    /// Operation that takes the previous text to the new text.
+    pub operation: OperationSeq,
+    /// Parts of the operations that changed the sequence, leaving out any
+    /// that had already been applied.
+    pub ops: Vec<CrdtOp>,
+}
+
+/// A replica of a text sequence, identified by its site.
+#[derive(Clone, Debug, Default)]
+pub struct Sequence {
+    site: u64,
+    /// Highest clock seen so far, from this or any other site.
+    clock: u64,
+    /// Every character ever inserted, in document order.
+    chunks: Vec<Chunk>,
+    /// Key of the chunk that holds each character in `chunks`.
+    ids: HashMap<ItemId, usize>,
+    /// Number of characters that are not deleted.
+    len: usize,
+    /// Remote operations that refer to characters not seen yet.
+    pending: Vec<CrdtOp>,
+}
+
+impl Sequence {
+    /// Construct an empty sequence, which inserts characters as `site`.
+    pub fn new(site: u64) -> Self {
+        Self {
+            site,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the site that inserts characters from local edits.
+    pub fn site(&self) -> u64 {
+        self.site
+    }
+
+    /// Change the site for future local edits. Sites must never be shared by
+    /// two replicas that edit concurrently.
+    pub fn set_site(&mut self, site: u64) {
+        self.site = site;
+    }
+
+    /// Returns the number of characters in the text.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns if the text is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of deleted characters, which are kept as tombstones
+    /// so that operations can still refer to them.
+    pub fn tombstones(&self) -> usize {
+        self.ids.len() - self.len
+    }
+
+    /// Returns the current text.
+    pub fn text(&self) -> String {
+        self.items()
+            .filter(|item| !item.deleted)
+            .map(|item| item.ch)
+            .collect()
+    }
+
+    /// Returns the number of remote operations waiting for characters that
+    /// have not been seen yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns operations that rebuild this sequence from scratch.
+    ///
+    /// Insertions come first in document order, which is also an order where
+    /// each character comes after its origin, followed by deletions.
+    pub fn state(&self) -> Vec<CrdtOp> {
+        let mut ops = Vec::new();
+        let mut deleted = Vec::new();
+        let mut prev: Option<&Item> = None;
+        for item in self.items() {
+            match ops.last_mut() {
+                Some(CrdtOp::Insert { text, .. })
+                    if prev.is_some_and(|prev| {
+                        item.origin == Some(prev.id) && item.id == prev.id.offset(1)
+                    }) =>
+                {
+                    text.push(item.ch);
+                }
+                _ => ops.push(CrdtOp::Insert {
+                    id: item.id,
+                    origin: item.origin,
+                    text: item.ch.into(),
+                }),
+            }
+            if item.deleted {
+                push_delete(&mut deleted, item.id);
+            }
+            prev = Some(item);
+        }
+        ops.extend(deleted);
+        ops
+    }
+
+    /// Apply an edit made to the local text, returning the operations that
+    /// other replicas need to apply the same edit.
+    pub fn apply_local(&mut self, operation: &OperationSeq) -> Result<Vec<CrdtOp>> {
+        if operation.base_len() != self.len() {
+            bail!(
+                "operation has base length {}, but text has length {}",
+                operation.base_len(),
+                self.len()
+            );
+        }
+        let mut ops = Vec::new();
+        let mut deleted = Vec::new();
+        // Number of visible characters before the current position.
+        let mut cursor = 0;
+        for op in operation.ops() {
+            match op {
+                &Operation::Retain(n) => cursor += n as usize,
+                &Operation::Delete(n) => {
+                    let mut position = self.nth_visible(cursor);
+                    for _ in 0..n {
+                        position = self.next_visible(position);
+                        push_delete(&mut deleted, self.id(position));
+                        self.delete(position);
+                        position = self.next(position);
+                    }
+                }
+                Operation::Insert(text) => {
+                    // A new character has a higher clock than anything seen,
+                    // so it goes right after its origin on every replica.
+                    let id = ItemId {
+                        clock: self.clock + 1,
+                        site: self.site,
+                    };
+                    let origin = cursor.checked_sub(1).map(|n| self.nth_visible(n));
+                    let mut prev = origin.map(|origin| self.id(origin));
+                    ops.push(CrdtOp::Insert {
+                        id,
+                        origin: prev,
+                        text: text.clone(),
+                    });
+                    let mut position = origin.map_or((0, 0), |origin| self.next(origin));
+                    for ch in text.chars() {
+                        self.clock += 1;
+                        let item = Item {
+                            id: id.offset(self.clock - id.clock),
+                            origin: prev,
+                            ch,
+                            deleted: false,
+                        };
+                        prev = Some(item.id);
+                        let inserted = self.insert(position, item);
+                        position = self.next(inserted);
+                        cursor += 1;
+                    }
+                }
+            }
+        }
+        ops.extend(deleted);
+        Ok(ops)
+    }
+
+    /// Check that remote operations can be applied right away, without
+    /// waiting for characters from other sites. Returns the number of new
+    /// characters that they would insert.
+    ///
+    /// New characters must be at a site that `sites` accepts, so that a
+    /// replica can't take IDs that another site will use for its own edits.
+    pub fn check_remote(&self, ops: &[CrdtOp], sites: impl Fn(u64) -> bool) -> Result<usize> {
+        let mut seen = HashSet::new();
+        let known =
+            |id: &ItemId, seen: &HashSet<ItemId>| self.ids.contains_key(id) || seen.contains(id);
+        let mut inserted = 0;
+        for op in ops {
+            op.validate()?;
+            match op {
+                CrdtOp::Insert { id, origin, text } => {
+                    if let Some(origin) = origin.filter(|origin| !known(origin, &seen)) {
+                        bail!("insertion refers to unknown character {:?}", origin);
+                    }
+                    for n in 0..text.chars().count() as u64 {
+                        if !known(&id.offset(n), &seen) {
+                            if !sites(id.site) {
+                                bail!("insertion at {:?} is from another site", id.offset(n));
+                            }
+                            seen.insert(id.offset(n));
+                            inserted += 1;
+                        }
+                    }
+                }
+                CrdtOp::Delete { id, len } => {
+                    if let Some(n) = (0..*len).find(|&n| !known(&id.offset(n), &seen)) {
+                        bail!("deletion refers to unknown character {:?}", id.offset(n));
+                    }
+                }
+            }
+        }
+        Ok(inserted)
+    }
+
+    /// Merge operations from another replica.
+    ///
+    /// Operations can arrive in any order, and more than once. Those that
+    /// refer to characters not seen yet are held back until they arrive.
+    pub fn apply_remote(&mut self, ops: Vec<CrdtOp>) -> Result<Merged> {
+        for op in &ops {
+            op.validate()?;
+        }
+        let mut inserted = HashSet::new();
+        let mut deleted = HashSet::new();
+        let mut applied = Vec::new();
+        let mut queue = ops;
+        loop {
+            let mut progress = false;
+            for op in std::mem::take(&mut queue) {
+                if !self.ready(&op) {
+                    queue.push(op);
+                    continue;
+                }
+                progress = true;
+                match op {
+                    CrdtOp::Insert { id, origin, text } => {
+                        self.integrate(id, origin, &text, &mut inserted, &mut applied)
+                    }
+                    CrdtOp::Delete { id, len } => self.remove(id, len, &mut deleted, &mut applied),
+                }
+            }
+            if !progress {
+                break;
+            }
+            queue.append(&mut self.pending);
+        }
+        self.pending.append(&mut queue);
+
+        // Turn the changes into an operation on the text as it was before,
+        // only walking through the chunks that changed.
+        let touched: HashSet<usize> = (inserted.iter().chain(&deleted))
+            .map(|id| self.ids[id])
+            .collect();
+        let mut operation = OperationSeq::default();
+        for chunk in &self.chunks {
+            if !touched.contains(&chunk.key) {
+                operation.retain(chunk.visible as u64);
+                continue;
+            }
+            for item in &chunk.items {
+                let is_new = inserted.contains(&item.id);
+                let was_visible = !is_new && (!item.deleted || deleted.contains(&item.id));
+                match (was_visible, !item.deleted) {
+                    (true, true) => operation.retain(1),
+                    (true, false) => operation.delete(1),
+                    (false, true) => operation.insert(item.ch.encode_utf8(&mut [0; 4])),
+                    (false, false) => {}
+                }
+            }
+        }
+        Ok(Merged {
+            operation,
+            ops: applied,
+        })
+    }
+
+    /// Returns if every character that an operation refers to is known.
+    fn ready(&self, op: &CrdtOp) -> bool {
+        match op {
+            CrdtOp::Insert { origin, .. } => {
+                origin.is_none_or(|origin| self.ids.contains_key(&origin))
+            }
+            CrdtOp::Delete { id, len } => (0..*len).all(|n| self.ids.contains_key(&id.offset(n))),
+        }
+    }
+
+    /// Insert a run of characters from another replica, skipping any that
+    /// were already inserted.
+    fn integrate(
+        &mut self,
+        id: ItemId,
+        origin: Option<ItemId>,
+        text: &str,
+        inserted: &mut HashSet<ItemId>,
+        applied: &mut Vec<CrdtOp>,
+    ) {
+        let mut prev = origin.map(|origin| self.position(origin));
+        let mut run: Option<CrdtOp> = None;
+        for (n, ch) in text.chars().enumerate() {
+            let item_id = id.offset(n as u64);
+            let item_origin = if n == 0 {
+                origin
+            } else {
+                Some(id.offset(n as u64 - 1))
+            };
+            let mut position = prev.map_or((0, 0), |prev| self.next(prev));
+            if self.ids.contains_key(&item_id) {
+                prev = Some(self.find(item_id, position));
+                applied.extend(run.take());
+                continue;
+            }
+            // Concurrent insertions after the same origin are ordered by
+            // descending ID. Everything inserted after a character with a
+            // higher ID also has a higher ID, so it is skipped as well.
+            while self.item(position).is_some_and(|item| item.id > item_id) {
+                position = self.next(position);
+            }
+            let item = Item {
+                id: item_id,
+                origin: item_origin,
+                ch,
+                deleted: false,
+            };
+            prev = Some(self.insert(position, item));
+            inserted.insert(item_id);
+            self.clock = self.clock.max(item_id.clock);
+            match &mut run {
+                Some(CrdtOp::Insert { text, .. }) => text.push(ch),
+                _ => {
+                    run = Some(CrdtOp::Insert {
+                        id: item_id,
+                        origin: item_origin,
+                        text: ch.into(),
+                    })
+                }
+            }
+        }
+        applied.extend(run);
+    }
+
+    /// Delete a run of characters from another replica, skipping any that
+    /// were already deleted.
+    fn remove(
+        &mut self,
+        id: ItemId,
+        len: u64,
+        deleted: &mut HashSet<ItemId>,
+        applied: &mut Vec<CrdtOp>,
+    ) {
+        let mut run = Vec::new();
+        let mut position = (0, 0);
+        for n in 0..len {
+            let item_id = id.offset(n);
+            position = self.find(item_id, position);
+            if self.delete(position) {
+                deleted.insert(item_id);
+                push_delete(&mut run, item_id);
+            }
+            position = self.next(position);
+        }
+        applied.extend(run);
+    }
+
+    /// Returns every character in document order.
+    fn items(&self) -> impl Iterator<Item = &Item> {
+        self.chunks.iter().flat_map(|chunk| &chunk.items)
+    }
+
+    /// Returns the character at a position, or `None` at the end.
+    fn item(&self, (chunk, index): Position) -> Option<&Item> {
+        self.chunks.get(chunk)?.items.get(index)
+    }
+
+    /// Returns the ID of the character at a position before the end.
+    fn id(&self, position: Position) -> ItemId {
+        self.item(position)
+            .expect("character should be in the sequence")
+            .id
+    }
+
+    /// Returns the position after the character at `(chunk, index)`.
+    fn next(&self, (chunk, index): Position) -> Position {
+        if index + 1 < self.chunks[chunk].items.len() {
+            (chunk, index + 1)
+        } else {
+            (chunk + 1, 0)
+        }
+    }
+
+    /// Returns the first visible character at or after a position.
+    fn next_visible(&self, mut position: Position) -> Position {
+        while self
+            .item(position)
+            .expect("text should be long enough")
+            .deleted
+        {
+            position = self.next(position);
+        }
+        position
+    }
+
+    /// Returns the position of the `n`th visible character in the text.
+    fn nth_visible(&self, mut n: usize) -> Position {
+        for (chunk, Chunk { items, visible, .. }) in self.chunks.iter().enumerate() {
+            if n < *visible {
+                let index = (items.iter().enumerate())
+                    .filter(|(_, item)| !item.deleted)
+                    .nth(n)
+                    .map(|(index, _)| index)
+                    .expect("chunk should count its visible characters");
+                return (chunk, index);
+            }
+            n -= visible;
+        }
+        panic!("text should be long enough");
+    }
+
+    /// Returns the position of a character that is known to be in the
+    /// sequence.
+    fn position(&self, id: ItemId) -> Position {
+        let key = self.ids[&id];
+        let chunk = (self.chunks.iter())
+            .position(|chunk| chunk.key == key)
+            .expect("chunk should be in the sequence");
+        let index = (self.chunks[chunk].items.iter())
+            .position(|item| item.id == id)
+            .expect("character should be in its chunk");
+        (chunk, index)
+    }
+
+    /// Like [`Sequence::position`], but checks `hint` first, since runs of
+    /// characters are usually next to each other.
+    fn find(&self, id: ItemId, hint: Position) -> Position {
+        match self.item(hint) {
+            Some(item) if item.id == id => hint,
+            _ => self.position(id),
+        }
+    }
+
+    /// Insert a character before a position, returning where it ended up.
+    fn insert(&mut self, (mut chunk, mut index): Position, item: Item) -> Position {
+        if chunk == self.chunks.len() {
+            match self.chunks.last() {
+                Some(last) => (chunk, index) = (chunk - 1, last.items.len()),
+                None => self.chunks.push(Chunk {
+                    key: 0,
+                    items: Vec::new(),
+                    visible: 0,
+                }),
+            }
+        }
+        self.ids.insert(item.id, self.chunks[chunk].key);
+        self.chunks[chunk].items.insert(index, item);
+        self.chunks[chunk].visible += 1;
+        self.len += 1;
+
+        if self.chunks[chunk].items.len() > CHUNK_SIZE {
+            let key = self.chunks.len();
+            let items = self.chunks[chunk].items.split_off(CHUNK_SIZE / 2);
+            let visible = items.iter().filter(|item| !item.deleted).count();
+            self.chunks[chunk].visible -= visible;
+            for item in &items {
+                self.ids.insert(item.id, key);
+            }
+            (self.chunks).insert(
+                chunk + 1,
+                Chunk {
+                    key,
+                    items,
+                    visible,
+                },
+            );
+            if index >= CHUNK_SIZE / 2 {
+                return (chunk + 1, index - CHUNK_SIZE / 2);
+            }
+        }
+        (chunk, index)
+    }
+
+    /// Mark the character at a position as deleted, returning if it was
+    /// visible before.
+    fn delete(&mut self, (chunk, index): Position) -> bool {
+        let chunk = &mut self.chunks[chunk];
+        let item = &mut chunk.items[index];
+        if item.deleted {
+            return false;
+        }
+        item.deleted = true;
+        chunk.visible -= 1;
+        self.len -= 1;
+        true
+    }
+}
+
+/// Add a deleted character to a list of deletions, extending the last run if
+/// it is the next character at the same site.
+fn push_delete(ops: &mut Vec<CrdtOp>, item_id: ItemId) {
+    match ops.last_mut() {
+        Some(CrdtOp::Delete { id, len }) if id.offset(*len) == item_id => *len += 1,
+        _ => ops.push(CrdtOp::Delete {
+            id: item_id,
+            len: 1,
+        }),
+    }
+}
+
+/// Client side of the CRDT protocol.
+///
+/// Unlike [`crate::client::OtClient`], edits never wait for the server, since
+/// every replica can merge them in any order. The client uses its ID from the
+/// server as its site, so edits made before the first connection are held
+/// until then.
+#[derive(Clone, Debug, Default)]
+pub struct CrdtClient {
+    sequence: Sequence,
+    /// Epoch of the document that the sequence belongs to, once identified.
+    epoch: Option<String>,
+    /// Local edits made before the first identity, composed together.
+    early: Option<OperationSeq>,
+    /// Token from the latest identity, which claims the client's sites again
+    /// after it reconnects.
+    token: Option<String>,
+}
+
+impl CrdtClient {
+    /// Construct a new client, before it has received any operations.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the local replica of the document.
+    pub fn sequence(&self) -> &Sequence {
+        &self.sequence
+    }
+
+    /// Returns the epoch of the document, once identified by the server.
+    pub fn epoch(&self) -> Option<&str> {
+        self.epoch.as_deref()
+    }
+
+    /// Record an edit made to the local text.
+    ///
+    /// Returns a message to send to the server, unless the client has not been
+    /// identified yet.
+    pub fn apply_client(&mut self, operation: OperationSeq) -> Result<Option<ClientMsg>> {
+        if self.epoch.is_none() {
+            self.early = Some(match self.early.take() {
+                Some(early) => early.compose(&operation)?,
+                None => operation,
+            });
+            return Ok(None);
+        }
+        let ops = self.sequence.apply_local(&operation)?;
+        Ok(Some(self.edit_msg(ops)))
+    }
+
+    /// Process an `Identity` message from the server, which starts each
+    /// connection.
+    ///
+    /// After reconnecting to the same document, the client sends its whole
+    /// sequence, since the server may have missed edits made while offline
+    /// and merging is idempotent. It resumes its earlier connection first,
+    /// so that the server accepts edits made as its old site. If the epoch
+    /// changed, the client starts over from an empty document instead.
+    pub fn apply_identity(
+        &mut self,
+        id: u64,
+        epoch: String,
+        token: Option<String>,
+    ) -> Result<Vec<Action>> {
+        let old_token = std::mem::replace(&mut self.token, token);
+        match self.epoch.replace(epoch) {
+            None => {
+                self.sequence.set_site(id);
+                let Some(early) = self.early.take() else {
+                    return Ok(Vec::new());
+                };
+                let ops = self.sequence.apply_local(&early)?;
+                Ok(vec![Action::Send(self.edit_msg(ops))])
+            }
+            Some(old) if self.epoch.as_ref() != Some(&old) => {
+                self.sequence = Sequence::new(id);
+                Ok(vec![Action::Reset])
+            }
+            Some(_) => {
+                self.sequence.set_site(id);
+                let ops = self.sequence.state();
+                if ops.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let mut actions = Vec::new();
+                if let Some(token) = old_token {
+                    actions.push(Action::Send(ClientMsg::Resume { token }));
+                }
+                actions.push(Action::Send(self.edit_msg(ops)));
+                Ok(actions)
+            }
+        }
+    }
+
+    /// Process a `CrdtEdit` message from the server, made by user `id`.
+    pub fn apply_edit(&mut self, id: u64, ops: Vec<CrdtOp>) -> Result<Vec<Action>> {
+        let Merged { operation, .. } = self.sequence.apply_remote(ops)?;
+        if operation.is_noop() {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Action::Apply(UserOperation { id, operation })])
+    }
+
+    fn edit_msg(&self, ops: Vec<CrdtOp>) -> ClientMsg {
+        ClientMsg::CrdtEdit {
+            epoch: self.epoch.clone().unwrap_or_default(),
+            ops,
+        }
+    }
+}
+
+impl From<&str> for Sequence {
+    /// Construct a sequence whose text was inserted by site `u64::MAX`, which
+    /// is reserved for the server.
+    fn from(text: &str) -> Self {
+        let mut sequence = Sequence::new(u64::MAX);
+        let mut operation = OperationSeq::default();
+        operation.insert(text);
+        sequence
+            .apply_local(&operation)
+            .expect("insertion into empty sequence");
+        sequence
+    }
+}
@@ -0,0 +1,18 @@
+//! Core logic for Rustpad, shared between the server and its clients.
+//!
+//! This includes helpers for operational transformation, a sequence CRDT for
+//! documents that do not rely on the server to order edits, and the message
+//! types of the WebSocket protocol, so that both sides agree on them by
+//! construction.
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+pub mod checksum;
+pub mod client;
+pub mod crdt;
+pub mod lines;
+pub mod ot;
+pub mod protocol;
+pub mod undo;
+pub mod utf16;
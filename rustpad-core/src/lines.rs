@@ -0,0 +1,140 @@
+//! Conversions between character offsets and line and column positions.
+
+use std::mem;
+
+use operational_transform::{Operation, OperationSeq};
+
+/// An index of the line breaks in a text, for converting between offsets and
+/// (line, column) positions.
+///
+/// Lines are separated by `'\n'`, and both lines and columns are zero-based
+/// and counted in Unicode code points. The index can be kept up to date with
+/// each operation applied to the text, without rescanning it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineIndex {
+    /// Offset of the first character of each line.
+    starts: Vec<u32>,
+    /// Length of the text in characters.
+    len: u32,
+}
+
+impl Default for LineIndex {
+    fn default() -> Self {
+        Self {
+            starts: vec![0],
+            len: 0,
+        }
+    }
+}
+
+impl LineIndex {
+    /// Construct an index of the lines in a text.
+    pub fn new(text: &str) -> Self {
+        let mut index = Self::default();
+        index.len = index.push_text(0, text);
+        index
+    }
+
+    /// Returns the number of lines, which is always at least one.
+    pub fn len_lines(&self) -> u32 {
+        self.starts.len() as u32
+    }
+
+    /// Returns the length of the text in characters.
+    pub fn len_chars(&self) -> u32 {
+        self.len
+    }
+
+    /// Returns the offset of the start of a line, if it exists.
+    pub fn line_start(&self, line: u32) -> Option<u32> {
+        self.starts.get(line as usize).copied()
+    }
+
+    /// Returns the line and column of an offset, clamped to the text.
+    pub fn position(&self, offset: u32) -> (u32, u32) {
+        let offset = offset.min(self.len);
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        (line as u32, offset - self.starts[line])
+    }
+
+    /// Returns the offset of a line and column, clamped to the end of the
+    /// line and to the last line.
+    pub fn offset(&self, line: u32, column: u32) -> u32 {
+        let line = (line as usize).min(self.starts.len() - 1);
+        let (start, end) = self.line_bounds(line);
+        start + column.min(end - start)
+    }
+
+    /// Returns the range of offsets spanned by lines `start` up to `end`,
+    /// including their line breaks. Lines past the end of the text are empty.
+    pub fn range_of_lines(&self, start: u32, end: u32) -> (u32, u32) {
+        let offset = |line: u32| self.line_start(line).unwrap_or(self.len);
+        (offset(start), offset(end.max(start)))
+    }
+
+    /// Returns the slice of the text in lines `start` up to `end`, including
+    /// their line breaks. The text must be the one described by this index.
+    pub fn slice_lines<'a>(&self, text: &'a str, start: u32, end: u32) -> &'a str {
+        let (start, end) = self.range_of_lines(start, end);
+        let mut offsets = text.char_indices().map(|(i, _)| i).chain([text.len()]);
+        let start_byte = offsets.nth(start as usize).unwrap_or(text.len());
+        let end_byte = match end - start {
+            0 => start_byte,
+            n => offsets.nth(n as usize - 1).unwrap_or(text.len()),
+        };
+        &text[start_byte..end_byte]
+    }
+
+    /// Update the index for an operation applied to the text.
+    ///
+    /// The operation must apply to the text that this index describes, as
+    /// checked by [`OperationSeq::apply`].
+    pub fn apply(&mut self, operation: &OperationSeq) {
+        let capacity = self.starts.len();
+        let old = mem::replace(&mut self.starts, Vec::with_capacity(capacity));
+        self.starts.push(0);
+        let mut old_starts = old[1..].iter().copied().peekable();
+        let (mut index, mut new_index) = (0, 0);
+        for op in operation.ops() {
+            match op {
+                &Operation::Retain(n) => {
+                    let n = n as u32;
+                    while let Some(start) = old_starts.next_if(|&start| start <= index + n) {
+                        self.starts.push(start - index + new_index);
+                    }
+                    index += n;
+                    new_index += n;
+                }
+                Operation::Insert(s) => new_index = self.push_text(new_index, s),
+                &Operation::Delete(n) => {
+                    index += n as u32;
+                    while old_starts.next_if(|&start| start <= index).is_some() {}
+                }
+            }
+        }
+        self.len = new_index;
+    }
+
+    /// Returns the offsets of the start and end of a line, excluding its
+    /// line break.
+    fn line_bounds(&self, line: usize) -> (u32, u32) {
+        let start = self.starts[line];
+        let end = match self.starts.get(line + 1) {
+            Some(&next) => next - 1,
+            None => self.len,
+        };
+        (start, end)
+    }
+
+    /// Record the line breaks in text inserted at an offset, returning the
+    /// offset after it.
+    fn push_text(&mut self, mut offset: u32, text: &str) -> u32 {
+        for c in text.chars() {
+            offset += 1;
+            if c == '\n' {
+                self.starts.push(offset);
+            }
+        }
+        offset
+    }
+}
@@ -0,0 +1,281 @@
+//! Helper methods for working with operational transformation.
+
+use anyhow::{bail, Result};
+use operational_transform::{Operation, OperationSeq};
+use ropey::Rope;
+
+use crate::protocol::{Affinity, CursorData};
+
+/// Apply an operation to a rope in place.
+///
+/// Unlike [`OperationSeq::apply`], which builds a new string, this only touches
+/// the parts of the text being changed, so its cost scales with the size of
+/// the edit rather than the size of the document. The rope is left unchanged
+/// if the operation does not apply to it.
+pub fn apply_rope(operation: &OperationSeq, rope: &mut Rope) -> Result<()> {
+    if operation.base_len() != rope.len_chars() {
+        bail!(
+            "operation base length {} does not match text length {}",
+            operation.base_len(),
+            rope.len_chars()
+        );
+    }
+    let mut index = 0;
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => index += n as usize,
+            Operation::Insert(s) => {
+                rope.insert(index, s);
+                index += bytecount::num_chars(s.as_bytes());
+            }
+            &Operation::Delete(n) => rope.remove(index..index + n as usize),
+        }
+    }
+    Ok(())
+}
+
+/// Return the new index of a position in the string.
+///
+/// Text inserted exactly at the position pushes it to the right.
+pub fn transform_index(operation: &OperationSeq, position: u32) -> u32 {
+    transform_index_with_affinity(operation, position, Affinity::Right)
+}
+
+/// Return the new index of a position in the string, where `affinity` decides
+/// which side of the position text inserted exactly at it goes on.
+pub fn transform_index_with_affinity(
+    operation: &OperationSeq,
+    position: u32,
+    affinity: Affinity,
+) -> u32 {
+    let mut index = position as i32;
+    let mut new_index = index;
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => index -= n as i32,
+            Operation::Insert(s) => {
+                if index > 0 || affinity == Affinity::Right {
+                    new_index += bytecount::num_chars(s.as_bytes()) as i32;
+                }
+            }
+            &Operation::Delete(n) => {
+                new_index -= std::cmp::min(index, n as i32);
+                index -= n as i32;
+            }
+        }
+        if index < 0 {
+            break;
+        }
+    }
+    new_index as u32
+}
+
+/// Transform the positions of a user's cursors and selections, honoring their
+/// affinities.
+pub fn transform_cursors(operation: &OperationSeq, data: &mut CursorData) {
+    for (i, cursor) in data.cursors.iter_mut().enumerate() {
+        let affinity = data.cursor_affinities.get(i).copied().unwrap_or_default();
+        *cursor = transform_index_with_affinity(operation, *cursor, affinity);
+    }
+    for (i, (start, end)) in data.selections.iter_mut().enumerate() {
+        let (a, b) = data
+            .selection_affinities
+            .get(i)
+            .copied()
+            .unwrap_or_default();
+        *start = transform_index_with_affinity(operation, *start, a);
+        *end = transform_index_with_affinity(operation, *end, b);
+    }
+}
+
+/// Return the ranges of the original string changed by an operation.
+///
+/// Deletions produce the range of deleted characters, while insertions produce
+/// an empty range at the position where text was inserted.
+pub fn changed_ranges(operation: &OperationSeq) -> Vec<(u32, u32)> {
+    let mut ranges = Vec::new();
+    let mut index = 0;
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => index += n as u32,
+            Operation::Insert(_) => ranges.push((index, index)),
+            &Operation::Delete(n) => {
+                ranges.push((index, index + n as u32));
+                index += n as u32;
+            }
+        }
+    }
+    ranges
+}
+
+/// Return the ranges of the resulting string affected by an operation.
+///
+/// Insertions produce the range of inserted characters, while deletions produce
+/// an empty range at the position where text was deleted.
+pub fn affected_ranges(operation: &OperationSeq) -> Vec<(u32, u32)> {
+    let mut ranges = Vec::new();
+    let mut index = 0;
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => index += n as u32,
+            Operation::Insert(s) => {
+                let n = bytecount::num_chars(s.as_bytes()) as u32;
+                ranges.push((index, index + n));
+                index += n;
+            }
+            Operation::Delete(_) => ranges.push((index, index)),
+        }
+    }
+    ranges
+}
+
+/// Return the ranges of the original string changed by both operations.
+///
+/// Two changes conflict if they delete overlapping text, if one inserts text
+/// strictly inside of the other's deletion, or if both insert text at the same
+/// position. Overlapping conflicts are merged into a single range.
+pub fn conflicts(a: &OperationSeq, b: &OperationSeq) -> Vec<(u32, u32)> {
+    let overlaps = |(s1, e1): (u32, u32), (s2, e2): (u32, u32)| match (s1 == e1, s2 == e2) {
+        (false, false) => s1.max(s2) < e1.min(e2),
+        (true, false) => s2 < s1 && s1 < e2,
+        (false, true) => s1 < s2 && s2 < e1,
+        (true, true) => s1 == s2,
+    };
+    let b_ranges = changed_ranges(b);
+    let mut ranges = Vec::new();
+    for x in changed_ranges(a) {
+        for &y in &b_ranges {
+            if overlaps(x, y) {
+                ranges.push((x.0.min(y.0), x.1.max(y.1)));
+            }
+        }
+    }
+    ranges.sort_unstable();
+    let mut result: Vec<(u32, u32)> = Vec::new();
+    for range in ranges {
+        match result.last_mut() {
+            Some(last) if range.0 <= last.1 => last.1 = last.1.max(range.1),
+            _ => result.push(range),
+        }
+    }
+    result
+}
+
+/// Maximum number of edits explored from each end when searching for a snake,
+/// which bounds the running time of `diff` on very different strings.
+const MAX_DIFF_DEPTH: isize = 1024;
+
+/// Return an operation that transforms one string into another, touching as
+/// few characters as it can find within a bounded amount of work.
+///
+/// This uses the linear-space variant of Myers' diff algorithm on Unicode
+/// code points, so that replacing the full text of a document only touches the
+/// characters that actually changed. The result is minimal unless a region
+/// needs more than `2 * MAX_DIFF_DEPTH` edits, in which case that region is
+/// deleted and inserted wholesale, so the result is correct but may touch
+/// more characters than necessary.
+pub fn diff(old: &str, new: &str) -> OperationSeq {
+    diff_chars(&old.chars().collect::<Vec<_>>(), new)
+}
+
+/// Return an operation that transforms a rope into a string, like [`diff`],
+/// without first copying the rope into a string.
+pub fn diff_rope(old: &Rope, new: &str) -> OperationSeq {
+    diff_chars(&old.chars().collect::<Vec<_>>(), new)
+}
+
+/// Implementation of [`diff`] on the characters of the old text.
+fn diff_chars(old: &[char], new: &str) -> OperationSeq {
+    let new: Vec<char> = new.chars().collect();
+    let mut operation = OperationSeq::default();
+    let size = 2 * (old.len() + new.len()) + 4;
+    let (mut vf, mut vb) = (vec![0; size], vec![0; size]);
+    diff_range(old, &new, &mut operation, &mut vf, &mut vb);
+    operation
+}
+
+/// Appends the operations transforming `a` into `b`, one snake at a time.
+fn diff_range(
+    a: &[char],
+    b: &[char],
+    operation: &mut OperationSeq,
+    vf: &mut [usize],
+    vb: &mut [usize],
+) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let suffix = (a.iter().rev())
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+
+    operation.retain(prefix as u64);
+    if a.is_empty() {
+        operation.insert(&b.iter().collect::<String>());
+    } else if b.is_empty() {
+        operation.delete(a.len() as u64);
+    } else if let Some((x, y)) = middle_snake(a, b, vf, vb) {
+        diff_range(&a[..x], &b[..y], operation, vf, vb);
+        diff_range(&a[x..], &b[y..], operation, vf, vb);
+    } else {
+        operation.delete(a.len() as u64);
+        operation.insert(&b.iter().collect::<String>());
+    }
+    operation.retain(suffix as u64);
+}
+
+/// Find a point on an optimal edit path from `a` to `b`, strictly between the
+/// endpoints, by searching forwards and backwards until the paths overlap.
+///
+/// Both slices must be nonempty and differ in their first and last characters.
+/// Returns `None` if no point is found within `MAX_DIFF_DEPTH` edits.
+fn middle_snake(
+    a: &[char],
+    b: &[char],
+    vf: &mut [usize],
+    vb: &mut [usize],
+) -> Option<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let offset = n + m + 1;
+    let idx = |k: isize| (k + offset) as usize;
+    vf[idx(1)] = 0;
+    vb[idx(1)] = 0;
+    for d in 0..=((n + m + 1) / 2).min(MAX_DIFF_DEPTH) {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && vf[idx(k - 1)] < vf[idx(k + 1)]) {
+                vf[idx(k + 1)] as isize
+            } else {
+                vf[idx(k - 1)] as isize + 1
+            };
+            let mut y = x - k;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            vf[idx(k)] = x as usize;
+            if delta % 2 != 0 && (delta - k).abs() < d && x + vb[idx(delta - k)] as isize >= n {
+                return Some((x0 as usize, y0 as usize));
+            }
+        }
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && vb[idx(k - 1)] < vb[idx(k + 1)]) {
+                vb[idx(k + 1)] as isize
+            } else {
+                vb[idx(k - 1)] as isize + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            vb[idx(k)] = x as usize;
+            if delta % 2 == 0 && (delta - k).abs() <= d && x + vf[idx(delta - k)] as isize >= n {
+                return Some(((n - x) as usize, (m - y) as usize));
+            }
+        }
+    }
+    None
+}
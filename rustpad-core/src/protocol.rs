@@ -0,0 +1,337 @@
+//! Message types for the WebSocket protocol between clients and the server.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+use operational_transform::OperationSeq;
+use serde::{Deserialize, Serialize};
+
+use crate::crdt::CrdtOp;
+
+/// An operation in the document history, tagged with the user who made it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserOperation {
+    /// ID of the user, or `u64::MAX` for edits made by the server itself.
+    pub id: u64,
+    /// The text operation that was applied.
+    pub operation: OperationSeq,
+}
+
+/// Information about a user, chosen by their client.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserInfo {
+    /// Display name of the user.
+    pub name: String,
+    /// Hue of the user's color, from 0 to 359.
+    pub hue: u32,
+}
+
+/// Cursor and selection positions of a user, in Unicode code points unless
+/// the connection uses UTF-16 offsets.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CursorData {
+    /// Positions of each cursor.
+    pub cursors: Vec<u32>,
+    /// Start and end positions of each selection.
+    pub selections: Vec<(u32, u32)>,
+    /// Affinity of each cursor, or `Right` if missing.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cursor_affinities: Vec<Affinity>,
+    /// Affinity of the start and end of each selection, or `Right` if missing.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub selection_affinities: Vec<(Affinity, Affinity)>,
+}
+
+/// Cursor and selection positions of a user, tagged with the user's ID.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserCursor {
+    /// ID of the user.
+    pub id: u64,
+    /// Cursor and selection positions of the user.
+    pub data: CursorData,
+}
+
+/// Which side of a position text inserted exactly at it ends up on.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Affinity {
+    /// The position sticks to the text before it, so inserted text goes after.
+    Left,
+    /// The position sticks to the text after it, so inserted text goes before.
+    #[default]
+    Right,
+}
+
+/// Whether a user is actively using the editor.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The user made changes or moved their cursor recently.
+    #[default]
+    Active,
+    /// The user has been idle for a while, though still connected.
+    Away,
+}
+
+/// How a connection counts offsets in operations and cursor positions.
+///
+/// This is chosen by the client with the `encoding` query parameter when
+/// opening the WebSocket.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Offsets are counted in Unicode code points.
+    #[default]
+    Unicode,
+    /// Offsets are counted in UTF-16 code units, like JavaScript strings.
+    Utf16,
+}
+
+/// How a document merges concurrent edits from its clients.
+///
+/// This is chosen by the first client to open a new document, with the
+/// `engine` query parameter, and is fixed for the life of the document.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    /// Operational transformation, where the server orders all edits and
+    /// clients transform theirs against edits they have not seen.
+    #[default]
+    Ot,
+    /// A sequence CRDT, where clients merge edits from each other in any
+    /// order, so they can keep editing while offline.
+    Crdt,
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Engine::Ot => "ot",
+            Engine::Crdt => "crdt",
+        })
+    }
+}
+
+impl FromStr for Engine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "ot" => Engine::Ot,
+            "crdt" => Engine::Crdt,
+            _ => bail!("unknown sync engine: {}", s),
+        })
+    }
+}
+
+/// Why the server is closing a connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The document was removed from memory after a period of inactivity.
+    Evicted,
+    /// The document was destroyed by an administrator.
+    Killed,
+    /// The server is shutting down.
+    Shutdown,
+    /// The client sent an edit that would make the document too large.
+    EditTooLarge,
+    /// The client sent an edit that could not be applied to the document.
+    InvalidOperation,
+    /// The client sent too many messages.
+    RateLimited,
+    /// The client did not accept updates as fast as they were sent.
+    SlowClient,
+}
+
+impl CloseReason {
+    /// Returns the WebSocket close code sent along with this reason.
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::Shutdown => 1001,
+            CloseReason::EditTooLarge => 1009,
+            CloseReason::Evicted => 4000,
+            CloseReason::Killed => 4001,
+            CloseReason::InvalidOperation => 4002,
+            CloseReason::SlowClient => 4008,
+            CloseReason::RateLimited => 4029,
+        }
+    }
+
+    /// Returns if a client can expect to reconnect to the same document
+    /// later, rather than finding it empty or hitting the same error again.
+    /// The web client keeps the same list in `reconnectReasons`.
+    pub fn can_reconnect(self) -> bool {
+        matches!(
+            self,
+            CloseReason::Shutdown | CloseReason::RateLimited | CloseReason::SlowClient
+        )
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::Evicted => "document was evicted after inactivity",
+            CloseReason::Killed => "document was destroyed",
+            CloseReason::Shutdown => "server is shutting down",
+            CloseReason::EditTooLarge => "edit would make the document too large",
+            CloseReason::InvalidOperation => "edit could not be applied",
+            CloseReason::RateLimited => "too many messages",
+            CloseReason::SlowClient => "client fell too far behind",
+        })
+    }
+}
+
+impl std::error::Error for CloseReason {}
+
+/// A message received from the client over WebSocket.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ClientMsg {
+    /// Represents a sequence of local edits from the user.
+    Edit {
+        /// Epoch of the document that the revision refers to. Edits made
+        /// against another instance of the document are dropped, and the
+        /// client is sent its identity and the whole document again.
+        epoch: String,
+        /// Revision of the document that the operation is based on.
+        revision: usize,
+        /// The operation to apply.
+        operation: OperationSeq,
+    },
+    /// Represents local edits from the user, in a document that uses the CRDT
+    /// engine.
+    CrdtEdit {
+        /// Epoch of the document that the operations belong to. Edits made
+        /// against another instance of the document are dropped, as for
+        /// [`ClientMsg::Edit`].
+        epoch: String,
+        /// Operations on the sequence, which may include ones that the server
+        /// has already seen.
+        ops: Vec<CrdtOp>,
+    },
+    /// Claims the sites of an earlier connection to a document that uses the
+    /// CRDT engine, so that edits made as them while offline are accepted.
+    /// This is sent after reconnecting, before any edits.
+    Resume {
+        /// Token from the identity of the earlier connection, which can only
+        /// be used once.
+        token: String,
+    },
+    /// Sets the language of the editor.
+    SetLanguage(String),
+    /// Sets the user's current information.
+    ClientInfo(UserInfo),
+    /// Sets the user's cursor and selection positions.
+    CursorData(CursorData),
+    /// Reports that the local text did not match a checksum from the server.
+    ChecksumMismatch {
+        /// Revision of the document that the checksum was taken at.
+        revision: usize,
+    },
+    /// Asks the server to reply with `Synced` to this client alone, once it
+    /// has sent everything before it, such as the initial state.
+    Sync,
+}
+
+/// A message sent to the client over WebSocket.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ServerMsg {
+    /// Informs the client of their unique socket ID, and which instance of
+    /// the document they are connected to.
+    Identity {
+        /// ID of the client.
+        id: u64,
+        /// Random ID that changes whenever the document is loaded into memory,
+        /// such as after a server restart. Revisions from an earlier epoch do
+        /// not refer to the same history.
+        epoch: String,
+        /// How the document merges concurrent edits, which decides the
+        /// messages used to edit it.
+        #[serde(default)]
+        engine: Engine,
+        /// Secret that lets the client claim its site again after it
+        /// reconnects, in documents that use the CRDT engine.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Broadcasts text operations to all clients.
+    History {
+        /// Revision of the first operation in the list.
+        start: usize,
+        /// Consecutive operations in the document history.
+        operations: Vec<UserOperation>,
+    },
+    /// Broadcasts operations on the sequence of a document that uses the CRDT
+    /// engine, or sends the whole sequence to a client that just connected.
+    CrdtEdit {
+        /// ID of the user, or `u64::MAX` for edits made by the server itself.
+        id: u64,
+        /// Operations that changed the sequence.
+        ops: Vec<CrdtOp>,
+    },
+    /// Sends a range of history as a single composed operation, to a client
+    /// that fell too far behind. None of the operations in the range were
+    /// made by the receiving client.
+    Catchup {
+        /// Revision of the first operation in the range.
+        start: usize,
+        /// Revision after the last operation in the range.
+        end: usize,
+        /// Composition of the operations in the range.
+        operation: OperationSeq,
+    },
+    /// Broadcasts a checksum of the text after some revisions, so clients can
+    /// check that their text has not diverged from the server. Only sent for
+    /// documents that use the OT engine, since CRDT clients don't track
+    /// revisions.
+    Checksum {
+        /// Revision of the document that the checksum was taken at.
+        revision: usize,
+        /// Checksum of the text, as computed by [`crate::checksum::checksum`].
+        checksum: u32,
+    },
+    /// Sends the full text of the document to a client that reported a
+    /// checksum mismatch, replacing its local text.
+    Snapshot {
+        /// Revision of the document that the text is from.
+        revision: usize,
+        /// Text of the document at that revision.
+        text: String,
+    },
+    /// Broadcasts the current language, last writer wins.
+    Language(String),
+    /// Broadcasts a user's information, or `None` on disconnect.
+    UserInfo {
+        /// ID of the user.
+        id: u64,
+        /// Information about the user, if they are still connected.
+        info: Option<UserInfo>,
+    },
+    /// Broadcasts a user's cursor position.
+    UserCursor {
+        /// ID of the user.
+        id: u64,
+        /// Cursor and selection positions of the user.
+        data: CursorData,
+    },
+    /// Broadcasts the latest cursor positions of users that moved recently,
+    /// when the server batches cursor updates.
+    UserCursors(Vec<UserCursor>),
+    /// Broadcasts a change in whether a user is active or away.
+    UserStatus {
+        /// ID of the user.
+        id: u64,
+        /// Current status of the user.
+        status: Status,
+    },
+    /// Replies to a `Sync` message from the client, after everything the
+    /// server sent before it.
+    Synced,
+    /// Tells the client why the server is about to close the connection.
+    Closing {
+        /// The cause of closing the connection.
+        reason: CloseReason,
+    },
+}
@@ -0,0 +1,169 @@
+//! Undo history that is aware of edits made by other users.
+
+use operational_transform::{Operation, OperationSeq};
+
+use crate::ot::transform_index;
+
+/// Maximum number of entries kept on the undo stack.
+const MAX_UNDO_DEPTH: usize = 1000;
+
+/// Tracks the local user's edits so that they can be undone and redone.
+///
+/// Each stack holds operations that revert an earlier change, relative to the
+/// current text. Remote operations are transformed into both stacks as they
+/// arrive, so undoing only reverts the local user's own changes and leaves the
+/// work of others intact.
+#[derive(Clone, Debug, Default)]
+pub struct UndoManager {
+    undo_stack: Vec<OperationSeq>,
+    redo_stack: Vec<OperationSeq>,
+    /// Kind and end position of the last edit, if it can be extended by typing.
+    group: Option<(EditKind, u32)>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum EditKind {
+    Insert,
+    Delete,
+}
+
+impl UndoManager {
+    /// Construct a new undo manager with empty history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns if there is a change that can be undone.
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    /// Returns if there is a change that can be redone.
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Forget all undo and redo history.
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.group = None;
+    }
+
+    /// Record a local edit, given the text before the edit was applied.
+    ///
+    /// Consecutive typing or backspacing is grouped into a single undo step,
+    /// which ends at a newline.
+    pub fn add(&mut self, operation: &OperationSeq, text: &str) {
+        if operation.is_noop() {
+            return;
+        }
+        let inverse = operation.invert(text);
+        self.redo_stack.clear();
+
+        let edit = simple_edit(operation);
+        let grouped = match (self.group, edit) {
+            (Some((prev, end)), Some((kind, start, _))) => prev == kind && end == start,
+            _ => false,
+        };
+        let top = self.undo_stack.pop();
+        match top {
+            Some(top) if grouped => match inverse.compose(&top) {
+                Ok(composed) => self.undo_stack.push(composed),
+                Err(_) => self.undo_stack.extend([top, inverse]),
+            },
+            Some(top) => self.undo_stack.extend([top, inverse]),
+            None => self.undo_stack.push(inverse),
+        }
+        if self.undo_stack.len() > MAX_UNDO_DEPTH {
+            self.undo_stack.remove(0);
+        }
+
+        let newline = operation.ops().iter().any(|op| match op {
+            Operation::Insert(s) => s.contains('\n'),
+            _ => false,
+        });
+        self.group = match edit {
+            Some((kind, _, end)) if !newline => Some((kind, end)),
+            _ => None,
+        };
+    }
+
+    /// Transform the history by a remote operation applied to the text.
+    ///
+    /// If the operation does not fit the history, the history is cleared.
+    pub fn transform(&mut self, operation: &OperationSeq) {
+        let result = transform_stack(&mut self.undo_stack, operation)
+            .and_then(|_| transform_stack(&mut self.redo_stack, operation));
+        if result.is_none() {
+            self.clear();
+            return;
+        }
+        if let Some((_, end)) = &mut self.group {
+            *end = transform_index(operation, *end);
+        }
+    }
+
+    /// Pop the last local change, given the current text.
+    ///
+    /// Returns an operation that reverts the change, which should be applied
+    /// to the text as a local edit without being passed to [`Self::add`].
+    pub fn undo(&mut self, text: &str) -> Option<OperationSeq> {
+        let operation = pop_change(&mut self.undo_stack)?;
+        self.redo_stack.push(operation.invert(text));
+        self.group = None;
+        Some(operation)
+    }
+
+    /// Pop the last undone change, given the current text.
+    ///
+    /// Returns an operation that reapplies the change, which should be applied
+    /// to the text as a local edit without being passed to [`Self::add`].
+    pub fn redo(&mut self, text: &str) -> Option<OperationSeq> {
+        let operation = pop_change(&mut self.redo_stack)?;
+        self.undo_stack.push(operation.invert(text));
+        self.group = None;
+        Some(operation)
+    }
+}
+
+/// Pop the top operation from a stack, skipping changes that other users have
+/// already reverted.
+fn pop_change(stack: &mut Vec<OperationSeq>) -> Option<OperationSeq> {
+    std::iter::from_fn(|| stack.pop()).find(|operation| !operation.is_noop())
+}
+
+/// Transform a stack of operations, each applying to the text before the one
+/// above it, by an operation that applies to the text at the top of the stack.
+fn transform_stack(stack: &mut [OperationSeq], operation: &OperationSeq) -> Option<()> {
+    let mut operation = operation.clone();
+    for entry in stack.iter_mut().rev() {
+        let (a, b) = entry.transform(&operation).ok()?;
+        *entry = a;
+        operation = b;
+    }
+    Some(())
+}
+
+/// Describe an operation that inserts or deletes at a single position.
+///
+/// Returns the kind of edit, along with its starting position and the position
+/// of the cursor after typing it.
+fn simple_edit(operation: &OperationSeq) -> Option<(EditKind, u32, u32)> {
+    let ops = operation.ops();
+    let (retain, rest) = match ops {
+        [Operation::Retain(n), rest @ ..] => (*n as u32, rest),
+        rest => (0, rest),
+    };
+    match rest {
+        [Operation::Insert(s)] | [Operation::Insert(s), Operation::Retain(_)] => {
+            let len = s.chars().count() as u32;
+            Some((EditKind::Insert, retain, retain + len))
+        }
+        // Deleting backwards ends where the previous deletion started.
+        [Operation::Delete(n)] | [Operation::Delete(n), Operation::Retain(_)] => {
+            Some((EditKind::Delete, retain + *n as u32, retain))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,212 @@
+//! Conversions between Unicode code point offsets and UTF-16 code units.
+//!
+//! Operations and cursor positions are normally counted in Unicode code
+//! points, but JavaScript strings and most editors index text by UTF-16 code
+//! units. These helpers translate between the two, given the text that the
+//! offsets refer to.
+//!
+//! An operation converted to UTF-16 is only meant to be serialized, since its
+//! base and target lengths no longer agree with its insertions.
+
+use std::str::Chars;
+
+use anyhow::{bail, Result};
+use operational_transform::{Operation, OperationSeq};
+use ropey::Rope;
+
+use crate::protocol::CursorData;
+
+/// Return the UTF-16 offset of a code point index in the text.
+///
+/// Indices past the end of the text are shifted by the same amount.
+pub fn index_to_utf16(text: &str, index: u32) -> u32 {
+    let mut offset = 0;
+    let mut chars = text.chars();
+    for _ in 0..index {
+        match chars.next() {
+            Some(c) => offset += c.len_utf16() as u32,
+            None => offset += 1,
+        }
+    }
+    offset
+}
+
+/// Return the code point index of a UTF-16 offset in the text.
+///
+/// Offsets in the middle of a surrogate pair are rounded down, and offsets
+/// past the end of the text are shifted by the same amount.
+pub fn index_from_utf16(text: &str, offset: u32) -> u32 {
+    let mut index = 0;
+    let mut units = 0;
+    let mut chars = text.chars();
+    while units < offset {
+        let len = chars.next().map_or(1, |c| c.len_utf16() as u32);
+        if units + len > offset {
+            break;
+        }
+        units += len;
+        index += 1;
+    }
+    index
+}
+
+/// Convert an operation on the text from code points to UTF-16 code units.
+pub fn operation_to_utf16(operation: &OperationSeq, text: &str) -> Result<OperationSeq> {
+    let mut chars = text.chars();
+    let mut result = OperationSeq::default();
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => result.retain(take_chars(&mut chars, n)?),
+            Operation::Insert(s) => result.insert(s),
+            &Operation::Delete(n) => result.delete(take_chars(&mut chars, n)?),
+        }
+    }
+    if chars.next().is_some() {
+        bail!("operation is shorter than the text");
+    }
+    Ok(result)
+}
+
+/// Convert an operation on the text from UTF-16 code units to code points.
+pub fn operation_from_utf16(operation: &OperationSeq, text: &str) -> Result<OperationSeq> {
+    let mut chars = text.chars();
+    let mut result = OperationSeq::default();
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => result.retain(take_utf16(&mut chars, n)?),
+            Operation::Insert(s) => result.insert(s),
+            &Operation::Delete(n) => result.delete(take_utf16(&mut chars, n)?),
+        }
+    }
+    if chars.next().is_some() {
+        bail!("operation is shorter than the text");
+    }
+    Ok(result)
+}
+
+/// Convert cursor and selection positions from code points to UTF-16.
+pub fn cursors_to_utf16(data: &CursorData, text: &str) -> CursorData {
+    map_cursors(data, |index| index_to_utf16(text, index))
+}
+
+/// Convert cursor and selection positions from UTF-16 to code points.
+pub fn cursors_from_utf16(data: &CursorData, text: &str) -> CursorData {
+    map_cursors(data, |offset| index_from_utf16(text, offset))
+}
+
+/// Convert an operation on a rope from code points to UTF-16 code units.
+///
+/// This gives the same result as [`operation_to_utf16`], but its cost scales
+/// with the number of components in the operation rather than the size of the
+/// text.
+pub fn rope_operation_to_utf16(operation: &OperationSeq, rope: &Rope) -> Result<OperationSeq> {
+    if operation.base_len() != rope.len_chars() {
+        bail!("operation does not match the length of the text");
+    }
+    let mut index = 0;
+    let mut units = |n: u64| {
+        let start = rope.char_to_utf16_cu(index);
+        index += n as usize;
+        (rope.char_to_utf16_cu(index) - start) as u64
+    };
+    let mut result = OperationSeq::default();
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => result.retain(units(n)),
+            Operation::Insert(s) => result.insert(s),
+            &Operation::Delete(n) => result.delete(units(n)),
+        }
+    }
+    Ok(result)
+}
+
+/// Convert an operation on a rope from UTF-16 code units to code points.
+///
+/// This gives the same result as [`operation_from_utf16`], but its cost scales
+/// with the number of components in the operation rather than the size of the
+/// text.
+pub fn rope_operation_from_utf16(operation: &OperationSeq, rope: &Rope) -> Result<OperationSeq> {
+    let len = rope.len_utf16_cu() as u64;
+    let (mut offset, mut index) = (0, 0);
+    let mut chars = |n: u64| {
+        offset += n;
+        if offset > len {
+            bail!("operation is longer than the text");
+        }
+        let end = rope.utf16_cu_to_char(offset as usize);
+        if rope.char_to_utf16_cu(end) as u64 != offset {
+            bail!("operation splits a surrogate pair");
+        }
+        Ok((end - std::mem::replace(&mut index, end)) as u64)
+    };
+    let mut result = OperationSeq::default();
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => result.retain(chars(n)?),
+            Operation::Insert(s) => result.insert(s),
+            &Operation::Delete(n) => result.delete(chars(n)?),
+        }
+    }
+    if offset < len {
+        bail!("operation is shorter than the text");
+    }
+    Ok(result)
+}
+
+/// Convert cursor and selection positions in a rope from code points to
+/// UTF-16, like [`cursors_to_utf16`].
+pub fn rope_cursors_to_utf16(data: &CursorData, rope: &Rope) -> CursorData {
+    let len = rope.len_chars() as u32;
+    map_cursors(data, |index| {
+        rope.char_to_utf16_cu(index.min(len) as usize) as u32 + index.saturating_sub(len)
+    })
+}
+
+/// Convert cursor and selection positions in a rope from UTF-16 to code
+/// points, like [`cursors_from_utf16`].
+pub fn rope_cursors_from_utf16(data: &CursorData, rope: &Rope) -> CursorData {
+    let len = rope.len_utf16_cu() as u32;
+    map_cursors(data, |offset| {
+        rope.utf16_cu_to_char(offset.min(len) as usize) as u32 + offset.saturating_sub(len)
+    })
+}
+
+fn map_cursors(data: &CursorData, f: impl Fn(u32) -> u32) -> CursorData {
+    let mut data = data.clone();
+    for cursor in data.cursors.iter_mut() {
+        *cursor = f(*cursor);
+    }
+    for (start, end) in data.selections.iter_mut() {
+        *start = f(*start);
+        *end = f(*end);
+    }
+    data
+}
+
+/// Consume `n` code points, returning their length in UTF-16 code units.
+fn take_chars(chars: &mut Chars, n: u64) -> Result<u64> {
+    let mut units = 0;
+    for _ in 0..n {
+        match chars.next() {
+            Some(c) => units += c.len_utf16() as u64,
+            None => bail!("operation is longer than the text"),
+        }
+    }
+    Ok(units)
+}
+
+/// Consume `n` UTF-16 code units, returning their length in code points.
+fn take_utf16(chars: &mut Chars, n: u64) -> Result<u64> {
+    let (mut units, mut count) = (0, 0);
+    while units < n {
+        match chars.next() {
+            Some(c) => units += c.len_utf16() as u64,
+            None => bail!("operation is longer than the text"),
+        }
+        count += 1;
+    }
+    if units > n {
+        bail!("operation splits a surrogate pair");
+    }
+    Ok(count)
+}
@@ -0,0 +1,19 @@
+//! Tests for checksums of document text.
+
+use rustpad_core::checksum::checksum;
+
+#[test]
+fn test_known_values() {
+    // Test vectors for 32-bit FNV-1a.
+    assert_eq!(checksum([""]), 0x811c9dc5);
+    assert_eq!(checksum(["a"]), 0xe40c292c);
+    assert_eq!(checksum(["foobar"]), 0xbf9cf968);
+}
+
+#[test]
+fn test_chunks() {
+    let text = "hello 🎉 world";
+    assert_eq!(checksum(["hello ", "🎉", " world"]), checksum([text]));
+    assert_eq!(checksum(["", text, ""]), checksum([text]));
+    assert_ne!(checksum(["hello world"]), checksum([text]));
+}
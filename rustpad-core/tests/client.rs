@@ -0,0 +1,295 @@
+//! Tests for the client-side synchronization state machine.
+
+use anyhow::Result;
+use operational_transform::OperationSeq;
+use rustpad_core::checksum::checksum;
+use rustpad_core::client::{Action, OtClient};
+use rustpad_core::protocol::{ClientMsg, UserOperation};
+
+fn insert(before: u64, text: &str, after: u64) -> OperationSeq {
+    let mut operation = OperationSeq::default();
+    operation.retain(before);
+    operation.insert(text);
+    operation.retain(after);
+    operation
+}
+
+#[test]
+fn test_local_edits() -> Result<()> {
+    let mut client = OtClient::new();
+    client.apply_identity(1, "e1".into());
+
+    let msg = client.apply_client(insert(0, "hello", 0))?;
+    assert_eq!(
+        msg,
+        Some(ClientMsg::Edit {
+            epoch: "e1".into(),
+            revision: 0,
+            operation: insert(0, "hello", 0),
+        })
+    );
+
+    // Further edits are buffered until the first is acknowledged.
+    assert_eq!(client.apply_client(insert(5, " world", 0))?, None);
+    assert_eq!(client.apply_client(insert(11, "!", 0))?, None);
+    assert_eq!(client.buffer(), Some(&insert(5, " world!", 0)));
+    assert_eq!(
+        client.resend(),
+        Some(ClientMsg::Edit {
+            epoch: "e1".into(),
+            revision: 0,
+            operation: insert(0, "hello", 0),
+        })
+    );
+
+    let actions = client.apply_history(
+        0,
+        vec![UserOperation {
+            id: 1,
+            operation: insert(0, "hello", 0),
+        }],
+    )?;
+    assert_eq!(
+        actions,
+        [
+            Action::Ack,
+            Action::Send(ClientMsg::Edit {
+                epoch: "e1".into(),
+                revision: 1,
+                operation: insert(5, " world!", 0),
+            }),
+        ]
+    );
+    assert_eq!(client.revision(), 1);
+    assert_eq!(client.buffer(), None);
+
+    let actions = client.apply_history(
+        1,
+        vec![UserOperation {
+            id: 1,
+            operation: insert(5, " world!", 0),
+        }],
+    )?;
+    assert_eq!(actions, [Action::Ack]);
+    assert_eq!(client.outstanding(), None);
+    assert_eq!(client.resend(), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_remote_edits() -> Result<()> {
+    let mut client = OtClient::new();
+    client.apply_identity(1, "e1".into());
+
+    let actions = client.apply_history(
+        0,
+        vec![UserOperation {
+            id: 0,
+            operation: insert(0, "abc", 0),
+        }],
+    )?;
+    assert_eq!(
+        actions,
+        [Action::Apply(UserOperation {
+            id: 0,
+            operation: insert(0, "abc", 0),
+        })]
+    );
+
+    // Remote operations are transformed against outstanding and buffered edits.
+    client.apply_client(insert(3, "d", 0))?;
+    client.apply_client(insert(4, "e", 0))?;
+    let history = vec![
+        UserOperation {
+            id: 0,
+            operation: insert(0, "abc", 0),
+        },
+        UserOperation {
+            id: 2,
+            operation: insert(0, "x", 3),
+        },
+    ];
+    let actions = client.apply_history(0, history)?;
+    assert_eq!(
+        actions,
+        [Action::Apply(UserOperation {
+            id: 2,
+            operation: insert(0, "x", 5),
+        })]
+    );
+    assert_eq!(client.revision(), 2);
+    assert_eq!(client.outstanding(), Some(&insert(4, "d", 0)));
+    assert_eq!(client.buffer(), Some(&insert(5, "e", 0)));
+
+    assert!(client.apply_history(3, vec![]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_unexpected_ack() {
+    let mut client = OtClient::new();
+    client.apply_identity(1, "e1".into());
+    let history = vec![UserOperation {
+        id: 1,
+        operation: insert(0, "abc", 0),
+    }];
+    assert!(client.apply_history(0, history).is_err());
+}
+
+#[test]
+fn test_catchup() -> Result<()> {
+    let mut client = OtClient::new();
+    client.apply_identity(1, "e1".into());
+    client.apply_history(
+        0,
+        vec![UserOperation {
+            id: 0,
+            operation: insert(0, "abc", 0),
+        }],
+    )?;
+
+    // A composed range of history counts as every revision in it.
+    client.apply_client(insert(3, "d", 0))?;
+    let actions = client.apply_catchup(1, 4, insert(0, "xyz", 3))?;
+    assert_eq!(
+        actions,
+        [Action::Apply(UserOperation {
+            id: u64::MAX,
+            operation: insert(0, "xyz", 4),
+        })]
+    );
+    assert_eq!(client.revision(), 4);
+    assert_eq!(client.outstanding(), Some(&insert(6, "d", 0)));
+
+    assert_eq!(client.apply_catchup(2, 4, insert(0, "xyz", 3))?, []);
+    assert!(client.apply_catchup(5, 6, insert(0, "!", 7)).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_epoch() -> Result<()> {
+    let mut client = OtClient::new();
+
+    // Edits made before connecting are sent once the epoch is known.
+    let msg = client.apply_client(insert(0, "hi", 0))?;
+    assert!(matches!(msg, Some(ClientMsg::Edit { epoch, .. }) if epoch.is_empty()));
+    let actions = client.apply_identity(0, "e1".into());
+    assert_eq!(
+        actions,
+        [Action::Send(ClientMsg::Edit {
+            epoch: "e1".into(),
+            revision: 0,
+            operation: insert(0, "hi", 0),
+        })]
+    );
+    client.apply_history(
+        0,
+        vec![UserOperation {
+            id: 1,
+            operation: insert(0, "abc", 0),
+        }],
+    )?;
+
+    // Reconnecting to the same instance of the document keeps local state.
+    assert_eq!(client.apply_identity(2, "e1".into()), []);
+    assert_eq!(client.revision(), 1);
+    assert_eq!(client.outstanding(), Some(&insert(0, "hi", 3)));
+
+    // After the server reloads the document, history starts over.
+    assert_eq!(client.apply_identity(0, "e2".into()), [Action::Reset]);
+    assert_eq!(client.epoch(), Some("e2"));
+    assert_eq!(client.revision(), 0);
+    assert_eq!(client.outstanding(), None);
+    let actions = client.apply_history(
+        0,
+        vec![UserOperation {
+            id: u64::MAX,
+            operation: insert(0, "abc", 0),
+        }],
+    )?;
+    assert_eq!(
+        actions,
+        [Action::Apply(UserOperation {
+            id: u64::MAX,
+            operation: insert(0, "abc", 0),
+        })]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_checksum() -> Result<()> {
+    let mut client = OtClient::new();
+    client.apply_identity(1, "e1".into());
+    client.apply_history(
+        0,
+        vec![UserOperation {
+            id: 0,
+            operation: insert(0, "abc", 0),
+        }],
+    )?;
+
+    assert_eq!(client.check(1, checksum(["abc"]), "abc"), None);
+    assert_eq!(
+        client.check(1, checksum(["abc"]), "abd"),
+        Some(ClientMsg::ChecksumMismatch { revision: 1 })
+    );
+    // Checksums from other revisions, or with local edits in flight, are
+    // not comparable with the local text.
+    assert_eq!(client.check(2, checksum(["abc"]), "abd"), None);
+    client.apply_client(insert(3, "!", 0))?;
+    assert_eq!(client.check(1, checksum(["abc"]), "abd!"), None);
+
+    // A snapshot replaces the text. Local edits refer to the diverged text,
+    // so they are dropped, even the one in flight.
+    client.apply_client(insert(4, "?", 0))?;
+    assert!(client.apply_snapshot(0, "abc").is_err());
+    let actions = client.apply_snapshot(1, "abc")?;
+    assert_eq!(
+        actions,
+        [
+            Action::Reset,
+            Action::Apply(UserOperation {
+                id: u64::MAX,
+                operation: insert(0, "abc", 0),
+            }),
+        ]
+    );
+    assert_eq!(client.outstanding(), None);
+    assert_eq!(client.buffer(), None);
+
+    // The edit in flight is still applied by the server, so once it is
+    // acknowledged, the server's version of it is applied locally.
+    let msg = client.apply_client(insert(0, "<", 3))?;
+    assert!(matches!(msg, Some(ClientMsg::Edit { revision: 1, .. })));
+    let actions = client.apply_history(
+        1,
+        vec![UserOperation {
+            id: 1,
+            operation: insert(3, "!", 0),
+        }],
+    )?;
+    assert_eq!(
+        actions,
+        [Action::Apply(UserOperation {
+            id: 1,
+            operation: insert(4, "!", 0),
+        })]
+    );
+    assert_eq!(client.check(2, checksum(["abc!"]), "<abc!"), None);
+    let actions = client.apply_history(
+        2,
+        vec![UserOperation {
+            id: 1,
+            operation: insert(0, "<", 4),
+        }],
+    )?;
+    assert_eq!(actions, [Action::Ack]);
+    assert_eq!(client.check(3, checksum(["<abc!"]), "<abc!"), None);
+
+    Ok(())
+}
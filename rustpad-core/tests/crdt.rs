@@ -0,0 +1,384 @@
+//! Tests for the sequence CRDT and its client.
+
+use anyhow::Result;
+use operational_transform::OperationSeq;
+use rustpad_core::client::Action;
+use rustpad_core::crdt::{CrdtClient, CrdtOp, ItemId, Merged, Sequence};
+use rustpad_core::protocol::{ClientMsg, UserOperation};
+
+fn insert(before: u64, text: &str, after: u64) -> OperationSeq {
+    let mut operation = OperationSeq::default();
+    operation.retain(before);
+    operation.insert(text);
+    operation.retain(after);
+    operation
+}
+
+fn delete(before: u64, n: u64, after: u64) -> OperationSeq {
+    let mut operation = OperationSeq::default();
+    operation.retain(before);
+    operation.delete(n);
+    operation.retain(after);
+    operation
+}
+
+/// Apply remote operations, checking that the returned operation turns the
+/// old text into the new text.
+fn merge(sequence: &mut Sequence, ops: &[CrdtOp]) -> Result<Merged> {
+    let old = sequence.text();
+    let merged = sequence.apply_remote(ops.to_vec())?;
+    assert_eq!(merged.operation.apply(&old)?, sequence.text());
+    Ok(merged)
+}
+
+#[test]
+fn test_local_edits() -> Result<()> {
+    let mut a = Sequence::new(1);
+    let ops = a.apply_local(&insert(0, "hello", 0))?;
+    assert_eq!(
+        ops,
+        [CrdtOp::Insert {
+            id: ItemId { clock: 1, site: 1 },
+            origin: None,
+            text: "hello".into(),
+        }]
+    );
+    let ops2 = a.apply_local(&delete(1, 3, 1))?;
+    assert_eq!(
+        ops2,
+        [CrdtOp::Delete {
+            id: ItemId { clock: 2, site: 1 },
+            len: 3,
+        }]
+    );
+    let ops3 = a.apply_local(&insert(1, "🎉", 1))?;
+    assert_eq!(a.text(), "h🎉o");
+    assert!(a.apply_local(&insert(0, "x", 0)).is_err());
+
+    let mut b = Sequence::new(2);
+    assert_eq!(merge(&mut b, &ops)?.operation, insert(0, "hello", 0));
+    assert_eq!(merge(&mut b, &ops2)?.operation, delete(1, 3, 1));
+    assert_eq!(merge(&mut b, &ops3)?.operation, insert(1, "🎉", 1));
+    assert_eq!(b.text(), "h🎉o");
+    assert_eq!(b.len(), 3);
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_inserts() -> Result<()> {
+    let mut a = Sequence::new(1);
+    let base = a.apply_local(&insert(0, "henlo", 0))?;
+    let mut b = Sequence::new(2);
+    merge(&mut b, &base)?;
+
+    // Both sites insert at the start, and one also edits inside the text.
+    let ops_a = a.apply_local(&insert(0, "~rust~", 5))?;
+    let ops_b = b.apply_local(&insert(0, "hey ", 5))?;
+    let ops_b2 = b.apply_local(&insert(6, "XX", 3))?;
+
+    merge(&mut a, &ops_b)?;
+    merge(&mut a, &ops_b2)?;
+    merge(&mut b, &ops_a)?;
+    assert_eq!(a.text(), b.text());
+    // Runs inserted at the same place are ordered by descending ID, and the
+    // clocks tie here, so the higher site goes first.
+    assert_eq!(a.text(), "hey ~rust~heXXnlo");
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_deletes() -> Result<()> {
+    let mut a = Sequence::new(1);
+    let base = a.apply_local(&insert(0, "abcdef", 0))?;
+    let mut b = Sequence::new(2);
+    merge(&mut b, &base)?;
+
+    // Overlapping deletions, and an insertion into a deleted range.
+    let ops_a = a.apply_local(&delete(1, 3, 2))?;
+    let ops_b = b.apply_local(&delete(2, 3, 1))?;
+    let ops_b2 = b.apply_local(&insert(2, "!", 1))?;
+
+    let merged = merge(&mut a, &[ops_b, ops_b2].concat())?;
+    assert_eq!(merged.operation, {
+        let mut operation = delete(1, 1, 0);
+        operation.insert("!");
+        operation.retain(1);
+        operation
+    });
+    merge(&mut b, &ops_a)?;
+    assert_eq!(a.text(), "a!f");
+    assert_eq!(b.text(), "a!f");
+    Ok(())
+}
+
+#[test]
+fn test_delivery_order() -> Result<()> {
+    // Three sites make concurrent edits on a shared base, then each gets the
+    // others' edits in every possible order, and every batch in every order.
+    let mut base = Sequence::new(0);
+    let base_ops = base.apply_local(&insert(0, "hello world", 0))?;
+
+    let mut edits = Vec::new();
+    for (site, operation) in [
+        (1, insert(5, ",", 6)),
+        (2, delete(0, 6, 5)),
+        (3, insert(11, "!", 0)),
+    ] {
+        let mut sequence = Sequence::new(site);
+        merge(&mut sequence, &base_ops)?;
+        let mut ops = sequence.apply_local(&operation)?;
+        // Follow up with an edit that depends on the first one.
+        let len = sequence.len() as u64;
+        ops.extend(sequence.apply_local(&insert(len, "?", 0))?);
+        edits.push(ops);
+    }
+
+    let orders = [
+        [0, 1, 2],
+        [0, 2, 1],
+        [1, 0, 2],
+        [1, 2, 0],
+        [2, 0, 1],
+        [2, 1, 0],
+    ];
+    let mut texts = Vec::new();
+    for order in orders {
+        for reverse in [false, true] {
+            let mut sequence = Sequence::new(9);
+            merge(&mut sequence, &base_ops)?;
+            for i in order {
+                let mut ops = edits[i].clone();
+                if reverse {
+                    ops.reverse();
+                }
+                merge(&mut sequence, &ops)?;
+            }
+            assert_eq!(sequence.pending(), 0);
+            texts.push(sequence.text());
+        }
+    }
+    assert!(texts.iter().all(|text| *text == texts[0]));
+    // The comma survives, since it was inserted after text that was deleted
+    // concurrently, and the runs at the end are ordered by descending ID.
+    assert_eq!(texts[0], ",world?!??");
+    Ok(())
+}
+
+#[test]
+fn test_causal_buffering() -> Result<()> {
+    let mut a = Sequence::new(1);
+    let ops1 = a.apply_local(&insert(0, "ab", 0))?;
+    let ops2 = a.apply_local(&insert(1, "X", 1))?;
+    let ops3 = a.apply_local(&delete(1, 1, 1))?;
+
+    // Operations that refer to unseen characters wait for them.
+    let mut b = Sequence::new(2);
+    assert!(merge(&mut b, &ops3)?.operation.is_noop());
+    assert!(merge(&mut b, &ops2)?.ops.is_empty());
+    assert_eq!(b.pending(), 2);
+    assert!(b.check_remote(&ops2, |_| true).is_err());
+
+    let merged = merge(&mut b, &ops1)?;
+    assert_eq!(b.pending(), 0);
+    assert_eq!(b.text(), "ab");
+    assert_eq!(merged.ops.len(), 3);
+    Ok(())
+}
+
+#[test]
+fn test_idempotent() -> Result<()> {
+    let mut a = Sequence::new(1);
+    let mut ops = a.apply_local(&insert(0, "hello", 0))?;
+    ops.extend(a.apply_local(&insert(2, "__", 3))?);
+    ops.extend(a.apply_local(&delete(0, 3, 4))?);
+    assert_eq!(a.text(), "_llo");
+
+    let mut b = Sequence::new(2);
+    assert_eq!(b.check_remote(&ops, |_| true)?, 7);
+    assert!(b.check_remote(&ops, |site| site != 1).is_err());
+    merge(&mut b, &ops)?;
+    // Characters that are already known can be from any site.
+    assert_eq!(b.check_remote(&ops, |_| false)?, 0);
+    let merged = merge(&mut b, &ops)?;
+    assert!(merged.operation.is_noop());
+    assert!(merged.ops.is_empty());
+
+    // The state of a sequence rebuilds it, and only contains what is new to
+    // a replica that saw part of it.
+    let state = a.state();
+    let mut c = Sequence::new(3);
+    merge(&mut c, &state)?;
+    assert_eq!(c.text(), "_llo");
+    assert_eq!(c.state(), state);
+    let more = a.apply_local(&insert(4, "!", 0))?;
+    let merged = merge(&mut c, &a.state())?;
+    assert_eq!(merged.ops, more);
+    Ok(())
+}
+
+#[test]
+fn test_invalid_ops() {
+    let mut a = Sequence::new(1);
+    let backwards = CrdtOp::Insert {
+        id: ItemId { clock: 1, site: 1 },
+        origin: Some(ItemId { clock: 2, site: 2 }),
+        text: "x".into(),
+    };
+    assert!(a.apply_remote(vec![backwards.clone()]).is_err());
+    assert!(a.check_remote(&[backwards], |_| true).is_err());
+
+    let overflow = CrdtOp::Delete {
+        id: ItemId {
+            clock: u64::MAX,
+            site: 1,
+        },
+        len: 2,
+    };
+    assert!(a.apply_remote(vec![overflow]).is_err());
+    assert_eq!(a.pending(), 0);
+}
+
+#[test]
+fn test_random_convergence() -> Result<()> {
+    // A small xorshift generator, so that failures are reproducible.
+    let mut state = 0x2545f4914f6cdd1d_u64;
+    let mut rand = move |n: u64| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % n.max(1)
+    };
+
+    let mut sites: Vec<_> = (0..4).map(Sequence::new).collect();
+    // Operations each site has made, and how many of them each other site
+    // has received so far.
+    let mut logs: Vec<Vec<CrdtOp>> = vec![Vec::new(); sites.len()];
+    let mut received = vec![vec![0; sites.len()]; sites.len()];
+    for _ in 0..400 {
+        let i = rand(4) as usize;
+        if rand(3) == 0 {
+            // Deliver part of another site's log.
+            let j = rand(4) as usize;
+            let end = received[i][j] + rand((logs[j].len() - received[i][j]) as u64 + 1) as usize;
+            let ops = logs[j][received[i][j]..end].to_vec();
+            merge(&mut sites[i], &ops)?;
+            received[i][j] = end;
+            continue;
+        }
+        let len = sites[i].len() as u64;
+        let pos = rand(len + 1);
+        let operation = if len > 0 && rand(2) == 0 {
+            let n = 1 + rand((len - pos).min(3));
+            delete(pos.min(len - n), n, len - pos.min(len - n) - n)
+        } else {
+            let text = ["a", "bc", "🦀", "\n"][rand(4) as usize];
+            insert(pos, text, len - pos)
+        };
+        let ops = sites[i].apply_local(&operation)?;
+        logs[i].extend(ops);
+    }
+
+    // Deliver everything else, with each log in reverse.
+    for i in 0..sites.len() {
+        for j in 0..sites.len() {
+            let mut ops = logs[j][received[i][j]..].to_vec();
+            ops.reverse();
+            merge(&mut sites[i], &ops)?;
+        }
+    }
+    for site in &sites {
+        assert_eq!(site.pending(), 0);
+        assert_eq!(site.text(), sites[0].text());
+    }
+    assert!(!sites[0].is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_long_text() -> Result<()> {
+    // Edits spread over a text much longer than a chunk, checked against a
+    // plain string on both the editing and the merging replica.
+    let mut a = Sequence::new(1);
+    let mut b = Sequence::new(2);
+    let mut text = "0123456789".repeat(500);
+    merge(&mut b, &a.apply_local(&insert(0, &text, 0))?)?;
+    for i in 0..500 {
+        let len = text.chars().count() as u64;
+        let pos = (i * 7919) % (len - 20);
+        let operation = if i % 3 == 0 {
+            delete(pos, 20, len - pos - 20)
+        } else {
+            insert(pos, "abc", len - pos)
+        };
+        text = operation.apply(&text)?;
+        let ops = a.apply_local(&operation)?;
+        merge(&mut b, &ops)?;
+        assert_eq!(a.len(), text.chars().count());
+    }
+    assert_eq!(a.text(), text);
+    assert_eq!(b.text(), text);
+    assert_eq!(b.tombstones(), 167 * 20);
+    assert_eq!(b.state(), a.state());
+    Ok(())
+}
+
+#[test]
+fn test_client() -> Result<()> {
+    let mut client = CrdtClient::new();
+
+    // Edits wait for an identity, which gives the client its site.
+    assert_eq!(client.apply_client(insert(0, "hi", 0))?, None);
+    let actions = client.apply_identity(4, "e1".into(), Some("t1".into()))?;
+    let ops = vec![CrdtOp::Insert {
+        id: ItemId { clock: 1, site: 4 },
+        origin: None,
+        text: "hi".into(),
+    }];
+    assert_eq!(
+        actions,
+        [Action::Send(ClientMsg::CrdtEdit {
+            epoch: "e1".into(),
+            ops: ops.clone(),
+        })]
+    );
+
+    // Echoes of our own edits change nothing.
+    assert_eq!(client.apply_edit(4, ops.clone())?, []);
+    let mut other = Sequence::new(5);
+    other.apply_remote(ops)?;
+    let remote = other.apply_local(&insert(2, "!", 0))?;
+    assert_eq!(
+        client.apply_edit(5, remote)?,
+        [Action::Apply(UserOperation {
+            id: 5,
+            operation: insert(2, "!", 0),
+        })]
+    );
+
+    // Edits made while disconnected are sent with everything else after
+    // reconnecting to the same document.
+    let msg = client.apply_client(insert(3, "?", 0))?;
+    assert!(matches!(msg, Some(ClientMsg::CrdtEdit { .. })));
+    let actions = client.apply_identity(7, "e1".into(), Some("t2".into()))?;
+    assert_eq!(
+        actions,
+        [
+            Action::Send(ClientMsg::Resume { token: "t1".into() }),
+            Action::Send(ClientMsg::CrdtEdit {
+                epoch: "e1".into(),
+                ops: client.sequence().state(),
+            })
+        ]
+    );
+    assert_eq!(client.sequence().site(), 7);
+    assert_eq!(client.sequence().text(), "hi!?");
+
+    // A new epoch starts over.
+    assert_eq!(
+        client.apply_identity(0, "e2".into(), Some("t3".into()))?,
+        [Action::Reset]
+    );
+    assert!(client.sequence().is_empty());
+    assert_eq!(client.epoch(), Some("e2"));
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! Tests for the line index.
+
+use anyhow::Result;
+use operational_transform::OperationSeq;
+use rustpad_core::lines::LineIndex;
+
+#[test]
+fn test_positions() {
+    let index = LineIndex::new("ab\n🎉\n\nxyz");
+    assert_eq!(index.len_lines(), 4);
+    assert_eq!(index.len_chars(), 9);
+    assert_eq!(index.line_start(1), Some(3));
+    assert_eq!(index.line_start(4), None);
+
+    assert_eq!(index.position(0), (0, 0));
+    assert_eq!(index.position(2), (0, 2));
+    assert_eq!(index.position(3), (1, 0));
+    assert_eq!(index.position(4), (1, 1));
+    assert_eq!(index.position(5), (2, 0));
+    assert_eq!(index.position(8), (3, 2));
+    assert_eq!(index.position(100), (3, 3));
+
+    assert_eq!(index.offset(1, 1), 4);
+    assert_eq!(index.offset(1, 5), 4);
+    assert_eq!(index.offset(3, 1), 7);
+    assert_eq!(index.offset(9, 9), 9);
+
+    assert_eq!(index.range_of_lines(1, 3), (3, 6));
+    assert_eq!(index.range_of_lines(3, 10), (6, 9));
+    assert_eq!(index.range_of_lines(5, 10), (9, 9));
+
+    let text = "ab\n🎉\n\nxyz";
+    assert_eq!(index.slice_lines(text, 1, 3), "🎉\n\n");
+    assert_eq!(index.slice_lines(text, 0, 1), "ab\n");
+    assert_eq!(index.slice_lines(text, 3, 4), "xyz");
+    assert_eq!(index.slice_lines(text, 2, 2), "");
+    assert_eq!(index.slice_lines(text, 5, 10), "");
+
+    let empty = LineIndex::new("");
+    assert_eq!(empty.len_lines(), 1);
+    assert_eq!(empty.position(0), (0, 0));
+    assert_eq!(empty.offset(0, 3), 0);
+}
+
+#[test]
+fn test_apply() -> Result<()> {
+    let mut text = String::from("one\ntwo\nthree\n");
+    let mut index = LineIndex::new(&text);
+
+    let edits: [&[(u64, &str, u64)]; 4] = [
+        &[(4, "", 4)],
+        &[(0, "zero\n", 0), (3, "\n", 0)],
+        &[(2, "", 0), (8, "🎉\n🎉", 3)],
+        &[(0, "", 0)],
+    ];
+    for edit in edits {
+        let len = text.chars().count() as u64;
+        let mut operation = OperationSeq::default();
+        let mut consumed = 0;
+        for &(delete, insert, retain) in edit {
+            operation.delete(delete);
+            operation.insert(insert);
+            operation.retain(retain);
+            consumed += delete + retain;
+        }
+        operation.retain(len - consumed);
+        text = operation.apply(&text)?;
+        index.apply(&operation);
+        assert_eq!(index, LineIndex::new(&text), "text = {:?}", text);
+    }
+    Ok(())
+}
@@ -0,0 +1,226 @@
+//! Tests for operational transformation helpers.
+
+use operational_transform::OperationSeq;
+use ropey::Rope;
+use rustpad_core::ot::{
+    affected_ranges, apply_rope, changed_ranges, conflicts, diff, diff_rope, transform_cursors,
+    transform_index, transform_index_with_affinity,
+};
+use rustpad_core::protocol::{Affinity, CursorData};
+
+#[test]
+fn test_transform_index() {
+    let mut o = OperationSeq::default();
+    o.retain(3);
+    o.insert("def");
+    o.retain(3);
+    o.insert("abc");
+    assert_eq!(transform_index(&o, 2), 2);
+    assert_eq!(transform_index(&o, 3), 6);
+    assert_eq!(transform_index(&o, 5), 8);
+    assert_eq!(transform_index(&o, 7), 13);
+
+    let mut o = OperationSeq::default();
+    o.retain(2);
+    o.delete(3);
+    o.insert("🎉");
+    o.retain(1);
+    assert_eq!(transform_index(&o, 1), 1);
+    assert_eq!(transform_index(&o, 3), 3);
+    assert_eq!(transform_index(&o, 5), 3);
+    assert_eq!(transform_index(&o, 6), 4);
+}
+
+#[test]
+fn test_transform_affinity() {
+    let mut o = OperationSeq::default();
+    o.insert("ab");
+    o.retain(3);
+    o.insert("cd");
+    o.retain(3);
+    assert_eq!(transform_index_with_affinity(&o, 0, Affinity::Left), 0);
+    assert_eq!(transform_index_with_affinity(&o, 0, Affinity::Right), 2);
+    assert_eq!(transform_index_with_affinity(&o, 3, Affinity::Left), 5);
+    assert_eq!(transform_index_with_affinity(&o, 3, Affinity::Right), 7);
+    assert_eq!(transform_index_with_affinity(&o, 4, Affinity::Left), 8);
+
+    let mut data = CursorData {
+        cursors: vec![0, 3],
+        selections: vec![(0, 3), (3, 6)],
+        cursor_affinities: vec![Affinity::Left],
+        selection_affinities: vec![(Affinity::Right, Affinity::Left)],
+    };
+    transform_cursors(&o, &mut data);
+    assert_eq!(data.cursors, [0, 7]);
+    assert_eq!(data.selections, [(2, 5), (7, 10)]);
+}
+
+#[test]
+fn test_apply_rope() {
+    let text = "h🎉llo world";
+    let mut rope = Rope::from_str(text);
+    let mut o = OperationSeq::default();
+    o.insert(">> ");
+    o.retain(2);
+    o.delete(3);
+    o.insert("y");
+    o.retain(6);
+    apply_rope(&o, &mut rope).unwrap();
+    assert_eq!(rope.to_string(), o.apply(text).unwrap());
+
+    let mut o = OperationSeq::default();
+    o.delete(3);
+    assert!(apply_rope(&o, &mut rope).is_err());
+    assert_eq!(rope.to_string(), ">> h🎉y world");
+}
+
+#[test]
+fn test_changed_ranges() {
+    let mut o = OperationSeq::default();
+    o.retain(2);
+    o.delete(3);
+    o.retain(4);
+    o.insert("abc");
+    o.retain(1);
+    assert_eq!(changed_ranges(&o), vec![(2, 5), (9, 9)]);
+}
+
+#[test]
+fn test_affected_ranges() {
+    let mut o = OperationSeq::default();
+    o.retain(2);
+    o.delete(3);
+    o.retain(4);
+    o.insert("a🎉c");
+    o.retain(1);
+    assert_eq!(affected_ranges(&o), vec![(2, 2), (6, 9)]);
+}
+
+#[test]
+fn test_conflicts() {
+    let mut a = OperationSeq::default();
+    a.retain(2);
+    a.delete(3);
+    a.retain(5);
+    let mut b = OperationSeq::default();
+    b.retain(4);
+    b.delete(4);
+    b.retain(2);
+    assert_eq!(conflicts(&a, &b), vec![(2, 8)]);
+
+    let mut c = OperationSeq::default();
+    c.retain(5);
+    c.insert("x");
+    c.retain(5);
+    assert_eq!(conflicts(&a, &c), vec![]);
+    assert_eq!(conflicts(&b, &c), vec![(4, 8)]);
+    assert_eq!(conflicts(&c, &c), vec![(5, 5)]);
+}
+
+#[test]
+fn test_diff() {
+    let o = diff("hello world", "help, world!");
+    let mut expected = OperationSeq::default();
+    expected.retain(3);
+    expected.insert("p,");
+    expected.delete(2);
+    expected.retain(6);
+    expected.insert("!");
+    assert_eq!(o, expected);
+
+    assert!(diff("same", "same").is_noop());
+    assert_eq!(diff("", "new").apply("").unwrap(), "new");
+    assert_eq!(diff("old", "").apply("old").unwrap(), "");
+    assert_eq!(
+        diff("h🎉llo", "🎉h🎉lo").apply("h🎉llo").unwrap(),
+        "🎉h🎉lo"
+    );
+
+    let rope = Rope::from_str("hello world");
+    assert_eq!(diff_rope(&rope, "help, world!"), o);
+}
+
+#[test]
+fn test_diff_minimal() {
+    // Compare against the length of the longest common subsequence.
+    fn lcs(a: &[char], b: &[char]) -> usize {
+        let mut dp = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                dp[i + 1][j + 1] = if a[i] == b[j] {
+                    dp[i][j] + 1
+                } else {
+                    dp[i][j + 1].max(dp[i + 1][j])
+                };
+            }
+        }
+        dp[a.len()][b.len()]
+    }
+
+    let words = [
+        "",
+        "a",
+        "ab",
+        "ba",
+        "abcabba",
+        "cbabac",
+        "🎉b🎉",
+        "aaaa",
+        "xyz",
+    ];
+    for old in words {
+        for new in words {
+            let o = diff(old, new);
+            assert_eq!(o.apply(old).unwrap(), new);
+            let (a, b): (Vec<_>, Vec<_>) = (old.chars().collect(), new.chars().collect());
+            let inserted = o.target_len() - (o.base_len() - deleted(&o));
+            assert_eq!(deleted(&o) + inserted, a.len() + b.len() - 2 * lcs(&a, &b));
+        }
+    }
+}
+
+/// Returns the number of characters deleted by an operation.
+fn deleted(o: &OperationSeq) -> usize {
+    changed_ranges(o)
+        .iter()
+        .map(|(s, e)| (e - s) as usize)
+        .sum()
+}
+
+#[test]
+fn test_diff_large() {
+    let old = "abc\n".repeat(50_000);
+    let mut new = old.clone();
+    new.insert_str(1000, "hello");
+    new.replace_range(100_000..100_010, "");
+    let o = diff(&old, &new);
+    assert_eq!(o.apply(&old).unwrap(), new);
+    assert_eq!(o.ops().len(), 5);
+
+    // Very different strings are replaced wholesale, rather than taking
+    // quadratic time to find a minimal diff.
+    let old: String = (0..20_000)
+        .map(|i| if i % 3 == 0 { 'x' } else { 'y' })
+        .collect();
+    let new: String = (0..20_000)
+        .map(|i| if i % 5 == 0 { 'y' } else { 'x' })
+        .collect();
+    assert_eq!(diff(&old, &new).apply(&old).unwrap(), new);
+
+    // Changing every other character stays minimal while the number of edits
+    // is within the bound ...
+    let old = "ax".repeat(500);
+    let new = "bx".repeat(500);
+    let o = diff(&old, &new);
+    assert_eq!(o.apply(&old).unwrap(), new);
+    assert_eq!(deleted(&o), 500);
+
+    // ... but past it, the whole text is replaced except for the last
+    // character, which is correct but no longer minimal.
+    let old = "ax".repeat(2500);
+    let new = "bx".repeat(2500);
+    let o = diff(&old, &new);
+    assert_eq!(o.apply(&old).unwrap(), new);
+    assert_eq!(deleted(&o), 4999);
+    assert_eq!(o.ops().len(), 3);
+}
@@ -0,0 +1,247 @@
+//! Tests for the serialization format of protocol messages.
+
+use operational_transform::OperationSeq;
+use rustpad_core::crdt::{CrdtOp, ItemId};
+use rustpad_core::protocol::{
+    Affinity, ClientMsg, CloseReason, CursorData, Engine, ServerMsg, Status, UserCursor, UserInfo,
+    UserOperation,
+};
+use serde_json::json;
+
+#[test]
+fn test_client_msg() {
+    let mut operation = OperationSeq::default();
+    operation.retain(2);
+    operation.insert("n");
+    operation.delete(1);
+    let msg = ClientMsg::Edit {
+        epoch: "8f3a".into(),
+        revision: 1,
+        operation,
+    };
+    let value = json!({ "Edit": { "epoch": "8f3a", "revision": 1, "operation": [2, "n", -1] } });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+    assert_eq!(serde_json::from_value::<ClientMsg>(value).unwrap(), msg);
+
+    let value = json!({ "ClientInfo": { "name": "Alice", "hue": 42 } });
+    let msg = ClientMsg::ClientInfo(UserInfo {
+        name: "Alice".into(),
+        hue: 42,
+    });
+    assert_eq!(serde_json::from_value::<ClientMsg>(value).unwrap(), msg);
+
+    let value = json!({ "ClientInfo": { "name": "Alice" } });
+    assert!(serde_json::from_value::<ClientMsg>(value).is_err());
+
+    let value = json!({ "Edit": { "revision": 1, "operation": [2, "n", -1] } });
+    assert!(serde_json::from_value::<ClientMsg>(value).is_err());
+
+    let msg = ClientMsg::ChecksumMismatch { revision: 100 };
+    let value = json!({ "ChecksumMismatch": { "revision": 100 } });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+    assert_eq!(serde_json::from_value::<ClientMsg>(value).unwrap(), msg);
+
+    let value = json!("Sync");
+    assert_eq!(serde_json::to_value(&ClientMsg::Sync).unwrap(), value);
+    assert_eq!(
+        serde_json::from_value::<ClientMsg>(value).unwrap(),
+        ClientMsg::Sync
+    );
+
+    let msg = ClientMsg::CrdtEdit {
+        epoch: "8f3a".into(),
+        ops: vec![
+            CrdtOp::Insert {
+                id: ItemId { clock: 3, site: 1 },
+                origin: None,
+                text: "hi".into(),
+            },
+            CrdtOp::Delete {
+                id: ItemId { clock: 1, site: 0 },
+                len: 2,
+            },
+        ],
+    };
+    let value = json!({
+        "CrdtEdit": {
+            "epoch": "8f3a",
+            "ops": [
+                { "Insert": { "id": { "clock": 3, "site": 1 }, "origin": null, "text": "hi" } },
+                { "Delete": { "id": { "clock": 1, "site": 0 }, "len": 2 } }
+            ]
+        }
+    });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+    assert_eq!(serde_json::from_value::<ClientMsg>(value).unwrap(), msg);
+
+    let msg = ClientMsg::Resume {
+        token: "c0ffee".into(),
+    };
+    let value = json!({ "Resume": { "token": "c0ffee" } });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+    assert_eq!(serde_json::from_value::<ClientMsg>(value).unwrap(), msg);
+}
+
+#[test]
+fn test_server_msg() {
+    let msg = ServerMsg::Identity {
+        id: 3,
+        epoch: "8f3a".into(),
+        engine: Engine::Crdt,
+        token: Some("c0ffee".into()),
+    };
+    let value = json!({
+        "Identity": { "id": 3, "epoch": "8f3a", "engine": "crdt", "token": "c0ffee" }
+    });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+
+    // Servers that predate sync engines only use OT.
+    let value = json!({ "Identity": { "id": 3, "epoch": "8f3a" } });
+    assert_eq!(
+        serde_json::from_value::<ServerMsg>(value).unwrap(),
+        ServerMsg::Identity {
+            id: 3,
+            epoch: "8f3a".into(),
+            engine: Engine::Ot,
+            token: None,
+        }
+    );
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = ServerMsg::History {
+        start: 0,
+        operations: vec![UserOperation { id: 0, operation }],
+    };
+    let value = json!({
+        "History": {
+            "start": 0,
+            "operations": [{ "id": 0, "operation": ["hello"] }]
+        }
+    });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert("!");
+    let msg = ServerMsg::Catchup {
+        start: 2,
+        end: 10,
+        operation,
+    };
+    let value = json!({ "Catchup": { "start": 2, "end": 10, "operation": [5, "!"] } });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+
+    let msg = ServerMsg::Checksum {
+        revision: 100,
+        checksum: 3758540268,
+    };
+    let value = json!({ "Checksum": { "revision": 100, "checksum": 3758540268u32 } });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+
+    let msg = ServerMsg::Snapshot {
+        revision: 100,
+        text: "hello".into(),
+    };
+    let value = json!({ "Snapshot": { "revision": 100, "text": "hello" } });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+
+    let msg = ServerMsg::CrdtEdit {
+        id: 2,
+        ops: vec![CrdtOp::Insert {
+            id: ItemId { clock: 5, site: 2 },
+            origin: Some(ItemId { clock: 4, site: 0 }),
+            text: "!".into(),
+        }],
+    };
+    let value = json!({
+        "CrdtEdit": {
+            "id": 2,
+            "ops": [{
+                "Insert": {
+                    "id": { "clock": 5, "site": 2 },
+                    "origin": { "clock": 4, "site": 0 },
+                    "text": "!"
+                }
+            }]
+        }
+    });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+
+    let msg = ServerMsg::UserCursor {
+        id: 1,
+        data: CursorData {
+            cursors: vec![4],
+            selections: vec![(5, 10)],
+            ..Default::default()
+        },
+    };
+    let value = json!({
+        "UserCursor": {
+            "id": 1,
+            "data": { "cursors": [4], "selections": [[5, 10]] }
+        }
+    });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+
+    let msg = ServerMsg::UserCursor {
+        id: 1,
+        data: CursorData {
+            cursors: vec![4],
+            selections: vec![(5, 10)],
+            cursor_affinities: vec![Affinity::Left],
+            selection_affinities: vec![(Affinity::Right, Affinity::Left)],
+        },
+    };
+    let value = json!({
+        "UserCursor": {
+            "id": 1,
+            "data": {
+                "cursors": [4],
+                "selections": [[5, 10]],
+                "cursor_affinities": ["left"],
+                "selection_affinities": [["right", "left"]]
+            }
+        }
+    });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+    assert_eq!(serde_json::from_value::<ServerMsg>(value).unwrap(), msg);
+
+    let msg = ServerMsg::UserCursors(vec![UserCursor {
+        id: 2,
+        data: CursorData {
+            cursors: vec![7],
+            ..Default::default()
+        },
+    }]);
+    let value = json!({
+        "UserCursors": [{ "id": 2, "data": { "cursors": [7], "selections": [] } }]
+    });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+    assert_eq!(serde_json::from_value::<ServerMsg>(value).unwrap(), msg);
+
+    let msg = ServerMsg::UserInfo { id: 1, info: None };
+    let value = json!({ "UserInfo": { "id": 1, "info": null } });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+
+    let msg = ServerMsg::UserStatus {
+        id: 1,
+        status: Status::Away,
+    };
+    let value = json!({ "UserStatus": { "id": 1, "status": "away" } });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+
+    let msg = ServerMsg::Closing {
+        reason: CloseReason::EditTooLarge,
+    };
+    let value = json!({ "Closing": { "reason": "edit_too_large" } });
+    assert_eq!(serde_json::to_value(&msg).unwrap(), value);
+    assert_eq!(serde_json::from_value::<ServerMsg>(value).unwrap(), msg);
+
+    let value = json!("Synced");
+    assert_eq!(serde_json::to_value(&ServerMsg::Synced).unwrap(), value);
+    assert_eq!(
+        serde_json::from_value::<ServerMsg>(value).unwrap(),
+        ServerMsg::Synced
+    );
+}
@@ -0,0 +1,127 @@
+//! Tests for the collaborative undo manager.
+
+use operational_transform::OperationSeq;
+use rustpad_core::undo::UndoManager;
+
+fn insert(before: u64, text: &str, after: u64) -> OperationSeq {
+    let mut operation = OperationSeq::default();
+    operation.retain(before);
+    operation.insert(text);
+    operation.retain(after);
+    operation
+}
+
+fn delete(before: u64, count: u64, after: u64) -> OperationSeq {
+    let mut operation = OperationSeq::default();
+    operation.retain(before);
+    operation.delete(count);
+    operation.retain(after);
+    operation
+}
+
+/// Apply a local edit to the text, recording it in the undo history.
+fn local(undo: &mut UndoManager, text: &mut String, operation: OperationSeq) {
+    undo.add(&operation, text);
+    *text = operation.apply(text).unwrap();
+}
+
+#[test]
+fn test_undo_redo() {
+    let mut undo = UndoManager::new();
+    let mut text = String::from("hello");
+    assert!(!undo.can_undo());
+
+    local(&mut undo, &mut text, insert(5, " world", 0));
+    local(&mut undo, &mut text, delete(0, 1, 10));
+    assert_eq!(text, "ello world");
+
+    let op = undo.undo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "hello world");
+    let op = undo.undo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "hello");
+    assert!(undo.undo(&text).is_none());
+
+    let op = undo.redo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "hello world");
+
+    // A new edit clears the redo stack.
+    local(&mut undo, &mut text, insert(11, "!", 0));
+    assert!(!undo.can_redo());
+}
+
+#[test]
+fn test_typing_groups() {
+    let mut undo = UndoManager::new();
+    let mut text = String::new();
+    for (i, c) in "ab\ncd".chars().enumerate() {
+        local(&mut undo, &mut text, insert(i as u64, &c.to_string(), 0));
+    }
+    local(&mut undo, &mut text, delete(4, 1, 0));
+    local(&mut undo, &mut text, delete(3, 1, 0));
+    assert_eq!(text, "ab\n");
+
+    let op = undo.undo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "ab\ncd");
+    let op = undo.undo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "ab\n");
+    let op = undo.undo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "");
+    assert!(!undo.can_undo());
+}
+
+#[test]
+fn test_remote_edits() {
+    let mut undo = UndoManager::new();
+    let mut text = String::from("world");
+
+    local(&mut undo, &mut text, insert(0, "hello ", 5));
+    local(&mut undo, &mut text, insert(11, "\n", 0));
+
+    // Another user edits in between our changes.
+    let remote = insert(6, "big ", 6);
+    text = remote.apply(&text).unwrap();
+    undo.transform(&remote);
+    assert_eq!(text, "hello big world\n");
+
+    let op = undo.undo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "hello big world");
+    let op = undo.undo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "big world");
+
+    let remote = delete(0, 4, 5);
+    text = remote.apply(&text).unwrap();
+    undo.transform(&remote);
+    let op = undo.redo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "hello world");
+}
+
+#[test]
+fn test_reverted_changes() {
+    let mut undo = UndoManager::new();
+    let mut text = String::from("abc");
+
+    local(&mut undo, &mut text, insert(3, "\n", 0));
+    local(&mut undo, &mut text, insert(4, "def", 0));
+
+    // Another user deletes the text we typed, so there is nothing to undo.
+    let remote = delete(4, 3, 0);
+    text = remote.apply(&text).unwrap();
+    undo.transform(&remote);
+    let op = undo.undo(&text).unwrap();
+    text = op.apply(&text).unwrap();
+    assert_eq!(text, "abc");
+
+    // A mismatched operation clears the history.
+    local(&mut undo, &mut text, insert(3, "!", 0));
+    undo.transform(&insert(0, "x", 100));
+    assert!(!undo.can_undo());
+}
@@ -0,0 +1,93 @@
+//! Tests for conversions between code points and UTF-16 code units.
+
+use anyhow::Result;
+use operational_transform::OperationSeq;
+use ropey::Rope;
+use rustpad_core::protocol::{Affinity, CursorData};
+use rustpad_core::utf16::*;
+use serde_json::json;
+
+#[test]
+fn test_index_conversion() {
+    let text = "a🎉b😍";
+    assert_eq!(index_to_utf16(text, 0), 0);
+    assert_eq!(index_to_utf16(text, 2), 3);
+    assert_eq!(index_to_utf16(text, 4), 6);
+    assert_eq!(index_to_utf16(text, 6), 8);
+
+    assert_eq!(index_from_utf16(text, 3), 2);
+    assert_eq!(index_from_utf16(text, 2), 1);
+    assert_eq!(index_from_utf16(text, 6), 4);
+    assert_eq!(index_from_utf16(text, 8), 6);
+}
+
+#[test]
+fn test_operation_conversion() -> Result<()> {
+    let text = "🎉😍𒀇";
+    let mut operation = OperationSeq::default();
+    operation.retain(1);
+    operation.insert("👯‍♂️");
+    operation.delete(1);
+    operation.retain(1);
+
+    let converted = operation_to_utf16(&operation, text)?;
+    assert_eq!(serde_json::to_value(&converted)?, json!([2, "👯‍♂️", -2, 2]));
+    assert_eq!(operation_from_utf16(&converted, text)?, operation);
+
+    let mut too_long = OperationSeq::default();
+    too_long.retain(4);
+    assert!(operation_to_utf16(&too_long, text).is_err());
+
+    let mut split = OperationSeq::default();
+    split.retain(1);
+    split.delete(5);
+    assert!(operation_from_utf16(&split, text).is_err());
+
+    let mut short = OperationSeq::default();
+    short.retain(4);
+    assert!(operation_from_utf16(&short, text).is_err());
+
+    // Ropes give the same results.
+    let rope = Rope::from_str(text);
+    assert_eq!(rope_operation_to_utf16(&operation, &rope)?, converted);
+    assert_eq!(rope_operation_from_utf16(&converted, &rope)?, operation);
+    assert!(rope_operation_to_utf16(&too_long, &rope).is_err());
+    assert!(rope_operation_from_utf16(&split, &rope).is_err());
+    assert!(rope_operation_from_utf16(&short, &rope).is_err());
+    assert!(rope_operation_from_utf16(&converted, &Rope::from_str("abc")).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_cursor_conversion() {
+    let text = "🎉🎉🎉";
+    let data = CursorData {
+        cursors: vec![0, 1, 2, 3],
+        selections: vec![(0, 1), (2, 3)],
+        selection_affinities: vec![(Affinity::Right, Affinity::Left)],
+        ..Default::default()
+    };
+    let converted = cursors_to_utf16(&data, text);
+    assert_eq!(converted.cursors, [0, 2, 4, 6]);
+    assert_eq!(converted.selections, [(0, 2), (4, 6)]);
+    assert_eq!(cursors_from_utf16(&converted, text), data);
+
+    let rope = Rope::from_str(text);
+    assert_eq!(rope_cursors_to_utf16(&data, &rope), converted);
+    assert_eq!(rope_cursors_from_utf16(&converted, &rope), data);
+
+    // Positions past the end of the text are shifted by the same amount.
+    let past_end = CursorData {
+        cursors: vec![5],
+        ..Default::default()
+    };
+    assert_eq!(rope_cursors_to_utf16(&past_end, &rope).cursors, [8]);
+    assert_eq!(cursors_to_utf16(&past_end, text).cursors, [8]);
+    let odd = CursorData {
+        cursors: vec![3, 9],
+        ..Default::default()
+    };
+    assert_eq!(rope_cursors_from_utf16(&odd, &rope).cursors, [1, 6]);
+    assert_eq!(cursors_from_utf16(&odd, text).cursors, [1, 6]);
+}
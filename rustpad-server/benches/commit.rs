@@ -0,0 +1,85 @@
+//! Benchmarks for committing edits to large documents, from the message sent
+//! by a client to the broadcast that acknowledges it.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+use warp::test::WsClient;
+
+/// Build a document of about `size` bytes, split into lines.
+fn document(size: usize) -> String {
+    "The quick brown fox jumps over the lazy dog.\n".repeat(size / 45)
+}
+
+/// Receive messages until the next one with the given name.
+async fn recv(client: &mut WsClient, name: &str) -> Value {
+    loop {
+        let msg = client.recv().await.expect("connection closed");
+        if let Ok(text) = msg.to_str() {
+            let value: Value = serde_json::from_str(text).unwrap();
+            if value.get(name).is_some() {
+                return value;
+            }
+        }
+    }
+}
+
+fn bench_commit(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    for size in [4 * 1024, 64 * 1024, 256 * 1024 - 45] {
+        let text = document(size);
+        let len = text.chars().count() as u64;
+        let filter = rt.block_on(async { server(ServerConfig::default()) });
+        let id = format!("bench{}", size);
+        let (mut client, epoch) = rt.block_on(async {
+            let resp = warp::test::request()
+                .method("PUT")
+                .path(&format!("/api/text/{}", id))
+                .body(text.clone())
+                .reply(&filter)
+                .await;
+            assert_eq!(resp.status(), 200);
+            let mut client = warp::test::ws()
+                .path(&format!("/api/socket/{}", id))
+                .handshake(filter.clone())
+                .await
+                .unwrap();
+            let identity = recv(&mut client, "Identity").await;
+            let epoch = identity["Identity"]["epoch"].clone();
+            recv(&mut client, "History").await;
+            (client, epoch)
+        });
+
+        // Overwrite a character in the middle of the document, so that its
+        // length stays the same, and wait for the server to acknowledge it.
+        let mut revision = 1;
+        let mut group = c.benchmark_group(format!("commit/{}KiB", size / 1024));
+        group.bench_function("keystroke", |b| {
+            b.iter(|| {
+                let mut operation = OperationSeq::default();
+                operation.retain(len / 2);
+                operation.delete(1);
+                operation.insert("x");
+                operation.retain(len - len / 2 - 1);
+                let msg = json!({
+                    "Edit": {
+                        "epoch": epoch,
+                        "revision": revision,
+                        "operation": operation,
+                    }
+                });
+                rt.block_on(async {
+                    client.send_text(msg.to_string()).await;
+                    recv(&mut client, "History").await;
+                });
+                revision += 1;
+            });
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_commit);
+criterion_main!(benches);
@@ -0,0 +1,6 @@
+CREATE TABLE lineage(
+    id TEXT PRIMARY KEY,
+    parent_id TEXT NOT NULL,
+    parent_revision INTEGER NOT NULL,
+    base TEXT NOT NULL
+)
@@ -0,0 +1 @@
+ALTER TABLE document ADD COLUMN engine TEXT NOT NULL DEFAULT 'ot'
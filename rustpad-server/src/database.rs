@@ -3,15 +3,32 @@
 use std::str::FromStr;
 
 use anyhow::{bail, Result};
+use rustpad_core::protocol::Engine;
 use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, SqlitePool};
 
 /// Represents a document persisted in database storage.
-#[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub struct PersistedDocument {
     /// Text content of the document.
     pub text: String,
     /// Language of the document for editor syntax highlighting.
     pub language: Option<String>,
+    /// How the document merges concurrent edits from clients.
+    pub engine: Engine,
+}
+
+/// Records the origin of a document that was forked from another document.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Lineage {
+    /// ID of the parent document.
+    pub parent_id: String,
+    /// Revision of the parent when the document was forked or last merged.
+    /// Revisions restart when the parent is reloaded, so this is only a
+    /// record, and merges are measured against `base`.
+    pub parent_revision: usize,
+    /// Text that the document and its parent had in common when it was forked
+    /// or last merged, which changes on both sides are measured against.
+    pub base: String,
 }
 
 /// A driver for database operations wrapping a pool connection.
@@ -38,11 +55,16 @@ impl Database {
 
     /// Load the text of a document from the database.
     pub async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
-        sqlx::query_as(r#"SELECT text, language FROM document WHERE id = $1"#)
-            .bind(document_id)
-            .fetch_one(&self.pool)
-            .await
-            .map_err(|e| e.into())
+        let row: (String, Option<String>, String) =
+            sqlx::query_as(r#"SELECT text, language, engine FROM document WHERE id = $1"#)
+                .bind(document_id)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(PersistedDocument {
+            text: row.0,
+            language: row.1,
+            engine: row.2.parse()?,
+        })
     }
 
     /// Store the text of a document in the database.
@@ -50,16 +72,18 @@ impl Database {
         let result = sqlx::query(
             r#"
 INSERT INTO
-    document (id, text, language)
+    document (id, text, language, engine)
 VALUES
-    ($1, $2, $3)
+    ($1, $2, $3, $4)
 ON CONFLICT(id) DO UPDATE SET
     text = excluded.text,
-    language = excluded.language"#,
+    language = excluded.language,
+    engine = excluded.engine"#,
         )
         .bind(document_id)
         .bind(&document.text)
         .bind(&document.language)
+        .bind(document.engine.to_string())
         .execute(&self.pool)
         .await?;
         if result.rows_affected() != 1 {
@@ -71,6 +95,62 @@ ON CONFLICT(id) DO UPDATE SET
         Ok(())
     }
 
+    /// Load the lineage of a forked document from the database.
+    pub async fn load_lineage(&self, document_id: &str) -> Result<Lineage> {
+        let row: (String, i64, String) =
+            sqlx::query_as(r#"SELECT parent_id, parent_revision, base FROM lineage WHERE id = $1"#)
+                .bind(document_id)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(Lineage {
+            parent_id: row.0,
+            parent_revision: row.1 as usize,
+            base: row.2,
+        })
+    }
+
+    /// Store the lineage of a forked document in the database.
+    pub async fn store_lineage(&self, document_id: &str, lineage: &Lineage) -> Result<()> {
+        let result = sqlx::query(
+            r#"
+INSERT INTO
+    lineage (id, parent_id, parent_revision, base)
+VALUES
+    ($1, $2, $3, $4)
+ON CONFLICT(id) DO UPDATE SET
+    parent_id = excluded.parent_id,
+    parent_revision = excluded.parent_revision,
+    base = excluded.base"#,
+        )
+        .bind(document_id)
+        .bind(&lineage.parent_id)
+        .bind(lineage.parent_revision as i64)
+        .bind(&lineage.base)
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() != 1 {
+            bail!(
+                "expected store_lineage() to receive 1 row affected, but it affected {} rows instead",
+                result.rows_affected(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Delete a document and its lineage from the database, returning whether
+    /// the document existed.
+    pub async fn delete(&self, document_id: &str) -> Result<bool> {
+        let result = sqlx::query(r#"DELETE FROM document WHERE id = $1"#)
+            .bind(document_id)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(r#"DELETE FROM lineage WHERE id = $1"#)
+            .bind(document_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
     /// Count the number of documents in the database.
     pub async fn count(&self) -> Result<usize> {
         let row: (i64,) = sqlx::query_as("SELECT count(*) FROM document")
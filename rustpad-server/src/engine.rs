@@ -0,0 +1,396 @@
+//! Sync engines, which decide how a document merges edits from its clients.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use operational_transform::{Operation, OperationSeq};
+use ropey::Rope;
+use tokio::time::Instant;
+
+use rustpad_core::crdt::{Merged, Sequence};
+use rustpad_core::protocol::{ClientMsg, CloseReason, Engine, ServerMsg, UserOperation};
+
+/// Maximum length of the text of a document, in characters.
+const MAX_LEN: usize = 256 * 1024;
+
+/// Maximum number of deleted characters kept by a CRDT document, past which
+/// it stops accepting new characters.
+const MAX_TOMBSTONES: usize = 16 * MAX_LEN;
+
+/// How long a client that disconnected from a CRDT document can take to
+/// resume its connection, and insert the edits it made while offline.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns an error if the text would be longer than the maximum length.
+pub fn check_len(len: usize) -> Result<()> {
+    if len > MAX_LEN {
+        return Err(anyhow!(
+            "target length {} is greater than 256 KiB maximum",
+            len
+        ))
+        .context(CloseReason::EditTooLarge);
+    }
+    Ok(())
+}
+
+/// How a document merges concurrent edits from its clients.
+///
+/// Whatever the engine, the document keeps a linear history of operations on
+/// its text, which is what gets persisted, forked and merged. The engine turns
+/// edits from clients into that history, and decides what clients are sent to
+/// stay in sync with it.
+pub trait SyncEngine: Send {
+    /// Returns which engine this is, as told to clients.
+    fn kind(&self) -> Engine;
+
+    /// Returns messages that send the whole document to a new client.
+    fn initial(&self, operations: &[UserOperation]) -> Vec<ServerMsg>;
+
+    /// Returns messages that bring a lagging client up to date, given the
+    /// revision of the first operation that it skipped.
+    fn catchup(
+        &self,
+        operations: &[UserOperation],
+        id: u64,
+        start: usize,
+    ) -> Result<Vec<ServerMsg>>;
+
+    /// Merge an edit from a client, returning an operation on the latest text
+    /// and the message that broadcasts it, or `None` if nothing changed.
+    fn client_edit(
+        &mut self,
+        operations: &[UserOperation],
+        id: u64,
+        msg: ClientMsg,
+    ) -> Result<Option<(OperationSeq, ServerMsg)>>;
+
+    /// Record an edit made by the server itself on the latest text, returning
+    /// the message that broadcasts it.
+    fn server_edit(
+        &mut self,
+        operations: &[UserOperation],
+        operation: &OperationSeq,
+    ) -> Result<ServerMsg>;
+
+    /// Record that a client has connected to the document.
+    fn join(&mut self, _id: u64) {}
+
+    /// Returns the token that a client can use to resume its connection
+    /// after reconnecting, if the engine needs one.
+    fn token(&self, _id: u64) -> Option<String> {
+        None
+    }
+
+    /// Resume the earlier connection of a client, given its token.
+    fn resume(&mut self, _id: u64, _token: &str) -> Result<()> {
+        bail!("document does not use the CRDT engine");
+    }
+
+    /// Record that a client has disconnected from the document.
+    fn leave(&mut self, _id: u64) {}
+}
+
+impl Default for Box<dyn SyncEngine> {
+    fn default() -> Self {
+        Box::new(OtEngine)
+    }
+}
+
+/// Construct an engine of the given kind for a document with some text.
+///
+/// Only the CRDT engine keeps its own copy of the text, so the OT engine is
+/// made without reading it.
+pub fn new_engine(kind: Engine, text: &Rope) -> Box<dyn SyncEngine> {
+    match kind {
+        Engine::Ot => Box::new(OtEngine),
+        Engine::Crdt => Box::new(CrdtEngine::new(&text.to_string())),
+    }
+}
+
+/// Operational transformation, where clients send edits based on a revision of
+/// the history, which the server transforms against everything after it.
+pub struct OtEngine;
+
+impl SyncEngine for OtEngine {
+    fn kind(&self) -> Engine {
+        Engine::Ot
+    }
+
+    fn initial(&self, operations: &[UserOperation]) -> Vec<ServerMsg> {
+        if operations.is_empty() {
+            return Vec::new();
+        }
+        vec![ServerMsg::History {
+            start: 0,
+            operations: operations.to_vec(),
+        }]
+    }
+
+    /// Runs of operations by other users are composed into one `Catchup`
+    /// message, while the client's own operations are sent individually, so
+    /// that it sees them acknowledged.
+    fn catchup(
+        &self,
+        operations: &[UserOperation],
+        id: u64,
+        start: usize,
+    ) -> Result<Vec<ServerMsg>> {
+        let mut messages = Vec::new();
+        let mut run: Option<(usize, OperationSeq)> = None;
+        for (revision, history_op) in operations.iter().enumerate().skip(start) {
+            if history_op.id == id {
+                messages.extend(run.take().map(|run| compacted(operations, run, revision)));
+                messages.push(ServerMsg::History {
+                    start: revision,
+                    operations: vec![history_op.clone()],
+                });
+                continue;
+            }
+            run = Some(match run.take() {
+                Some((start, operation)) => (start, operation.compose(&history_op.operation)?),
+                None => (revision, history_op.operation.clone()),
+            });
+        }
+        let end = operations.len();
+        messages.extend(run.map(|run| compacted(operations, run, end)));
+        Ok(messages)
+    }
+
+    fn client_edit(
+        &mut self,
+        operations: &[UserOperation],
+        id: u64,
+        msg: ClientMsg,
+    ) -> Result<Option<(OperationSeq, ServerMsg)>> {
+        let ClientMsg::Edit {
+            revision,
+            mut operation,
+            ..
+        } = msg
+        else {
+            bail!("document does not use the CRDT engine");
+        };
+        info!(
+            "edit: id = {}, revision = {}, base_len = {}, target_len = {}",
+            id,
+            revision,
+            operation.base_len(),
+            operation.target_len()
+        );
+        let len = operations.len();
+        if revision > len {
+            bail!("got revision {}, but current is {}", revision, len);
+        }
+        for history_op in &operations[revision..] {
+            operation = operation.transform(&history_op.operation)?.0;
+        }
+        let msg = history(operations, id, &operation);
+        Ok(Some((operation, msg)))
+    }
+
+    fn server_edit(
+        &mut self,
+        operations: &[UserOperation],
+        operation: &OperationSeq,
+    ) -> Result<ServerMsg> {
+        Ok(history(operations, u64::MAX, operation))
+    }
+}
+
+/// Returns a message that broadcasts the next operation in the history.
+fn history(operations: &[UserOperation], id: u64, operation: &OperationSeq) -> ServerMsg {
+    ServerMsg::History {
+        start: operations.len(),
+        operations: vec![UserOperation {
+            id,
+            operation: operation.clone(),
+        }],
+    }
+}
+
+/// Returns a message for a run of composed operations, ending before revision
+/// `end`.
+fn compacted(
+    operations: &[UserOperation],
+    (start, operation): (usize, OperationSeq),
+    end: usize,
+) -> ServerMsg {
+    if end - start == 1 {
+        ServerMsg::History {
+            start,
+            operations: operations[start..end].to_vec(),
+        }
+    } else {
+        ServerMsg::Catchup {
+            start,
+            end,
+            operation,
+        }
+    }
+}
+
+/// A sequence CRDT, where clients send operations on their own replica of the
+/// sequence, which the server merges into its replica and passes on.
+///
+/// The server does not need to order these edits, but it only accepts
+/// operations whose dependencies it has seen, so that every edit it passes on
+/// can be applied right away and also added to the history.
+///
+/// Each client inserts characters as its own site. Deleted characters stay in
+/// the sequence as tombstones, which have a separate and much larger limit
+/// than the text itself.
+///
+/// A client that reconnects inserts the edits it made while offline as its
+/// old site, which it claims by resuming the earlier connection with a token.
+pub struct CrdtEngine {
+    sequence: Sequence,
+    /// Connected clients, by ID.
+    sessions: HashMap<u64, Session>,
+    /// Sessions of clients that have disconnected, by token, with the time
+    /// that they left. Each can be resumed once, until it times out.
+    departed: HashMap<String, (Instant, Vec<u64>)>,
+}
+
+/// A client connected to a CRDT document.
+struct Session {
+    /// Secret that the client can use to resume this session.
+    token: String,
+    /// Sites that the client can insert characters as, starting with its ID.
+    sites: Vec<u64>,
+}
+
+impl CrdtEngine {
+    /// Construct an engine for a document with some text, which is inserted
+    /// by the server.
+    pub fn new(text: &str) -> Self {
+        Self {
+            sequence: Sequence::from(text),
+            sessions: HashMap::new(),
+            departed: HashMap::new(),
+        }
+    }
+
+    /// Returns an error if the text would be too long with `inserted` new
+    /// characters, or if there are too many tombstones to insert any more.
+    fn check_len(&self, inserted: usize) -> Result<()> {
+        check_len(self.sequence.len() + inserted)?;
+        let tombstones = self.sequence.tombstones();
+        if inserted > 0 && tombstones > MAX_TOMBSTONES {
+            return Err(anyhow!(
+                "document has {} deleted characters, more than the maximum of {}",
+                tombstones,
+                MAX_TOMBSTONES
+            ))
+            .context(CloseReason::EditTooLarge);
+        }
+        Ok(())
+    }
+}
+
+impl SyncEngine for CrdtEngine {
+    fn kind(&self) -> Engine {
+        Engine::Crdt
+    }
+
+    fn initial(&self, _operations: &[UserOperation]) -> Vec<ServerMsg> {
+        let ops = self.sequence.state();
+        if ops.is_empty() {
+            return Vec::new();
+        }
+        vec![ServerMsg::CrdtEdit { id: u64::MAX, ops }]
+    }
+
+    /// Merging is idempotent, so a lagging client is sent the whole sequence.
+    fn catchup(
+        &self,
+        operations: &[UserOperation],
+        _id: u64,
+        _start: usize,
+    ) -> Result<Vec<ServerMsg>> {
+        Ok(self.initial(operations))
+    }
+
+    fn client_edit(
+        &mut self,
+        _operations: &[UserOperation],
+        id: u64,
+        msg: ClientMsg,
+    ) -> Result<Option<(OperationSeq, ServerMsg)>> {
+        let ClientMsg::CrdtEdit { ops, .. } = msg else {
+            bail!("document does not use the OT engine");
+        };
+        // Check everything before changing the sequence, so that it always
+        // matches the text of the document.
+        let session = self.sessions.get(&id);
+        let sites = |site| session.is_some_and(|session| session.sites.contains(&site));
+        let inserted = self.sequence.check_remote(&ops, sites)?;
+        info!(
+            "crdt edit: id = {}, ops = {}, inserted = {}",
+            id,
+            ops.len(),
+            inserted
+        );
+        self.check_len(inserted)?;
+        let Merged { operation, ops } = self.sequence.apply_remote(ops)?;
+        if ops.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some((operation, ServerMsg::CrdtEdit { id, ops })))
+    }
+
+    fn server_edit(
+        &mut self,
+        _operations: &[UserOperation],
+        operation: &OperationSeq,
+    ) -> Result<ServerMsg> {
+        let inserted = (operation.ops().iter())
+            .map(|op| match op {
+                Operation::Insert(text) => text.chars().count(),
+                _ => 0,
+            })
+            .sum();
+        self.check_len(inserted)?;
+        let ops = self.sequence.apply_local(operation)?;
+        Ok(ServerMsg::CrdtEdit { id: u64::MAX, ops })
+    }
+
+    fn join(&mut self, id: u64) {
+        let session = Session {
+            token: format!("{:032x}", rand::random::<u128>()),
+            sites: vec![id],
+        };
+        self.sessions.insert(id, session);
+    }
+
+    fn token(&self, id: u64) -> Option<String> {
+        Some(self.sessions.get(&id)?.token.clone())
+    }
+
+    fn resume(&mut self, id: u64, token: &str) -> Result<()> {
+        let (left, sites) = self
+            .departed
+            .remove(token)
+            .context("unknown resume token")?;
+        if left.elapsed() >= RESUME_TIMEOUT {
+            bail!("resume token expired");
+        }
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .context("client is not connected")?;
+        info!("crdt resume: id = {}, sites = {:?}", id, sites);
+        session.sites.extend(sites);
+        Ok(())
+    }
+
+    fn leave(&mut self, id: u64) {
+        let now = Instant::now();
+        self.departed
+            .retain(|_, (left, _)| now.duration_since(*left) < RESUME_TIMEOUT);
+        if let Some(session) = self.sessions.remove(&id) {
+            self.departed.insert(session.token, (now, session.sites));
+        }
+    }
+}
@@ -7,16 +7,27 @@ use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use dashmap::DashMap;
-use log::{error, info};
-use rand::Rng;
-use serde::Serialize;
+use futures::future;
+use log::{error, info, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use rustpad_core::lines::LineIndex;
+use rustpad_core::protocol::{CloseReason, Encoding, Engine};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::time::{self, Instant};
-use warp::{filters::BoxedFilter, ws::Ws, Filter, Rejection, Reply};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{filters::BoxedFilter, hyper::body::Bytes, ws::Ws, Filter, Rejection, Reply};
 
-use crate::{database::Database, rustpad::Rustpad};
+use crate::{
+    database::Database,
+    rustpad::{Rustpad, Settings},
+};
 
 pub mod database;
-mod ot;
+mod engine;
 mod rustpad;
 
 /// An entry stored in the global server map.
@@ -40,7 +51,9 @@ impl Document {
 
 impl Drop for Document {
     fn drop(&mut self) {
-        self.rustpad.kill();
+        // Documents that are still in the map when it is dropped are being
+        // cleaned up as the server shuts down.
+        self.rustpad.kill(CloseReason::Shutdown);
     }
 }
 
@@ -57,6 +70,10 @@ struct ServerState {
     documents: Arc<DashMap<String, Document>>,
     /// Connection to the database pool, if persistence is enabled.
     database: Option<Database>,
+    /// Settings for connections to each document.
+    settings: Settings,
+    /// Token that must be given to destroy documents, if allowed at all.
+    admin_token: Option<String>,
 }
 
 /// Statistics about the server, returned from an API endpoint.
@@ -70,6 +87,54 @@ struct Stats {
     database_size: usize,
 }
 
+/// A document created by the server, returned from API endpoints that
+/// create documents with a fresh ID.
+#[derive(Serialize)]
+struct Created {
+    /// ID of the newly created document.
+    id: String,
+}
+
+/// Result of merging a fork into its parent, returned from an API endpoint.
+#[derive(Serialize)]
+struct Merge {
+    /// ID of the parent document.
+    parent_id: String,
+    /// Revision of the parent document after the merge.
+    parent_revision: usize,
+    /// Ranges of text at the fork point that were changed on both sides.
+    conflicts: Vec<(u32, u32)>,
+}
+
+/// Query parameters accepted when opening a WebSocket connection.
+#[derive(Deserialize)]
+struct SocketParams {
+    /// How the client counts offsets in operations and cursor positions.
+    #[serde(default)]
+    encoding: Encoding,
+    /// How the document merges concurrent edits, if it is new. Documents that
+    /// already exist keep their engine.
+    #[serde(default)]
+    engine: Engine,
+}
+
+/// Query parameters accepted by endpoints that set the text of a document.
+#[derive(Deserialize)]
+struct SetTextParams {
+    /// How the document merges concurrent edits, if it is new. Documents that
+    /// already exist keep their engine.
+    #[serde(default)]
+    engine: Engine,
+}
+
+/// Query parameters accepted by the text endpoint.
+#[derive(Deserialize)]
+struct TextParams {
+    /// Range of lines to return, like `10-40`, counting from one and
+    /// inclusive of both ends.
+    lines: Option<String>,
+}
+
 /// Server configuration.
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
@@ -77,23 +142,64 @@ pub struct ServerConfig {
     pub expiry_days: u32,
     /// Database object, for persistence if desired.
     pub database: Option<Database>,
+    /// Interval for batching cursor updates into a single message, or `None`
+    /// to send each update as soon as it arrives.
+    pub cursor_interval: Option<Duration>,
+    /// How often to ping clients, to check that they are still connected.
+    pub ping_interval: Duration,
+    /// How long a client can go without responding before it is dropped.
+    pub ping_timeout: Duration,
+    /// How long a user can be idle before they are shown as away.
+    pub away_timeout: Duration,
+    /// Number of messages queued for a client before it is considered to be
+    /// lagging, and sent a summary of the updates it missed instead.
+    pub max_backlog: usize,
+    /// Number of revisions between checksums of the text sent to clients, or
+    /// `None` to never send checksums.
+    pub checksum_interval: Option<usize>,
+    /// Number of messages per second that a client can send on average, or
+    /// `None` for no limit.
+    pub max_message_rate: Option<u32>,
+    /// Bearer token that allows destroying documents through the API, or
+    /// `None` to never allow it.
+    pub admin_token: Option<String>,
+    /// Receives a value when the server is shutting down, at which point
+    /// every document is persisted and its connections are closed.
+    pub shutdown: Option<watch::Receiver<()>>,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
+        let settings = Settings::default();
         Self {
             expiry_days: 1,
             database: None,
+            cursor_interval: settings.cursor_interval,
+            ping_interval: settings.ping_interval,
+            ping_timeout: settings.ping_timeout,
+            away_timeout: settings.away_timeout,
+            max_backlog: settings.max_backlog,
+            checksum_interval: settings.checksum_interval,
+            max_message_rate: settings.max_message_rate,
+            admin_token: None,
+            shutdown: None,
         }
     }
 }
 
 /// A combined filter handling all server routes.
 pub fn server(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
-    warp::path("api")
-        .and(backend(config))
-        .or(frontend())
-        .boxed()
+    server_with_shutdown(config).0
+}
+
+/// Like [`server`], but also returns a task that finishes once the server has
+/// shut down, after a signal from [`ServerConfig::shutdown`]. By then every
+/// document is persisted and its clients have been told why they were
+/// disconnected. Without a shutdown signal, the task finishes right away.
+pub fn server_with_shutdown(config: ServerConfig) -> (BoxedFilter<(impl Reply,)>, JoinHandle<()>) {
+    let (backend, shutdown) = backend(config);
+    let filter = warp::path("api").and(backend).or(frontend()).boxed();
+    (filter, shutdown)
 }
 
 /// Construct routes for static files from React.
@@ -101,25 +207,80 @@ fn frontend() -> BoxedFilter<(impl Reply,)> {
     warp::fs::dir("dist").boxed()
 }
 
-/// Construct backend routes, including WebSocket handlers.
-fn backend(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
+/// Construct backend routes, including WebSocket handlers, along with the
+/// task that shuts them down.
+fn backend(config: ServerConfig) -> (BoxedFilter<(impl Reply,)>, JoinHandle<()>) {
     let state = ServerState {
         documents: Default::default(),
         database: config.database,
+        settings: Settings {
+            cursor_interval: config.cursor_interval,
+            ping_interval: config.ping_interval,
+            ping_timeout: config.ping_timeout,
+            away_timeout: config.away_timeout,
+            max_backlog: config.max_backlog,
+            checksum_interval: config.checksum_interval,
+            max_message_rate: config.max_message_rate,
+        },
+        admin_token: config.admin_token,
     };
     tokio::spawn(cleaner(state.clone(), config.expiry_days));
+    let shutdown_task = tokio::spawn({
+        let state = state.clone();
+        async move {
+            if let Some(mut signal) = config.shutdown {
+                if signal.changed().await.is_ok() {
+                    shutdown(state).await;
+                }
+            }
+        }
+    });
 
     let state_filter = warp::any().map(move || state.clone());
 
     let socket = warp::path!("socket" / String)
         .and(warp::ws())
+        .and(warp::query::<SocketParams>())
         .and(state_filter.clone())
         .and_then(socket_handler);
 
+    let set_text = warp::put()
+        .and(warp::path!("text" / String))
+        .and(warp::query::<SetTextParams>())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and_then(set_text_handler);
+
+    let new_text = warp::post()
+        .and(warp::path!("text"))
+        .and(warp::query::<SetTextParams>())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and_then(new_text_handler);
+
+    let delete_text = warp::delete()
+        .and(warp::path!("text" / String))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(state_filter.clone())
+        .and_then(delete_text_handler);
+
     let text = warp::path!("text" / String)
+        .and(warp::query::<TextParams>())
         .and(state_filter.clone())
         .and_then(text_handler);
 
+    let fork = warp::post()
+        .and(warp::path!("fork" / String))
+        .and(state_filter.clone())
+        .and_then(fork_handler);
+
+    let merge = warp::post()
+        .and(warp::path!("merge" / String))
+        .and(state_filter.clone())
+        .and_then(merge_handler);
+
     let start_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("SystemTime returned before UNIX_EPOCH")
@@ -129,20 +290,71 @@ fn backend(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
         .and(state_filter)
         .and_then(stats_handler);
 
-    socket.or(text).or(stats).boxed()
+    let routes = socket
+        .or(set_text)
+        .or(new_text)
+        .or(delete_text)
+        .or(text)
+        .or(fork)
+        .or(merge)
+        .or(stats)
+        .boxed();
+    (routes, shutdown_task)
 }
 
 /// Handler for the `/api/socket/{id}` endpoint.
-async fn socket_handler(id: String, ws: Ws, state: ServerState) -> Result<impl Reply, Rejection> {
+async fn socket_handler(
+    id: String,
+    ws: Ws,
+    params: SocketParams,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let rustpad = load_document(&state, id, params.engine).await;
+    let encoding = params.encoding;
+    Ok(ws.on_upgrade(move |socket| async move { rustpad.on_connection(socket, encoding).await }))
+}
+
+/// Returns the in-memory document with a given ID, loading it if needed. New
+/// documents merge edits with the given engine.
+async fn load_document(state: &ServerState, id: String, engine: Engine) -> Arc<Rustpad> {
+    open_document(state, id, Some(engine))
+        .await
+        .expect("new documents are created")
+}
+
+/// Returns the in-memory document with a given ID, loading it from the
+/// database if needed. Documents that don't exist yet are created with the
+/// given engine, or `None` is returned if there is no engine.
+async fn open_document(
+    state: &ServerState,
+    id: String,
+    engine: Option<Engine>,
+) -> Option<Arc<Rustpad>> {
     use dashmap::mapref::entry::Entry;
 
     let mut entry = match state.documents.entry(id.clone()) {
         Entry::Occupied(e) => e.into_ref(),
         Entry::Vacant(e) => {
-            let rustpad = Arc::new(match &state.database {
-                Some(db) => db.load(&id).await.map(Rustpad::from).unwrap_or_default(),
-                None => Rustpad::default(),
-            });
+            let document = match &state.database {
+                Some(db) => db.load(&id).await.ok(),
+                None => None,
+            };
+            let rustpad = match (document, engine) {
+                (Some(document), _) => {
+                    let rustpad = Rustpad::from(document);
+                    let lineage = match &state.database {
+                        Some(db) => db.load_lineage(&id).await.ok(),
+                        None => None,
+                    };
+                    match lineage {
+                        Some(lineage) => rustpad.with_lineage(lineage),
+                        None => rustpad,
+                    }
+                }
+                (None, Some(engine)) => Rustpad::default().with_engine(engine),
+                (None, None) => return None,
+            };
+            let rustpad = Arc::new(rustpad.with_settings(state.settings.clone()));
             if let Some(db) = &state.database {
                 tokio::spawn(persister(id, Arc::clone(&rustpad), db.clone()));
             }
@@ -152,25 +364,248 @@ async fn socket_handler(id: String, ws: Ws, state: ServerState) -> Result<impl R
 
     let value = entry.value_mut();
     value.last_accessed = Instant::now();
-    let rustpad = Arc::clone(&value.rustpad);
-    Ok(ws.on_upgrade(|socket| async move { rustpad.on_connection(socket).await }))
+    Some(Arc::clone(&value.rustpad))
 }
 
 /// Handler for the `/api/text/{id}` endpoint.
-async fn text_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
-    Ok(match state.documents.get(&id) {
-        Some(value) => value.rustpad.text(),
-        None => {
-            if let Some(db) = &state.database {
-                db.load(&id)
-                    .await
-                    .map(|document| document.text)
-                    .unwrap_or_default()
-            } else {
-                String::new()
+async fn text_handler(
+    id: String,
+    params: TextParams,
+    state: ServerState,
+) -> Result<Response, Rejection> {
+    let lines = match params.lines.as_deref().map(parse_lines).transpose() {
+        Ok(lines) => lines,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &format!("{:#}", e))),
+    };
+    let rustpad = state
+        .documents
+        .get(&id)
+        .map(|value| Arc::clone(&value.rustpad));
+    if let Some(rustpad) = rustpad {
+        let text = match lines {
+            Some((start, end)) => (rustpad.text_lines(start, end).await)
+                .map_err(|e| warp::reject::custom(CustomReject(e)))?,
+            None => rustpad.text(),
+        };
+        return Ok(text.into_response());
+    }
+    let text = match &state.database {
+        Some(db) => db
+            .load(&id)
+            .await
+            .map(|document| document.text)
+            .unwrap_or_default(),
+        None => String::new(),
+    };
+    let text = match lines {
+        Some((start, end)) => LineIndex::new(&text).slice_lines(&text, start, end).into(),
+        None => text,
+    };
+    Ok(text.into_response())
+}
+
+/// Parse a range of lines like `10-40` or `10`, counting from one, into a
+/// half-open range counting from zero.
+fn parse_lines(lines: &str) -> anyhow::Result<(u32, u32)> {
+    let (start, end) = lines.split_once('-').unwrap_or((lines, lines));
+    let (start, end): (u32, u32) = match (start.parse(), end.parse()) {
+        (Ok(start), Ok(end)) => (start, end),
+        _ => anyhow::bail!("invalid range of lines: {}", lines),
+    };
+    if start == 0 || start > end {
+        anyhow::bail!("invalid range of lines: {}", lines);
+    }
+    Ok((start - 1, end))
+}
+
+/// Handler for `PUT` requests to the `/api/text/{id}` endpoint.
+async fn set_text_handler(
+    id: String,
+    params: SetTextParams,
+    body: Bytes,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let text = String::from_utf8(body.to_vec())
+        .map_err(|e| warp::reject::custom(CustomReject(e.into())))?;
+    load_document(&state, id, params.engine)
+        .await
+        .set_text(text)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    Ok(warp::reply())
+}
+
+/// Handler for the `/api/fork/{id}` endpoint.
+async fn fork_handler(id: String, state: ServerState) -> Result<Response, Rejection> {
+    let Some(parent) = open_document(&state, id.clone(), None).await else {
+        return Ok(error_reply(StatusCode::NOT_FOUND, "document not found"));
+    };
+    let (rustpad, document, lineage) = parent
+        .fork(id.clone())
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    let rustpad = Arc::new(rustpad.with_settings(state.settings.clone()));
+
+    let fork_id = claim_id(&state, Arc::clone(&rustpad)).await;
+    info!("forking id = {} into id = {}", id, fork_id);
+
+    if let Some(db) = &state.database {
+        db.store(&fork_id, &document)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+        db.store_lineage(&fork_id, &lineage)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+        tokio::spawn(persister(fork_id.clone(), Arc::clone(&rustpad), db.clone()));
+    }
+
+    Ok(warp::reply::json(&Created { id: fork_id }).into_response())
+}
+
+/// Handler for the `/api/text` endpoint, which creates a new document.
+async fn new_text_handler(
+    params: SetTextParams,
+    body: Bytes,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let text = String::from_utf8(body.to_vec())
+        .map_err(|e| warp::reject::custom(CustomReject(e.into())))?;
+    let rustpad = Rustpad::default()
+        .with_settings(state.settings.clone())
+        .with_engine(params.engine);
+    let rustpad = Arc::new(rustpad);
+    rustpad
+        .set_text(text)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    let id = claim_id(&state, Arc::clone(&rustpad)).await;
+    info!("creating id = {}", id);
+
+    if let Some(db) = &state.database {
+        let (document, _) = (rustpad.snapshot_revision().await)
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+        db.store(&id, &document)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+        tokio::spawn(persister(id.clone(), rustpad, db.clone()));
+    }
+
+    Ok(warp::reply::json(&Created { id }))
+}
+
+/// Stores a new document under a random ID that is not used in memory or in
+/// the database, returning the ID.
+async fn claim_id(state: &ServerState, rustpad: Arc<Rustpad>) -> String {
+    use dashmap::mapref::entry::Entry;
+
+    // Claim an unused ID in the map first, so no one else can load or create
+    // a document with it while we check the database.
+    loop {
+        let candidate = random_id();
+        let Entry::Vacant(e) = state.documents.entry(candidate.clone()) else {
+            continue;
+        };
+        if let Some(db) = &state.database {
+            if db.load(&candidate).await.is_ok() {
+                continue;
             }
         }
+        e.insert(Document::new(rustpad));
+        break candidate;
+    }
+}
+
+/// Handler for the `/api/merge/{id}` endpoint.
+async fn merge_handler(id: String, state: ServerState) -> Result<Response, Rejection> {
+    let Some(rustpad) = open_document(&state, id.clone(), None).await else {
+        return Ok(error_reply(StatusCode::NOT_FOUND, "document not found"));
+    };
+    let parent_id = match rustpad
+        .lineage()
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?
+    {
+        Some(lineage) => lineage.parent_id,
+        None => return Ok(error_reply(StatusCode::NOT_FOUND, "document is not a fork")),
+    };
+    if parent_id == id {
+        let message = "cannot merge a document into itself";
+        return Ok(error_reply(StatusCode::BAD_REQUEST, message));
+    }
+    let Some(parent) = open_document(&state, parent_id, None).await else {
+        return Ok(error_reply(
+            StatusCode::NOT_FOUND,
+            "parent document not found",
+        ));
+    };
+    // Changes that can't be merged into a single edit on the parent, such as
+    // ones that would make it too large, leave it unchanged.
+    let (lineage, parent_revision, conflicts) = match rustpad.merge_into(&parent).await {
+        Ok(merged) => merged,
+        Err(e) => {
+            warn!("failed to merge id = {}: {:#}", id, e);
+            return Ok(error_reply(StatusCode::CONFLICT, &format!("{:#}", e)));
+        }
+    };
+    if let Some(db) = &state.database {
+        db.store_lineage(&id, &lineage)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    }
+    Ok(warp::reply::json(&Merge {
+        parent_id: lineage.parent_id,
+        parent_revision,
+        conflicts,
     })
+    .into_response())
+}
+
+/// Handler for deleting a document through the `/api/text/{id}` endpoint,
+/// which closes its connections and removes it from the database.
+async fn delete_text_handler(
+    id: String,
+    authorization: Option<String>,
+    state: ServerState,
+) -> Result<Response, Rejection> {
+    let token = match &state.admin_token {
+        Some(token) => token,
+        None => return Ok(error_reply(StatusCode::FORBIDDEN, "deleting is disabled")),
+    };
+    // Compare in constant time, so the token can't be guessed a byte at a time
+    // from how long rejections take.
+    let authorized = (authorization.as_deref())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given.as_bytes().ct_eq(token.as_bytes()).into());
+    if !authorized {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "invalid admin token"));
+    }
+    let removed = state.documents.remove(&id);
+    if let Some((_, document)) = &removed {
+        document.rustpad.kill(CloseReason::Killed);
+    }
+    let deleted = match &state.database {
+        Some(db) => (db.delete(&id).await).map_err(|e| warp::reject::custom(CustomReject(e)))?,
+        None => false,
+    };
+    if removed.is_none() && !deleted {
+        return Ok(error_reply(StatusCode::NOT_FOUND, "document not found"));
+    }
+    info!("deleted id = {}", id);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Returns a response with an error status, explaining what went wrong.
+fn error_reply(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(message.to_string(), status).into_response()
+}
+
+/// Generates a random document ID, in the same format as the frontend.
+fn random_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(6)
+        .map(char::from)
+        .collect()
 }
 
 /// Handler for the `/api/stats` endpoint.
@@ -204,9 +639,38 @@ async fn cleaner(state: ServerState, expiry_days: u32) {
         }
         info!("cleaner removing keys: {:?}", keys);
         for key in keys {
-            state.documents.remove(&key);
+            if let Some((_, document)) = state.documents.remove(&key) {
+                document.rustpad.kill(CloseReason::Evicted);
+            }
+        }
+    }
+}
+
+/// Persists every document and closes all connections, so that clients know
+/// to reconnect once the server is back. Returns once every client has been
+/// told, or given up on.
+async fn shutdown(state: ServerState) {
+    let keys: Vec<String> = state.documents.iter().map(|e| e.key().clone()).collect();
+    info!("shutting down, closing {} documents", keys.len());
+    let mut closed = Vec::new();
+    for key in keys {
+        let Some((id, document)) = state.documents.remove(&key) else {
+            continue;
+        };
+        if let Some(db) = &state.database {
+            match document.rustpad.snapshot_revision().await {
+                Ok((document, revision)) if revision > 0 => {
+                    if let Err(e) = db.store(&id, &document).await {
+                        error!("when persisting document {}: {}", id, e);
+                    }
+                }
+                Ok(_) | Err(_) => {}
+            }
         }
+        document.rustpad.kill(CloseReason::Shutdown);
+        closed.push(async move { document.rustpad.closed().await });
     }
+    future::join_all(closed).await;
 }
 
 const PERSIST_INTERVAL: Duration = Duration::from_secs(3);
@@ -219,10 +683,13 @@ async fn persister(id: String, rustpad: Arc<Rustpad>, db: Database) {
         let interval = PERSIST_INTERVAL
             + rand::thread_rng().gen_range(Duration::ZERO..=PERSIST_INTERVAL_JITTER);
         time::sleep(interval).await;
-        let revision = rustpad.revision();
+        let (document, revision) = match rustpad.snapshot_revision().await {
+            Ok(snapshot) => snapshot,
+            Err(_) => break,
+        };
         if revision > last_revision {
             info!("persisting revision {} for id = {}", revision, id);
-            if let Err(e) = db.store(&id, &rustpad.snapshot()).await {
+            if let Err(e) = db.store(&id, &document).await {
                 error!("when persisting document {}: {}", id, e);
             } else {
                 last_revision = revision;
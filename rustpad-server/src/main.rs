@@ -1,4 +1,7 @@
-use rustpad_server::{server, database::Database, ServerConfig};
+use std::time::Duration;
+
+use rustpad_server::{server_with_shutdown, database::Database, ServerConfig};
+use tokio::sync::watch;
 
 #[tokio::main]
 async fn main() {
@@ -10,6 +13,7 @@ async fn main() {
         .parse()
         .expect("Unable to parse PORT");
 
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
     let config = ServerConfig {
         expiry_days: std::env::var("EXPIRY_DAYS")
             .unwrap_or_else(|_| String::from("1"))
@@ -23,7 +27,46 @@ async fn main() {
             ),
             Err(_) => None,
         },
+        cursor_interval: match std::env::var("CURSOR_INTERVAL_MS") {
+            Ok(ms) => Some(Duration::from_millis(
+                ms.parse().expect("Unable to parse CURSOR_INTERVAL_MS"),
+            )),
+            Err(_) => None,
+        },
+        max_message_rate: match std::env::var("MAX_MESSAGE_RATE") {
+            Ok(rate) => Some(rate.parse().expect("Unable to parse MAX_MESSAGE_RATE")),
+            Err(_) => None,
+        },
+        admin_token: std::env::var("ADMIN_TOKEN").ok(),
+        shutdown: Some(shutdown_rx),
+        ..ServerConfig::default()
+    };
+
+    // Close connections and persist documents before exiting, waiting for
+    // clients to be told that the server is shutting down. The server stops
+    // without waiting for WebSocket connections, so wait for the shutdown
+    // task as well.
+    let signal = async move {
+        shutdown_signal().await;
+        shutdown_tx.send(()).ok();
     };
+    let (filter, shutdown) = server_with_shutdown(config);
+    let (_, serve) = warp::serve(filter).bind_with_graceful_shutdown(([0, 0, 0, 0], port), signal);
+    serve.await;
+    shutdown.await.expect("Shutdown task failed");
+}
 
-    warp::serve(server(config)).run(([0, 0, 0, 0], port)).await;
+/// Wait for the process to be asked to stop, with Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to handle SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
 }
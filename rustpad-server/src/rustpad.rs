@@ -1,112 +1,450 @@
 //! Eventually consistent server-side logic for Rustpad.
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use futures::prelude::*;
 use log::{info, warn};
 use operational_transform::OperationSeq;
-use parking_lot::{RwLock, RwLockUpgradableReadGuard};
-use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast, Notify};
+use ropey::Rope;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::time::{self, Instant};
 use warp::ws::{Message, WebSocket};
 
-use crate::{database::PersistedDocument, ot::transform_index};
+use rustpad_core::checksum::checksum;
+use rustpad_core::crdt::Sequence;
+use rustpad_core::ot::{apply_rope, conflicts, diff, diff_rope, transform_cursors};
+use rustpad_core::protocol::{
+    ClientMsg, CloseReason, CursorData, Encoding, Engine, ServerMsg, Status, UserCursor, UserInfo,
+    UserOperation,
+};
+use rustpad_core::utf16::{
+    rope_cursors_from_utf16, rope_cursors_to_utf16, rope_operation_from_utf16,
+    rope_operation_to_utf16,
+};
+
+use crate::database::{Lineage, PersistedDocument};
+use crate::engine::{check_len, new_engine, SyncEngine};
 
 /// The main object representing a collaborative session.
+///
+/// Each document is owned by a single task, which makes changes one at a time
+/// in the order that they arrive. This object is a handle that sends work to
+/// that task, so no locks are held while talking to clients.
 pub struct Rustpad {
-    /// State modified by critical sections of the code.
-    state: RwLock<State>,
-    /// Incremented to obtain unique user IDs.
-    count: AtomicU64,
-    /// Used to notify clients of new text operations.
-    notify: Notify,
-    /// Used to inform all clients of metadata updates.
-    update: broadcast::Sender<ServerMsg>,
+    /// Used to send work to the task that owns the document.
+    commands: mpsc::UnboundedSender<Command>,
+    /// Latest text of the document, which is cheap to clone.
+    text: watch::Receiver<Rope>,
+    /// Held while merging into a parent, so that merges don't overlap.
+    merging: Mutex<()>,
     /// Set to true when the document is destroyed.
     killed: AtomicBool,
+    /// Number of connections being handled, including ones that are still
+    /// telling their client why they are closing.
+    connected: watch::Sender<usize>,
+    /// Settings for connections to the document.
+    settings: Settings,
+    /// Random ID of this instance of the document, sent to clients so they
+    /// can tell when the history they know about was lost.
+    epoch: String,
+}
+
+/// Settings that control how a document talks to its clients.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// How long to collect cursor updates before broadcasting them together,
+    /// or `None` to broadcast each update immediately.
+    pub cursor_interval: Option<Duration>,
+    /// How often to ping each client, to check that it is still connected.
+    pub ping_interval: Duration,
+    /// How long a client can go without responding before it is dropped.
+    pub ping_timeout: Duration,
+    /// How long a user can go without sending any messages before they are
+    /// shown as away to other users.
+    pub away_timeout: Duration,
+    /// Number of queued messages after which a client is considered to be
+    /// lagging, so that it is sent a summary once it catches up. The queue
+    /// holds only a couple more items than this, and a client whose queue
+    /// fills up anyway is disconnected.
+    pub max_backlog: usize,
+    /// Number of revisions between checksums of the text, which clients of
+    /// the OT engine use to detect that they diverged, or `None` to never send
+    /// checksums.
+    pub checksum_interval: Option<usize>,
+    /// Number of messages per second that a client can send on average, or
+    /// `None` for no limit. Clients that send more are disconnected.
+    pub max_message_rate: Option<u32>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            cursor_interval: None,
+            ping_interval: Duration::from_secs(30),
+            ping_timeout: Duration::from_secs(90),
+            away_timeout: Duration::from_secs(5 * 60),
+            max_backlog: 64,
+            checksum_interval: Some(100),
+            max_message_rate: None,
+        }
+    }
+}
+
+/// How many seconds of messages at the maximum rate a client can send at once.
+const RATE_LIMIT_BURST: u32 = 10;
+
+/// Limits the rate of messages from a client, allowing for short bursts.
+struct RateLimiter {
+    /// Messages allowed per second.
+    rate: f64,
+    /// Number of messages that can be sent right away.
+    tokens: f64,
+    /// When `tokens` was last updated.
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.into(),
+            tokens: (rate * RATE_LIMIT_BURST).into(),
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take the allowance for one message, returning `false` if the client
+    /// has sent too many.
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate * RATE_LIMIT_BURST as f64);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
 }
 
-/// Shared state involving multiple users, protected by a lock.
+/// A unit of work run by the task that owns a document.
+type Command = Box<dyn FnOnce(&mut State) + Send>;
+
+/// State of a document, owned by a single task.
 #[derive(Default)]
 struct State {
+    /// Random ID of this instance of the document.
+    epoch: String,
+    /// Merges edits from clients into the history.
+    engine: Box<dyn SyncEngine>,
     operations: Vec<UserOperation>,
-    text: String,
+    text: Rope,
     language: Option<String>,
     users: HashMap<u64, UserInfo>,
     cursors: HashMap<u64, CursorData>,
+    /// Users who are currently away.
+    away: HashSet<u64>,
+    fork_point: Option<ForkPoint>,
+    /// Outgoing message queues for each connected client.
+    connections: HashMap<u64, Connection>,
+    /// Incremented to obtain unique user IDs.
+    count: u64,
+    /// Set when the document is destroyed, with the reason why.
+    killed: Option<CloseReason>,
+    /// Settings for connections to the document.
+    settings: Settings,
+    /// Users whose cursors moved since the last batch was broadcast.
+    moved_cursors: BTreeSet<u64>,
+    /// When the next batch of cursor updates is due, if any are pending.
+    flush_at: Option<Instant>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct UserOperation {
-    id: u64,
-    operation: OperationSeq,
+/// Outgoing message queue for a connected client.
+///
+/// The queue is bounded: once `max_backlog` items are waiting, updates are
+/// skipped until the client catches up, so the queue only fills up if the
+/// client keeps asking for replies without reading them.
+struct Connection {
+    tx: mpsc::Sender<Outgoing>,
+    /// Set while updates are being skipped, until the client catches up with
+    /// its queue and is sent a resync.
+    lagging: bool,
+    /// Revision of the first operation skipped while the client is lagging.
+    behind: Option<usize>,
+    /// Users who left while the client was lagging.
+    departed: Vec<u64>,
+    /// Set when the client's text diverged, so that it is sent a snapshot of
+    /// the text with its next resync.
+    snapshot: bool,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct UserInfo {
-    name: String,
-    hue: u32,
+/// An item in the outgoing message queue of a client.
+enum Outgoing {
+    /// A message to send to the client.
+    Message(ServerMsg),
+    /// Messages to send to the client one after another, such as the state
+    /// of the document when it joins.
+    Batch(Vec<ServerMsg>),
+    /// The client fell behind, so it should be sent the operations and
+    /// metadata it missed once it reaches this point in the queue.
+    Resync,
+    /// The connection should be closed after sending everything before it.
+    Close(CloseReason),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct CursorData {
-    cursors: Vec<u32>,
-    selections: Vec<(u32, u32)>,
+impl Connection {
+    /// Construct a connection whose queue holds up to `max_backlog` updates,
+    /// along with the receiving end of the queue.
+    fn new(max_backlog: usize) -> (Self, mpsc::Receiver<Outgoing>) {
+        // One slot is for the resync sent when the client starts lagging, and
+        // one is kept free so that the connection can always be closed.
+        let (tx, rx) = mpsc::channel(max_backlog + 2);
+        let connection = Self {
+            tx,
+            lagging: false,
+            behind: None,
+            departed: Vec::new(),
+            snapshot: false,
+        };
+        (connection, rx)
+    }
+
+    /// Returns the number of items queued but not yet taken by the client's
+    /// task.
+    fn backlog(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    /// Queue a message to send to the client.
+    fn send(&self, msg: ServerMsg) {
+        self.push(Outgoing::Message(msg));
+    }
+
+    /// Queue an item for the client. If this would take the last free slot,
+    /// the connection is closed instead.
+    fn push(&self, item: Outgoing) {
+        let item = match item {
+            Outgoing::Close(_) => item,
+            _ if self.tx.capacity() <= 1 => Outgoing::Close(CloseReason::SlowClient),
+            _ => item,
+        };
+        self.tx.try_send(item).ok();
+    }
+}
+
+/// Information about where a forked document diverged from its parent.
+#[derive(Clone)]
+struct ForkPoint {
+    lineage: Lineage,
+    /// Where the histories of the two documents diverged, if both are still
+    /// the same instances as when the fork was made or last merged.
+    history: Option<Divergence>,
 }
 
-/// A message received from the client over WebSocket.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-enum ClientMsg {
-    /// Represents a sequence of local edits from the user.
-    Edit {
-        revision: usize,
-        operation: OperationSeq,
-    },
-    /// Sets the language of the editor.
-    SetLanguage(String),
-    /// Sets the user's current information.
-    ClientInfo(UserInfo),
-    /// Sets the user's cursor and selection positions.
-    CursorData(CursorData),
+/// Revisions at which a fork and its parent last had the same text, used to
+/// find the changes on each side from their histories.
+///
+/// Diffing against the text at the fork point gives the same result after a
+/// reload, but the history keeps changes together as they were made, which
+/// merges more cleanly.
+#[derive(Clone)]
+struct Divergence {
+    /// Revision of this document when it was forked or last merged.
+    revision: usize,
+    /// Epoch of the parent document that `parent_revision` refers to.
+    parent_epoch: String,
+    /// Revision of the parent document when it was forked or last merged.
+    parent_revision: usize,
+    /// Operation taking this document at `revision` to the parent document at
+    /// `parent_revision`, which is a no-op right after forking.
+    offset: OperationSeq,
+}
+
+/// Number of earlier revisions whose text is kept for a client that counts
+/// offsets in UTF-16. Edits from a client that is further behind than this
+/// can't be converted, and are rejected.
+const MAX_SHADOW_REVISIONS: usize = 1000;
+
+/// Per-connection state for a client that counts offsets in UTF-16 code units.
+///
+/// Converting offsets requires the text that they refer to, so this tracks the
+/// text as last seen by the client, along with its text at recent revisions
+/// that the client might base an edit on. Ropes share their unchanged parts,
+/// so keeping many versions of the text is cheap.
+#[derive(Default)]
+struct Utf16Shadow {
+    /// Text of the document at the last revision sent to the client.
+    text: Rope,
+    /// Revision of the document that `text` is at.
+    revision: usize,
+    /// Earlier revisions sent to the client, and their text, oldest first.
+    history: VecDeque<(usize, Rope)>,
+    /// Replica of the sequence as last seen by the client, for documents that
+    /// use the CRDT engine, whose operations don't say where their changes go.
+    sequence: Option<Sequence>,
 }
 
-/// A message sent to the client over WebSocket.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-enum ServerMsg {
-    /// Informs the client of their unique socket ID.
-    Identity(u64),
-    /// Broadcasts text operations to all clients.
-    History {
-        start: usize,
-        operations: Vec<UserOperation>,
-    },
-    /// Broadcasts the current language, last writer wins.
-    Language(String),
-    /// Broadcasts a user's information, or `None` on disconnect.
-    UserInfo { id: u64, info: Option<UserInfo> },
-    /// Broadcasts a user's cursor position.
-    UserCursor { id: u64, data: CursorData },
+impl Utf16Shadow {
+    /// Convert a message from the server into UTF-16 offsets.
+    fn encode(&mut self, msg: ServerMsg) -> Result<ServerMsg> {
+        Ok(match msg {
+            // The whole document is sent again after each identity.
+            ServerMsg::Identity { .. } => {
+                *self = Self::default();
+                msg
+            }
+            ServerMsg::History { start, operations } => {
+                if start != self.revision {
+                    bail!("history starting at {} is out of order", start);
+                }
+                let mut encoded = Vec::with_capacity(operations.len());
+                for UserOperation { id, operation } in operations {
+                    encoded.push(UserOperation {
+                        id,
+                        operation: rope_operation_to_utf16(&operation, &self.text)?,
+                    });
+                    self.advance(self.revision + 1, &operation)?;
+                }
+                ServerMsg::History {
+                    start,
+                    operations: encoded,
+                }
+            }
+            ServerMsg::Catchup {
+                start,
+                end,
+                operation,
+            } => {
+                if start != self.revision || end <= start {
+                    bail!("catchup from {} to {} is out of order", start, end);
+                }
+                // The client never sees the revisions inside the range, so
+                // it can't base edits on them.
+                let encoded = rope_operation_to_utf16(&operation, &self.text)?;
+                self.advance(end, &operation)?;
+                ServerMsg::Catchup {
+                    start,
+                    end,
+                    operation: encoded,
+                }
+            }
+            ServerMsg::CrdtEdit { id, ops } => {
+                let sequence = self.sequence.get_or_insert_with(Sequence::default);
+                let merged = sequence.apply_remote(ops.clone())?;
+                apply_rope(&merged.operation, &mut self.text)?;
+                ServerMsg::CrdtEdit { id, ops }
+            }
+            ServerMsg::UserCursor { id, data } => ServerMsg::UserCursor {
+                id,
+                data: rope_cursors_to_utf16(&data, &self.text),
+            },
+            ServerMsg::UserCursors(cursors) => ServerMsg::UserCursors(
+                cursors
+                    .into_iter()
+                    .map(|UserCursor { id, data }| UserCursor {
+                        id,
+                        data: rope_cursors_to_utf16(&data, &self.text),
+                    })
+                    .collect(),
+            ),
+            msg => msg,
+        })
+    }
+
+    /// Apply an operation sent to the client, moving it to a new revision.
+    fn advance(&mut self, revision: usize, operation: &OperationSeq) -> Result<()> {
+        self.history.push_back((self.revision, self.text.clone()));
+        if self.history.len() > MAX_SHADOW_REVISIONS {
+            self.history.pop_front();
+        }
+        apply_rope(operation, &mut self.text)?;
+        self.revision = revision;
+        Ok(())
+    }
+
+    /// Convert a message from the client into code point offsets.
+    fn decode(&mut self, msg: ClientMsg) -> Result<ClientMsg> {
+        Ok(match msg {
+            ClientMsg::Edit {
+                epoch,
+                revision,
+                operation,
+            } => {
+                // Edits are based on increasing revisions, so older history
+                // can be discarded once it is no longer needed.
+                let first = self.history.partition_point(|&(r, _)| r < revision);
+                self.history.drain(..first);
+                let text = match self.history.front() {
+                    _ if revision == self.revision => &self.text,
+                    Some((r, text)) if *r == revision => text,
+                    _ if revision > self.revision => bail!(
+                        "got revision {}, but the latest is {}",
+                        revision,
+                        self.revision
+                    ),
+                    _ => bail!("text at revision {} is not kept", revision),
+                };
+                ClientMsg::Edit {
+                    epoch,
+                    revision,
+                    operation: rope_operation_from_utf16(&operation, text)?,
+                }
+            }
+            ClientMsg::CursorData(data) => {
+                ClientMsg::CursorData(rope_cursors_from_utf16(&data, &self.text))
+            }
+            msg => msg,
+        })
+    }
 }
 
-impl From<ServerMsg> for Message {
-    fn from(msg: ServerMsg) -> Self {
-        let serialized = serde_json::to_string(&msg).expect("failed serialize");
-        Message::text(serialized)
+/// Convert a message for a client that may count offsets in UTF-16.
+fn encode(shadow: &mut Option<Utf16Shadow>, msg: ServerMsg) -> Result<ServerMsg> {
+    match shadow {
+        Some(shadow) => shadow.encode(msg),
+        None => Ok(msg),
     }
 }
 
+/// Send a message to a client, giving up if it is not accepted in time,
+/// rather than holding the client's updates forever.
+async fn send_within(
+    socket: &mut WebSocket,
+    shadow: &mut Option<Utf16Shadow>,
+    msg: ServerMsg,
+    timeout: Duration,
+) -> Result<()> {
+    let message = to_message(&encode(shadow, msg)?);
+    time::timeout(timeout, socket.send(message))
+        .await
+        .context(CloseReason::SlowClient)??;
+    Ok(())
+}
+
+/// Tell a client why its connection is closing, then close it with the
+/// matching close code.
+async fn close(socket: &mut WebSocket, reason: CloseReason) -> Result<()> {
+    socket
+        .send(to_message(&ServerMsg::Closing { reason }))
+        .await?;
+    socket
+        .send(Message::close_with(reason.code(), reason.to_string()))
+        .await?;
+    Ok(())
+}
+
+/// Serialize a message to be sent to the client over WebSocket.
+fn to_message(msg: &ServerMsg) -> Message {
+    let serialized = serde_json::to_string(msg).expect("failed serialize");
+    Message::text(serialized)
+}
+
 impl Default for Rustpad {
     fn default() -> Self {
-        let (tx, _) = broadcast::channel(16);
-        Self {
-            state: Default::default(),
-            count: Default::default(),
-            notify: Default::default(),
-            update: tx,
-            killed: AtomicBool::new(false),
-        }
+        Self::new(State::default())
     }
 }
 
@@ -114,227 +452,723 @@ impl From<PersistedDocument> for Rustpad {
     fn from(document: PersistedDocument) -> Self {
         let mut operation = OperationSeq::default();
         operation.insert(&document.text);
+        let text = Rope::from_str(&document.text);
 
-        let rustpad = Self::default();
-        {
-            let mut state = rustpad.state.write();
-            state.text = document.text;
-            state.language = document.language;
-            state.operations.push(UserOperation {
+        Self::new(State {
+            operations: vec![UserOperation {
                 id: u64::MAX,
                 operation,
-            })
-        }
-        rustpad
+            }],
+            engine: new_engine(document.engine, &text),
+            text,
+            language: document.language,
+            ..Default::default()
+        })
     }
 }
 
 impl Rustpad {
-    /// Handle a connection from a WebSocket.
-    pub async fn on_connection(&self, socket: WebSocket) {
-        let id = self.count.fetch_add(1, Ordering::Relaxed);
-        info!("connection! id = {}", id);
-        if let Err(e) = self.handle_connection(id, socket).await {
-            warn!("connection terminated early: {}", e);
+    /// Spawn a task that owns the state of a document, returning its handle.
+    fn new(mut state: State) -> Self {
+        let epoch = format!("{:016x}", rand::random::<u64>());
+        state.epoch = epoch.clone();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Command>();
+        let (text_tx, text_rx) = watch::channel(state.text.clone());
+        tokio::spawn(async move {
+            loop {
+                let flush_at = state.flush_at;
+                let flush = async move {
+                    match flush_at {
+                        Some(deadline) => time::sleep_until(deadline).await,
+                        None => future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    command = rx.recv() => match command {
+                        Some(command) => {
+                            let revision = state.operations.len();
+                            command(&mut state);
+                            if state.operations.len() != revision {
+                                text_tx.send_replace(state.text.clone());
+                            }
+                        }
+                        None => break,
+                    },
+                    _ = flush => state.flush_cursors(),
+                }
+            }
+        });
+        Self {
+            commands: tx,
+            text: text_rx,
+            merging: Mutex::new(()),
+            killed: AtomicBool::new(false),
+            connected: watch::Sender::new(0),
+            settings: Settings::default(),
+            epoch,
+        }
+    }
+
+    /// Use the given settings for connections to this document.
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings.clone();
+        self.send(move |state| state.settings = settings);
+        self
+    }
+
+    /// Merge edits from clients with the given engine. This should only be
+    /// called before any clients have connected.
+    pub fn with_engine(self, engine: Engine) -> Self {
+        self.send(move |state| state.engine = new_engine(engine, &state.text));
+        self
+    }
+
+    /// Run a function on the document state, without waiting for it.
+    fn send(&self, f: impl FnOnce(&mut State) + Send + 'static) {
+        // This only fails once the task has stopped, when there is nothing
+        // left to update.
+        self.commands.send(Box::new(f)).ok();
+    }
+
+    /// Run a function on the document state, returning its result.
+    async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut State) -> T + Send + 'static,
+    ) -> Result<T> {
+        let (tx, rx) = oneshot::channel();
+        self.send(move |state| {
+            tx.send(f(state)).ok();
+        });
+        rx.await.map_err(|_| anyhow!("document was destroyed"))
+    }
+
+    /// Handle a connection from a WebSocket, counting offsets in the given
+    /// encoding.
+    pub async fn on_connection(&self, socket: WebSocket, encoding: Encoding) {
+        self.connected.send_modify(|n| *n += 1);
+        self.run_connection(socket, encoding).await;
+        self.connected.send_modify(|n| *n -= 1);
+    }
+
+    /// Wait until every connection to the document has finished closing.
+    pub async fn closed(&self) {
+        self.connected.subscribe().wait_for(|&n| n == 0).await.ok();
+    }
+
+    async fn run_connection(&self, mut socket: WebSocket, encoding: Encoding) {
+        let (connection, rx) = Connection::new(self.settings.max_backlog);
+        let id = match self.call(move |state| state.join(connection)).await {
+            Ok(Ok(id)) => id,
+            Ok(Err(e)) | Err(e) => {
+                warn!("connection refused: {}", e);
+                if let Some(&reason) = e.downcast_ref::<CloseReason>() {
+                    close(&mut socket, reason).await.ok();
+                }
+                return;
+            }
+        };
+        info!("connection! id = {}, encoding = {:?}", id, encoding);
+        let mut shadow = match encoding {
+            Encoding::Unicode => None,
+            Encoding::Utf16 => Some(Utf16Shadow::default()),
+        };
+        let result = self.handle_connection(id, &mut socket, rx, &mut shadow);
+        if let Err(e) = result.await {
+            warn!("connection terminated early: {:#}", e);
+            if let Some(&reason) = e.downcast_ref::<CloseReason>() {
+                // The client may not be reading at all, so don't wait forever.
+                let timeout = self.settings.ping_timeout;
+                time::timeout(timeout, close(&mut socket, reason))
+                    .await
+                    .ok();
+            }
         }
         info!("disconnection, id = {}", id);
-        self.state.write().users.remove(&id);
-        self.state.write().cursors.remove(&id);
-        self.update
-            .send(ServerMsg::UserInfo { id, info: None })
-            .ok();
+        self.send(move |state| state.leave(id));
     }
 
     /// Returns a snapshot of the latest text.
     pub fn text(&self) -> String {
-        let state = self.state.read();
-        state.text.clone()
+        self.text.borrow().to_string()
     }
 
-    /// Returns a snapshot of the current document for persistence.
-    pub fn snapshot(&self) -> PersistedDocument {
-        let state = self.state.read();
-        PersistedDocument {
-            text: state.text.clone(),
-            language: state.language.clone(),
-        }
+    /// Returns a snapshot of the text in lines `start` up to `end`, counting
+    /// from zero.
+    pub async fn text_lines(&self, start: u32, end: u32) -> Result<String> {
+        self.call(move |state| {
+            // Lines past the end of the text start at its end, so they are empty.
+            let offset = |line: u32| {
+                let line = (line as usize).min(state.text.len_lines());
+                state.text.line_to_char(line)
+            };
+            let (start, end) = (offset(start), offset(end.max(start)));
+            state.text.slice(start..end).to_string()
+        })
+        .await
     }
 
-    /// Returns the current revision.
-    pub fn revision(&self) -> usize {
-        let state = self.state.read();
-        state.operations.len()
+    /// Replace the text of the document, as a minimal edit that preserves the
+    /// positions of other users' cursors.
+    pub async fn set_text(&self, text: String) -> Result<()> {
+        self.call(move |state| {
+            let operation = diff_rope(&state.text, &text);
+            if !operation.is_noop() {
+                state.server_edit(operation)?;
+            }
+            Ok(())
+        })
+        .await?
     }
 
-    /// Kill this object immediately, dropping all current connections.
-    pub fn kill(&self) {
-        self.killed.store(true, Ordering::Relaxed);
-        self.notify.notify_waiters();
+    /// Returns a snapshot of the current document, along with its revision.
+    /// Fails once the document has been destroyed, so that it is not
+    /// persisted again.
+    pub async fn snapshot_revision(&self) -> Result<(PersistedDocument, usize)> {
+        self.call(|state| {
+            if state.killed == Some(CloseReason::Killed) {
+                bail!("document was destroyed");
+            }
+            let document = PersistedDocument {
+                text: state.text.to_string(),
+                language: state.language.clone(),
+                engine: state.engine.kind(),
+            };
+            Ok((document, state.operations.len()))
+        })
+        .await?
     }
 
-    /// Returns if this Rustpad object has been killed.
-    pub fn killed(&self) -> bool {
-        self.killed.load(Ordering::Relaxed)
+    /// Returns the parent of this document, if it was forked.
+    pub async fn lineage(&self) -> Result<Option<Lineage>> {
+        self.call(|state| state.fork_point.as_ref().map(|fork| fork.lineage.clone()))
+            .await
     }
 
-    async fn handle_connection(&self, id: u64, mut socket: WebSocket) -> Result<()> {
-        let mut update_rx = self.update.subscribe();
+    /// Record that this document was forked from a parent, such as when it is
+    /// loaded from the database. This should only be called before any
+    /// clients have connected.
+    pub fn with_lineage(self, lineage: Lineage) -> Self {
+        self.send(move |state| {
+            state.fork_point = Some(ForkPoint {
+                lineage,
+                history: None,
+            })
+        });
+        self
+    }
 
-        let mut revision: usize = self.send_initial(id, &mut socket).await?;
+    /// Create a new document with the same text, language and engine as this
+    /// one, recording this document, with the given ID, as its parent.
+    ///
+    /// Returns the new document along with a snapshot of its contents and its
+    /// lineage, for persisting.
+    pub async fn fork(&self, id: String) -> Result<(Rustpad, PersistedDocument, Lineage)> {
+        let (document, parent_revision) = self.snapshot_revision().await?;
+        let lineage = Lineage {
+            parent_id: id,
+            parent_revision,
+            base: document.text.clone(),
+        };
+        let fork = Rustpad::from(document.clone());
+        let mut offset = OperationSeq::default();
+        offset.retain(document.text.chars().count() as u64);
+        let fork_point = ForkPoint {
+            lineage: lineage.clone(),
+            history: Some(Divergence {
+                revision: 1,
+                parent_epoch: self.epoch.clone(),
+                parent_revision,
+                offset,
+            }),
+        };
+        fork.send(move |state| state.fork_point = Some(fork_point));
+        Ok((fork, document, lineage))
+    }
 
-        loop {
-            // In order to avoid the "lost wakeup" problem, we first request a
-            // notification, **then** check the current state for new revisions.
-            // This is the same approach that `tokio::sync::watch` takes.
-            let notified = self.notify.notified();
-            if self.killed() {
-                break;
-            }
-            if self.revision() > revision {
-                revision = self.send_history(revision, &mut socket).await?
+    /// Merge the changes in this forked document back into its parent.
+    ///
+    /// Changes made on each side since the fork point are composed into one
+    /// operation, or found by diffing against the text at the fork point if
+    /// either document was reloaded since. The changes in the fork are
+    /// transformed against those in the parent, and applied to the parent as
+    /// a single edit by the server, through the parent's engine. Returns the
+    /// updated lineage and revision of the parent, as well as ranges of text
+    /// at the fork point that were changed on both sides.
+    pub async fn merge_into(&self, parent: &Rustpad) -> Result<(Lineage, usize, Vec<(u32, u32)>)> {
+        if std::ptr::eq(self, parent) {
+            bail!("cannot merge a document into itself");
+        }
+        let _merging = self.merging.lock().await;
+        let (fork, text, revision, changes) = self
+            .call(|state| {
+                let fork = state.fork_point.clone().context("document is not a fork")?;
+                let text = state.text.to_string();
+                let changes = match &fork.history {
+                    Some(history) => {
+                        let mut changes = OperationSeq::default();
+                        changes.retain(history.offset.base_len() as u64);
+                        for history_op in &state.operations[history.revision..] {
+                            changes = changes.compose(&history_op.operation)?;
+                        }
+                        changes
+                    }
+                    None => diff(&fork.lineage.base, &text),
+                };
+                anyhow::Ok((fork, text, state.operations.len(), changes))
+            })
+            .await??;
+
+        let parent_id = fork.lineage.parent_id.clone();
+        let (len, conflicts, offset) = parent
+            .call(move |state| {
+                let len = state.operations.len();
+                let history = (fork.history)
+                    .filter(|history| history.parent_epoch == state.epoch)
+                    .filter(|history| history.parent_revision <= len);
+                let parent_changes = match history {
+                    Some(history) => {
+                        let mut parent_changes = history.offset;
+                        for history_op in &state.operations[history.parent_revision..] {
+                            parent_changes = parent_changes.compose(&history_op.operation)?;
+                        }
+                        parent_changes
+                    }
+                    None => diff(&fork.lineage.base, &state.text.to_string()),
+                };
+
+                let conflicts = conflicts(&changes, &parent_changes);
+                let (merged, offset) = changes.transform(&parent_changes)?;
+                state.server_edit(merged)?;
+                anyhow::Ok((len, conflicts, offset))
+            })
+            .await??;
+        info!(
+            "merge: parent = {}, revision = {}, conflicts = {:?}",
+            parent_id, len, conflicts
+        );
+
+        // The parent now has every change up to the text that was merged, so
+        // that is where the next merge starts from.
+        let lineage = Lineage {
+            parent_id,
+            parent_revision: len + 1,
+            base: text,
+        };
+        let fork = ForkPoint {
+            lineage: lineage.clone(),
+            history: Some(Divergence {
+                revision,
+                parent_epoch: parent.epoch.clone(),
+                parent_revision: len + 1,
+                offset,
+            }),
+        };
+        self.call(move |state| state.fork_point = Some(fork))
+            .await?;
+        Ok((lineage, len + 1, conflicts))
+    }
+
+    /// Kill this object immediately, closing all current connections with the
+    /// given reason. Only the first call has any effect.
+    pub fn kill(&self, reason: CloseReason) {
+        if self.killed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.send(move |state| {
+            state.killed = Some(reason);
+            let ids: Vec<_> = state.connections.keys().copied().collect();
+            for id in ids {
+                state.close(id, reason);
             }
+        });
+    }
 
+    /// Returns if this Rustpad object has been killed.
+    pub fn killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+
+    async fn handle_connection(
+        &self,
+        id: u64,
+        socket: &mut WebSocket,
+        mut rx: mpsc::Receiver<Outgoing>,
+        shadow: &mut Option<Utf16Shadow>,
+    ) -> Result<()> {
+        let Settings {
+            ping_interval,
+            ping_timeout,
+            away_timeout,
+            max_message_rate,
+            ..
+        } = self.settings;
+        let mut limiter = max_message_rate.map(RateLimiter::new);
+        let mut ping = time::interval_at(Instant::now() + ping_interval, ping_interval);
+        // Any message counts as a sign of life, while only text messages
+        // count as activity from the user.
+        let mut last_seen = Instant::now();
+        let mut last_active = Instant::now();
+        let mut away = false;
+        loop {
             tokio::select! {
-                _ = notified => {}
-                update = update_rx.recv() => {
-                    socket.send(update?.into()).await?;
-                }
-                result = socket.next() => {
-                    match result {
-                        None => break,
-                        Some(message) => {
-                            self.handle_message(id, message?).await?;
+                item = rx.recv() => match item {
+                    // The queue is closed when the document is destroyed, or
+                    // after the client sends an invalid edit.
+                    None => break,
+                    Some(Outgoing::Message(msg)) => {
+                        send_within(socket, shadow, msg, ping_timeout).await?;
+                    }
+                    Some(Outgoing::Batch(messages)) => {
+                        for msg in messages {
+                            send_within(socket, shadow, msg, ping_timeout).await?;
                         }
                     }
+                    Some(Outgoing::Resync) => self.send(move |state| state.resync(id)),
+                    Some(Outgoing::Close(reason)) => return Err(reason.into()),
+                },
+                result = socket.next() => match result {
+                    None => break,
+                    Some(message) => {
+                        let message = message?;
+                        last_seen = Instant::now();
+                        if message.is_text() {
+                            if !limiter.as_mut().is_none_or(RateLimiter::take) {
+                                bail!(CloseReason::RateLimited);
+                            }
+                            last_active = last_seen;
+                            if away {
+                                away = false;
+                                self.send(move |state| state.set_status(id, Status::Active));
+                            }
+                        }
+                        self.handle_message(id, message, shadow)?;
+                    }
+                },
+                _ = ping.tick() => {
+                    if last_seen.elapsed() >= ping_timeout {
+                        bail!("no response in {:?}", last_seen.elapsed());
+                    }
+                    time::timeout(ping_timeout, socket.send(Message::ping(Vec::new())))
+                        .await
+                        .context(CloseReason::SlowClient)??;
+                }
+                _ = time::sleep_until(last_active + away_timeout), if !away => {
+                    away = true;
+                    self.send(move |state| state.set_status(id, Status::Away));
                 }
             }
         }
-
         Ok(())
     }
 
-    async fn send_initial(&self, id: u64, socket: &mut WebSocket) -> Result<usize> {
-        socket.send(ServerMsg::Identity(id).into()).await?;
-        let mut messages = Vec::new();
-        let revision = {
-            let state = self.state.read();
-            if !state.operations.is_empty() {
-                messages.push(ServerMsg::History {
-                    start: 0,
-                    operations: state.operations.clone(),
-                });
-            }
-            if let Some(language) = &state.language {
-                messages.push(ServerMsg::Language(language.clone()));
-            }
-            for (&id, info) in &state.users {
-                messages.push(ServerMsg::UserInfo {
-                    id,
-                    info: Some(info.clone()),
-                });
-            }
-            for (&id, data) in &state.cursors {
-                messages.push(ServerMsg::UserCursor {
-                    id,
-                    data: data.clone(),
-                });
+    fn handle_message(
+        &self,
+        id: u64,
+        message: Message,
+        shadow: &mut Option<Utf16Shadow>,
+    ) -> Result<()> {
+        let msg: ClientMsg = match message.to_str() {
+            Ok(text) => serde_json::from_str(text).context("failed to deserialize message")?,
+            Err(()) => return Ok(()), // Ignore non-text messages
+        };
+        if let ClientMsg::Edit { epoch, .. } | ClientMsg::CrdtEdit { epoch, .. } = &msg {
+            if *epoch != self.epoch {
+                // The edit can't be applied, so the client is told to start
+                // over, in case it missed the identity of this instance.
+                warn!("edit from id = {} for stale epoch {:?}", id, epoch);
+                self.send(move |state| state.restart(id));
+                return Ok(());
             }
-            state.operations.len()
+        }
+        let msg = match shadow {
+            Some(shadow) => (shadow.decode(msg).context("invalid UTF-16 offsets"))
+                .context(CloseReason::InvalidOperation)?,
+            None => msg,
         };
-        for msg in messages {
-            socket.send(msg.into()).await?;
+        self.send(move |state| state.handle_message(id, msg));
+        Ok(())
+    }
+}
+
+impl State {
+    /// Add a client with an outgoing message queue, sending it the current
+    /// state of the document. Returns the ID of the client.
+    fn join(&mut self, connection: Connection) -> Result<u64> {
+        if let Some(reason) = self.killed {
+            bail!(reason);
+        }
+        let id = self.count;
+        self.count += 1;
+        self.engine.join(id);
+
+        connection.push(Outgoing::Batch(self.initial(id)));
+        self.connections.insert(id, connection);
+        Ok(id)
+    }
+
+    /// Returns messages that tell a client its identity, then send it the
+    /// whole document.
+    fn initial(&self, id: u64) -> Vec<ServerMsg> {
+        let mut messages = vec![ServerMsg::Identity {
+            id,
+            epoch: self.epoch.clone(),
+            engine: self.engine.kind(),
+            token: self.engine.token(id),
+        }];
+        messages.extend(self.engine.initial(&self.operations));
+        messages.extend(self.metadata());
+        messages
+    }
+
+    /// Send a client its identity and the whole document again, after it made
+    /// an edit for another instance of the document. Clients that already
+    /// started over skip the parts that they have seen.
+    fn restart(&mut self, id: u64) {
+        let messages = self.initial(id);
+        if let Some(connection) = self.connections.get_mut(&id) {
+            // Anything skipped so far is in the document that is sent now.
+            connection.behind = None;
+            connection.push(Outgoing::Batch(messages));
         }
-        Ok(revision)
     }
 
-    async fn send_history(&self, start: usize, socket: &mut WebSocket) -> Result<usize> {
-        let operations = {
-            let state = self.state.read();
-            let len = state.operations.len();
-            if start < len {
-                state.operations[start..].to_owned()
-            } else {
-                Vec::new()
+    /// Returns messages describing the current language, users and cursors.
+    fn metadata(&self) -> Vec<ServerMsg> {
+        let mut messages = Vec::new();
+        if let Some(language) = &self.language {
+            messages.push(ServerMsg::Language(language.clone()));
+        }
+        for (&id, info) in &self.users {
+            messages.push(ServerMsg::UserInfo {
+                id,
+                info: Some(info.clone()),
+            });
+        }
+        for (&id, data) in &self.cursors {
+            messages.push(ServerMsg::UserCursor {
+                id,
+                data: data.clone(),
+            });
+        }
+        for &id in &self.away {
+            messages.push(ServerMsg::UserStatus {
+                id,
+                status: Status::Away,
+            });
+        }
+        messages
+    }
+
+    /// Bring a lagging client up to date with the latest users, cursors and
+    /// language, after it has caught up with its queue. This also sends the
+    /// client a snapshot of the text, if it asked for one.
+    fn resync(&mut self, id: u64) {
+        let (behind, snapshot) = match self.connections.get(&id) {
+            Some(connection) => (connection.behind, connection.snapshot),
+            None => return,
+        };
+        let catchup = |start| self.engine.catchup(&self.operations, id, start);
+        let mut messages = match behind.map(catchup) {
+            Some(Ok(messages)) => messages,
+            Some(Err(e)) => {
+                // The client can reconnect to get the whole document again.
+                warn!("failed to catch up id = {}: {}", id, e);
+                self.close(id, CloseReason::SlowClient);
+                return;
             }
+            None => Vec::new(),
         };
-        let num_ops = operations.len();
-        if num_ops > 0 {
-            let msg = ServerMsg::History { start, operations };
-            socket.send(msg.into()).await?;
+        if snapshot {
+            messages.push(ServerMsg::Snapshot {
+                revision: self.operations.len(),
+                text: self.text.to_string(),
+            });
+        }
+        messages.extend(self.metadata());
+        if let Some(connection) = self.connections.get_mut(&id) {
+            info!("resync: id = {}, behind = {:?}", id, behind);
+            connection.lagging = false;
+            connection.behind = None;
+            connection.snapshot = false;
+            let departures = connection.departed.drain(..);
+            let departures = departures.map(|id| ServerMsg::UserInfo { id, info: None });
+            messages.splice(0..0, departures);
+            connection.push(Outgoing::Batch(messages));
         }
-        Ok(start + num_ops)
     }
 
-    async fn handle_message(&self, id: u64, message: Message) -> Result<()> {
-        let msg: ClientMsg = match message.to_str() {
-            Ok(text) => serde_json::from_str(text).context("failed to deserialize message")?,
-            Err(()) => return Ok(()), // Ignore non-text messages
+    /// Close the connection to a client, after sending any queued messages.
+    fn close(&mut self, id: u64, reason: CloseReason) {
+        if let Some(connection) = self.connections.remove(&id) {
+            connection.push(Outgoing::Close(reason));
+        }
+    }
+
+    /// Remove a client after it disconnects.
+    fn leave(&mut self, id: u64) {
+        self.connections.remove(&id);
+        self.users.remove(&id);
+        self.cursors.remove(&id);
+        self.away.remove(&id);
+        self.engine.leave(id);
+        self.broadcast(ServerMsg::UserInfo { id, info: None });
+    }
+
+    /// Update whether a client is away, letting other clients know.
+    fn set_status(&mut self, id: u64, status: Status) {
+        if !self.connections.contains_key(&id) {
+            return;
+        }
+        let changed = match status {
+            Status::Active => self.away.remove(&id),
+            Status::Away => self.away.insert(id),
         };
+        if changed {
+            self.broadcast(ServerMsg::UserStatus { id, status });
+        }
+    }
+
+    /// Send a message to every connected client.
+    ///
+    /// Clients that have fallen behind skip every update until they catch up
+    /// with their queue, when they are sent a summary of what they missed,
+    /// including which users left in the meantime.
+    fn broadcast(&mut self, msg: ServerMsg) {
+        let max_backlog = self.settings.max_backlog;
+        // Edits are broadcast right after they are added to the history.
+        let revision = self.operations.len().saturating_sub(1);
+        for connection in self.connections.values_mut() {
+            if !connection.lagging && connection.backlog() >= max_backlog {
+                connection.lagging = true;
+                connection.push(Outgoing::Resync);
+            }
+            match &msg {
+                _ if !connection.lagging => connection.send(msg.clone()),
+                ServerMsg::History { start, .. } => {
+                    connection.behind.get_or_insert(*start);
+                }
+                ServerMsg::CrdtEdit { .. } => {
+                    connection.behind.get_or_insert(revision);
+                }
+                &ServerMsg::UserInfo { id, info: None } => connection.departed.push(id),
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_message(&mut self, id: u64, msg: ClientMsg) {
         match msg {
-            ClientMsg::Edit {
-                revision,
-                operation,
-            } => {
-                self.apply_edit(id, revision, operation)
-                    .context("invalid edit operation")?;
-                self.notify.notify_waiters();
+            msg @ (ClientMsg::Edit { .. } | ClientMsg::CrdtEdit { .. }) => {
+                if let Err(e) = self.apply_edit(id, msg) {
+                    warn!("invalid edit operation from id = {}: {:#}", id, e);
+                    let reason = e.downcast_ref::<CloseReason>().copied();
+                    self.close(id, reason.unwrap_or(CloseReason::InvalidOperation));
+                }
+            }
+            ClientMsg::Resume { token } => {
+                if let Err(e) = self.engine.resume(id, &token) {
+                    warn!("could not resume connection for id = {}: {:#}", id, e);
+                }
             }
             ClientMsg::SetLanguage(language) => {
-                self.state.write().language = Some(language.clone());
-                self.update.send(ServerMsg::Language(language)).ok();
+                self.language = Some(language.clone());
+                self.broadcast(ServerMsg::Language(language));
             }
             ClientMsg::ClientInfo(info) => {
-                self.state.write().users.insert(id, info.clone());
-                let msg = ServerMsg::UserInfo {
+                self.users.insert(id, info.clone());
+                self.broadcast(ServerMsg::UserInfo {
                     id,
                     info: Some(info),
-                };
-                self.update.send(msg).ok();
+                });
             }
-            ClientMsg::CursorData(data) => {
-                self.state.write().cursors.insert(id, data.clone());
-                let msg = ServerMsg::UserCursor { id, data };
-                self.update.send(msg).ok();
+            ClientMsg::CursorData(data) => match self.settings.cursor_interval {
+                Some(interval) => {
+                    self.cursors.insert(id, data);
+                    self.moved_cursors.insert(id);
+                    self.flush_at
+                        .get_or_insert_with(|| Instant::now() + interval);
+                }
+                None => {
+                    self.cursors.insert(id, data.clone());
+                    self.broadcast(ServerMsg::UserCursor { id, data });
+                }
+            },
+            ClientMsg::ChecksumMismatch { revision } => {
+                warn!(
+                    "checksum mismatch from id = {} at revision {}, sending snapshot",
+                    id, revision
+                );
+                if let Some(connection) = self.connections.get_mut(&id) {
+                    // The snapshot must be at the client's revision, so a
+                    // lagging client gets it once it has caught up.
+                    connection.snapshot = true;
+                    if !connection.lagging {
+                        self.resync(id);
+                    }
+                }
+            }
+            ClientMsg::Sync => {
+                if let Some(connection) = self.connections.get(&id) {
+                    connection.send(ServerMsg::Synced);
+                }
             }
         }
-        Ok(())
     }
 
-    fn apply_edit(&self, id: u64, revision: usize, mut operation: OperationSeq) -> Result<()> {
-        info!(
-            "edit: id = {}, revision = {}, base_len = {}, target_len = {}",
-            id,
-            revision,
-            operation.base_len(),
-            operation.target_len()
-        );
-        let state = self.state.upgradable_read();
-        let len = state.operations.len();
-        if revision > len {
-            bail!("got revision {}, but current is {}", revision, len);
+    /// Broadcast the latest positions of cursors that moved since the last
+    /// batch, skipping users that have since disconnected.
+    fn flush_cursors(&mut self) {
+        self.flush_at = None;
+        let cursors: Vec<_> = std::mem::take(&mut self.moved_cursors)
+            .into_iter()
+            .filter_map(|id| {
+                let data = self.cursors.get(&id)?.clone();
+                Some(UserCursor { id, data })
+            })
+            .collect();
+        if !cursors.is_empty() {
+            self.broadcast(ServerMsg::UserCursors(cursors));
         }
-        for history_op in &state.operations[revision..] {
-            operation = operation.transform(&history_op.operation)?.0;
+    }
+
+    /// Merge an edit from a client with the document's engine, and send it to
+    /// all clients.
+    fn apply_edit(&mut self, id: u64, msg: ClientMsg) -> Result<()> {
+        if let Some((operation, msg)) = self.engine.client_edit(&self.operations, id, msg)? {
+            self.commit(id, operation, msg)?;
         }
-        if operation.target_len() > 256 * 1024 {
-            bail!(
-                "target length {} is greater than 256 KiB maximum",
-                operation.target_len()
-            );
+        Ok(())
+    }
+
+    /// Apply an edit made by the server itself to the latest text, and send it
+    /// to all clients.
+    fn server_edit(&mut self, operation: OperationSeq) -> Result<()> {
+        let msg = self.engine.server_edit(&self.operations, &operation)?;
+        self.commit(u64::MAX, operation, msg)
+    }
+
+    /// Apply an operation based on the latest revision to the document, and
+    /// broadcast the message that the engine made for it.
+    fn commit(&mut self, id: u64, operation: OperationSeq, msg: ServerMsg) -> Result<()> {
+        check_len(operation.target_len())?;
+        apply_rope(&operation, &mut self.text)?;
+        for (_, data) in self.cursors.iter_mut() {
+            transform_cursors(&operation, data);
         }
-        let new_text = operation.apply(&state.text)?;
-        let mut state = RwLockUpgradableReadGuard::upgrade(state);
-        for (_, data) in state.cursors.iter_mut() {
-            for cursor in data.cursors.iter_mut() {
-                *cursor = transform_index(&operation, *cursor);
-            }
-            for (start, end) in data.selections.iter_mut() {
-                *start = transform_index(&operation, *start);
-                *end = transform_index(&operation, *end);
+        self.operations.push(UserOperation { id, operation });
+        self.broadcast(msg);
+        let revision = self.operations.len();
+        // Clients of the CRDT engine don't know which revision they are at,
+        // so only clients of the OT engine get checksums.
+        let interval = self.settings.checksum_interval;
+        if let Some(interval) = interval.filter(|_| self.engine.kind() == Engine::Ot) {
+            if revision.is_multiple_of(interval) {
+                self.broadcast(ServerMsg::Checksum {
+                    revision,
+                    checksum: checksum(self.text.chunks()),
+                });
             }
         }
-        state.operations.push(UserOperation { id, operation });
-        state.text = new_text;
         Ok(())
     }
 }
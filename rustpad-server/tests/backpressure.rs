@@ -0,0 +1,140 @@
+//! Tests for clients that cannot keep up with updates.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use tokio::time;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_slow_client_catchup() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_backlog: 8,
+        ..ServerConfig::default()
+    });
+    let (addr, serve) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut slow = connect_raw(addr, "foobar").await?;
+    identity(recv_raw(&mut slow).await?, 0)?;
+
+    let mut client = connect(&filter, "foobar").await?;
+    let epoch = client.recv_identity(1).await?;
+    send_large_edits(&mut client, &epoch, 100).await;
+    let mut revision = 0;
+    while revision < 200 {
+        let msg = client.recv().await?;
+        revision += msg["History"]["operations"].as_array().map_or(0, Vec::len);
+    }
+
+    // The slow client's socket filled up, so the rest of the history is
+    // collapsed into a few messages once it starts reading again.
+    let (mut text, mut revision, mut catchups) = (String::new(), 0, 0);
+    while revision < 200 {
+        let msg = recv_raw(&mut slow).await?;
+        if let Some(history) = msg.get("History") {
+            assert_eq!(history["start"], revision);
+            for op in history["operations"].as_array().unwrap() {
+                let operation: OperationSeq = serde_json::from_value(op["operation"].clone())?;
+                text = operation.apply(&text)?;
+                revision += 1;
+            }
+        } else if let Some(catchup) = msg.get("Catchup") {
+            assert_eq!(catchup["start"], revision);
+            let operation: OperationSeq = serde_json::from_value(catchup["operation"].clone())?;
+            text = operation.apply(&text)?;
+            revision = catchup["end"].as_u64().unwrap();
+            catchups += 1;
+        }
+    }
+    assert!(catchups > 0);
+    assert_eq!(text, "a".repeat(100));
+    expect_text(&filter, "foobar", &text).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stuck_client_dropped() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        ping_timeout: Duration::from_secs(2),
+        ..ServerConfig::default()
+    });
+    let (addr, serve) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut client = connect(&filter, "foobar").await?;
+    let epoch = client.recv_identity(0).await?;
+
+    // This client stops reading, so the server eventually gives up on it.
+    let mut stuck = connect_raw(addr, "foobar").await?;
+    send_large_edits(&mut client, &epoch, 100).await;
+    time::sleep(Duration::from_secs(3)).await;
+
+    // The server still tries to say why, in case the client is only slow.
+    loop {
+        let msg = recv_raw(&mut stuck).await?;
+        if let Some(closing) = msg.get("Closing") {
+            assert_eq!(closing["reason"], "slow_client");
+            break;
+        }
+    }
+    assert_eq!(recv_close_raw(&mut stuck).await?, 4008);
+
+    let departure = json!({ "UserInfo": { "id": 1, "info": Value::Null } });
+    while client.recv().await? != departure {}
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_slow_client_departures() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_backlog: 8,
+        ..ServerConfig::default()
+    });
+    let (addr, serve) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut slow = connect_raw(addr, "foobar").await?;
+    identity(recv_raw(&mut slow).await?, 0)?;
+    let mut client = connect(&filter, "foobar").await?;
+    let epoch = client.recv_identity(1).await?;
+    send_large_edits(&mut client, &epoch, 20).await;
+    let mut revision = 0;
+    while revision < 40 {
+        let msg = client.recv().await?;
+        revision += msg["History"]["operations"].as_array().map_or(0, Vec::len);
+    }
+
+    // Users come and go while the slow client is skipping updates.
+    for id in 2..50 {
+        let mut other = connect(&filter, "foobar").await?;
+        other.recv_identity(id).await?;
+        other
+            .send(&json!({ "ClientInfo": { "name": "other", "hue": 0 } }))
+            .await;
+        drop(other);
+        let departure = json!({ "UserInfo": { "id": id, "info": Value::Null } });
+        while client.recv().await? != departure {}
+    }
+
+    // Once it reads again, it hears about every departure.
+    let mut departed = 0;
+    while departed < 48 {
+        let msg = recv_raw(&mut slow).await?;
+        if msg["UserInfo"]["info"].is_null() && msg["UserInfo"]["id"].is_u64() {
+            departed += 1;
+        }
+    }
+
+    Ok(())
+}
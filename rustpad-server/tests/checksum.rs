@@ -0,0 +1,98 @@
+//! Tests for detecting clients whose text diverged from the server.
+
+use anyhow::Result;
+use common::*;
+use rustpad_core::checksum::checksum;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_checksum_snapshot() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        checksum_interval: Some(2),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    let epoch = client.recv_identity(0).await?;
+
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 0, "operation": ["hello"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 1, "operation": [5, " world"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    // A checksum is sent after every other revision.
+    let expected = checksum(["hello world"]);
+    assert_eq!(
+        client.recv().await?,
+        json!({ "Checksum": { "revision": 2, "checksum": expected } })
+    );
+
+    let mut client2 = connect(&filter, "foobar").await?;
+    client2.recv_identity(1).await?;
+    client2.recv().await?;
+    let cursor = json!({ "cursors": [5], "selections": [] });
+    client2.send(&json!({ "CursorData": cursor })).await;
+    client.recv().await?;
+
+    // After a mismatch, the client is sent the full text and cursors again.
+    let msg = json!({ "ChecksumMismatch": { "revision": 2 } });
+    client.send(&msg).await;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "Snapshot": { "revision": 2, "text": "hello world" } })
+    );
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserCursor": { "id": 1, "data": cursor } })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checksum_snapshot_lagging() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_backlog: 8,
+        ..ServerConfig::default()
+    });
+    let (addr, serve) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut slow = connect_raw(addr, "foobar").await?;
+    identity(recv_raw(&mut slow).await?, 0)?;
+    let mut client = connect(&filter, "foobar").await?;
+    let epoch = client.recv_identity(1).await?;
+    send_large_edits(&mut client, &epoch, 20).await;
+    let mut revision = 0;
+    while revision < 40 {
+        let msg = client.recv().await?;
+        revision += msg["History"]["operations"].as_array().map_or(0, Vec::len);
+    }
+
+    // The slow client asks for a snapshot while it is skipping updates, and
+    // gets it once it has caught up to the revision of the snapshot.
+    send_raw(&mut slow, &json!({ "ChecksumMismatch": { "revision": 0 } })).await?;
+    let mut revision = 0;
+    loop {
+        let msg = recv_raw(&mut slow).await?;
+        if let Some(history) = msg.get("History") {
+            revision += history["operations"].as_array().map_or(0, Vec::len);
+        } else if let Some(catchup) = msg.get("Catchup") {
+            revision = catchup["end"].as_u64().unwrap() as usize;
+        } else if let Some(snapshot) = msg.get("Snapshot") {
+            assert_eq!(snapshot["revision"], revision);
+            assert_eq!(snapshot["text"], "a".repeat(20));
+            break;
+        }
+    }
+    assert_eq!(revision, 40);
+
+    Ok(())
+}
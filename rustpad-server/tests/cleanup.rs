@@ -22,13 +22,13 @@ async fn test_cleanup() -> Result<()> {
     expect_text(&filter, "old", "").await;
 
     let mut client = connect(&filter, "old").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("hello");
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
@@ -48,5 +48,37 @@ async fn test_cleanup() -> Result<()> {
     time::advance(3 * hour).await;
     expect_text(&filter, "old", "").await;
 
+    // A reloaded document is a new instance, with a new epoch.
+    let mut client = connect(&filter, "old").await?;
+    assert_ne!(client.recv_identity(0).await?, epoch);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cleanup_closing() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let hour = Duration::from_secs(3600);
+    let filter = server(ServerConfig {
+        expiry_days: 1,
+        ping_interval: 100 * hour,
+        ping_timeout: 100 * hour,
+        away_timeout: 100 * hour,
+        ..ServerConfig::default()
+    });
+    let (addr, serve) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut client = connect_raw(addr, "old").await?;
+    identity(recv_raw(&mut client).await?, 0)?;
+
+    // Connected clients are told why the document went away.
+    time::pause();
+    time::advance(25 * hour).await;
+    time::resume();
+    let msg = recv_raw(&mut client).await?;
+    assert_eq!(msg, json!({ "Closing": { "reason": "evicted" } }));
+    assert_eq!(recv_close_raw(&mut client).await?, 4000);
+
     Ok(())
 }
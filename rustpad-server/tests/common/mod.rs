@@ -1,5 +1,11 @@
-use anyhow::{anyhow, Result};
-use serde_json::Value;
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{SinkExt, StreamExt};
+use operational_transform::OperationSeq;
+use serde_json::{json, Value};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 use warp::{filters::BoxedFilter, test::WsClient, Reply};
 
 /// A test WebSocket client that sends and receives JSON messages.
@@ -11,14 +17,32 @@ impl JsonSocket {
     }
 
     pub async fn recv(&mut self) -> Result<Value> {
-        let msg = self.0.recv().await?;
+        let mut msg = self.0.recv().await?;
+        while msg.is_ping() || msg.is_pong() {
+            msg = self.0.recv().await?;
+        }
         let msg = msg.to_str().map_err(|_| anyhow!("non-string message"))?;
         Ok(serde_json::from_str(msg)?)
     }
 
+    /// Check that the next message assigns the given ID, returning the epoch
+    /// of the document.
+    pub async fn recv_identity(&mut self, id: u64) -> Result<String> {
+        identity(self.recv().await?, id)
+    }
+
     pub async fn recv_closed(&mut self) -> Result<()> {
         self.0.recv_closed().await.map_err(|e| e.into())
     }
+
+    /// Check that the server closes the connection with the given reason.
+    pub async fn recv_closing(&mut self, reason: &str) -> Result<()> {
+        let msg = self.recv().await?;
+        if msg != json!({ "Closing": { "reason": reason } }) {
+            bail!("expected closing with reason {}, got {}", reason, msg);
+        }
+        self.recv_closed().await
+    }
 }
 
 /// Connect a new test client WebSocket.
@@ -33,6 +57,31 @@ pub async fn connect(
     Ok(JsonSocket(client))
 }
 
+/// Connect a new test client WebSocket that counts offsets in UTF-16.
+pub async fn connect_utf16(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+) -> Result<JsonSocket> {
+    let client = warp::test::ws()
+        .path(&format!("/api/socket/{}?encoding=utf16", id))
+        .handshake(filter.clone())
+        .await?;
+    Ok(JsonSocket(client))
+}
+
+/// Connect a new test client WebSocket, creating the document with the CRDT
+/// engine if it is new.
+pub async fn connect_crdt(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+) -> Result<JsonSocket> {
+    let client = warp::test::ws()
+        .path(&format!("/api/socket/{}?engine=crdt", id))
+        .handshake(filter.clone())
+        .await?;
+    Ok(JsonSocket(client))
+}
+
 /// Check the text route.
 pub async fn expect_text(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str, text: &str) {
     let resp = warp::test::request()
@@ -42,3 +91,121 @@ pub async fn expect_text(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str
     assert_eq!(resp.status(), 200);
     assert_eq!(resp.body(), text);
 }
+
+/// Replace the text through the text route.
+pub async fn set_text(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str, text: &str) {
+    let resp = warp::test::request()
+        .method("PUT")
+        .path(&format!("/api/text/{}", id))
+        .body(text)
+        .reply(filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+}
+
+/// Create a document through the text route, returning the new ID.
+pub async fn new_text(filter: &BoxedFilter<(impl Reply + 'static,)>, text: &str) -> Result<String> {
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text")
+        .body(text)
+        .reply(filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    body["id"]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| anyhow!("missing id in new text response"))
+}
+
+/// Fork a document through the fork route, returning the new ID.
+pub async fn fork(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str) -> Result<String> {
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/fork/{}", id))
+        .reply(filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    body["id"]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| anyhow!("missing id in fork response"))
+}
+
+/// Check that a message assigns the given ID, returning the epoch of the
+/// document.
+pub fn identity(msg: Value, id: u64) -> Result<String> {
+    match (
+        msg["Identity"]["id"].as_u64(),
+        msg["Identity"]["epoch"].as_str(),
+    ) {
+        (Some(got), Some(epoch)) if got == id => Ok(epoch.into()),
+        _ => bail!("expected identity {}, got {}", id, msg),
+    }
+}
+
+/// A WebSocket to a running server over a plain TCP stream, which is only read
+/// from when asked, like a client on a slow network.
+pub type RawSocket = WebSocketStream<TcpStream>;
+
+/// Open a [`RawSocket`] to a document.
+pub async fn connect_raw(addr: SocketAddr, id: &str) -> Result<RawSocket> {
+    let socket = TcpSocket::new_v4()?;
+    socket.set_recv_buffer_size(4096)?;
+    let stream = socket.connect(addr).await?;
+    let url = format!("ws://{}/api/socket/{}", addr, id);
+    let (socket, _) = tokio_tungstenite::client_async(url, stream).await?;
+    Ok(socket)
+}
+
+/// Read the next text message from a raw WebSocket, skipping control frames.
+pub async fn recv_raw(socket: &mut RawSocket) -> Result<Value> {
+    loop {
+        match socket.next().await.context("connection closed")?? {
+            Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+            Message::Close(_) => bail!("connection closed"),
+            _ => {}
+        }
+    }
+}
+
+/// Wait for a raw WebSocket to be closed, returning the close code.
+pub async fn recv_close_raw(socket: &mut RawSocket) -> Result<u16> {
+    loop {
+        match socket.next().await.context("connection closed")?? {
+            Message::Text(text) => bail!("received message: {}", text),
+            Message::Close(Some(frame)) => return Ok(frame.code.into()),
+            Message::Close(None) => bail!("connection closed without a code"),
+            _ => {}
+        }
+    }
+}
+
+/// Send edits that insert and then delete a large block of text, each pair
+/// leaving one more character at the start of the document.
+pub async fn send_large_edits(client: &mut JsonSocket, epoch: &str, count: u64) {
+    let block = "a".repeat(100_000);
+    for i in 0..count {
+        let mut insert = OperationSeq::default();
+        insert.insert(&block);
+        insert.retain(i);
+        let mut delete = OperationSeq::default();
+        delete.retain(1);
+        delete.delete(block.len() as u64 - 1);
+        delete.retain(i);
+        for (revision, operation) in [(2 * i, insert), (2 * i + 1, delete)] {
+            let msg = json!({
+                "Edit": { "epoch": epoch, "revision": revision, "operation": operation }
+            });
+            client.send(&msg).await;
+        }
+    }
+}
+
+/// Send a text message over a raw WebSocket.
+pub async fn send_raw(socket: &mut RawSocket, msg: &Value) -> Result<()> {
+    socket.send(Message::Text(msg.to_string())).await?;
+    Ok(())
+}
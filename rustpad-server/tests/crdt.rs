@@ -0,0 +1,421 @@
+//! Tests for documents that use the CRDT sync engine.
+
+use anyhow::Result;
+use common::*;
+use log::info;
+use operational_transform::OperationSeq;
+use rustpad_core::crdt::{CrdtOp, Sequence};
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use tokio::time::{self, Duration};
+
+pub mod common;
+
+fn insert(before: u64, text: &str, after: u64) -> OperationSeq {
+    let mut operation = OperationSeq::default();
+    operation.retain(before);
+    operation.insert(text);
+    operation.retain(after);
+    operation
+}
+
+/// Returns the operations in a `CrdtEdit` message from the given user.
+fn crdt_edit(msg: &Value, id: u64) -> Result<Vec<CrdtOp>> {
+    assert_eq!(
+        msg["CrdtEdit"]["id"],
+        json!(id),
+        "unexpected message {}",
+        msg
+    );
+    Ok(serde_json::from_value(msg["CrdtEdit"]["ops"].clone())?)
+}
+
+/// Check that the next message identifies a CRDT document, returning its
+/// epoch.
+async fn recv_crdt_identity(client: &mut JsonSocket, id: u64) -> Result<String> {
+    Ok(recv_crdt_session(client, id).await?.0)
+}
+
+/// Check that the next message identifies a CRDT document, returning its
+/// epoch and the token that resumes the connection.
+async fn recv_crdt_session(client: &mut JsonSocket, id: u64) -> Result<(String, String)> {
+    let msg = client.recv().await?;
+    assert_eq!(msg["Identity"]["engine"], json!("crdt"));
+    let token = msg["Identity"]["token"]
+        .as_str()
+        .expect("identity should have a token")
+        .to_owned();
+    Ok((identity(msg, id)?, token))
+}
+
+#[tokio::test]
+async fn test_concurrent_crdt() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // Connect the first client, which uses its ID as its site.
+    let mut client = connect_crdt(&filter, "foobar").await?;
+    let epoch = recv_crdt_identity(&mut client, 0).await?;
+    let mut replica = Sequence::new(0);
+
+    // Insert the first operation
+    let ops = replica.apply_local(&insert(0, "hello", 0))?;
+    let msg = json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } });
+    info!("sending ClientMsg {}", msg);
+    client.send(&msg).await;
+    assert_eq!(crdt_edit(&client.recv().await?, 0)?, ops);
+
+    // Insert the second operation
+    let mut operation = OperationSeq::default();
+    operation.retain(2);
+    operation.delete(1);
+    operation.insert("n");
+    operation.retain(2);
+    let ops = replica.apply_local(&operation)?;
+    let msg = json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } });
+    info!("sending ClientMsg {}", msg);
+    client.send(&msg).await;
+    assert_eq!(crdt_edit(&client.recv().await?, 0)?, ops);
+    expect_text(&filter, "foobar", "henlo").await;
+
+    // Connect the second client
+    let mut client2 = connect_crdt(&filter, "foobar").await?;
+    recv_crdt_identity(&mut client2, 1).await?;
+    let mut replica2 = Sequence::new(1);
+
+    // Insert a concurrent operation before seeing the existing text
+    time::sleep(Duration::from_millis(50)).await;
+    let ops2 = replica2.apply_local(&insert(0, "~rust~", 0))?;
+    let msg = json!({ "CrdtEdit": { "epoch": epoch, "ops": ops2 } });
+    info!("sending ClientMsg {}", msg);
+    client2.send(&msg).await;
+
+    // Receive the existing sequence, from the server
+    let state = crdt_edit(&client2.recv().await?, u64::MAX)?;
+    let merged = replica2.apply_remote(state)?;
+    assert_eq!(merged.operation.apply("~rust~")?, "~rust~henlo");
+
+    // Expect to receive the operation unchanged, in the first client ...
+    let msg = client.recv().await?;
+    assert_eq!(crdt_edit(&msg, 1)?, ops2);
+    let merged = replica.apply_remote(ops2.clone())?;
+    assert_eq!(merged.operation.apply("henlo")?, "~rust~henlo");
+
+    // ... and in the second client, where it changes nothing
+    let msg = client2.recv().await?;
+    assert_eq!(crdt_edit(&msg, 1)?, ops2);
+    assert!(replica2.apply_remote(ops2)?.operation.is_noop());
+
+    assert_eq!(replica.text(), replica2.text());
+    expect_text(&filter, "foobar", "~rust~henlo").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crdt_reconnect() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect_crdt(&filter, "offline").await?;
+    let (epoch, token) = recv_crdt_session(&mut client, 0).await?;
+    let mut replica = Sequence::new(0);
+    let ops = replica.apply_local(&insert(0, "abc", 0))?;
+    client
+        .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+        .await;
+    crdt_edit(&client.recv().await?, 0)?;
+    drop(client);
+
+    // Edits made while offline are sent along with everything else, after
+    // resuming the earlier connection, and only the new parts are passed on.
+    let mut watcher = connect(&filter, "offline").await?;
+    recv_crdt_identity(&mut watcher, 1).await?;
+    crdt_edit(&watcher.recv().await?, u64::MAX)?;
+    let new_ops = replica.apply_local(&insert(3, "d", 0))?;
+
+    let mut client = connect(&filter, "offline").await?;
+    recv_crdt_identity(&mut client, 2).await?;
+    client.send(&json!({ "Resume": { "token": token } })).await;
+    let msg = json!({ "CrdtEdit": { "epoch": epoch, "ops": replica.state() } });
+    client.send(&msg).await;
+    let msg = watcher.recv().await?;
+    assert_eq!(crdt_edit(&msg, 2)?, new_ops);
+    expect_text(&filter, "offline", "abcd").await;
+
+    // Edits from the text route are made by the server, as another site.
+    set_text(&filter, "offline", "xabcd").await;
+    let msg = watcher.recv().await?;
+    replica.apply_remote(crdt_edit(&msg, u64::MAX)?)?;
+    assert_eq!(replica.text(), "xabcd");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crdt_engine_choice() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // Documents that already exist keep their engine.
+    let mut client = connect(&filter, "ot").await?;
+    client.recv_identity(0).await?;
+    let mut client2 = connect_crdt(&filter, "ot").await?;
+    let msg = client2.recv().await?;
+    assert_eq!(msg["Identity"]["engine"], json!("ot"));
+
+    let msg = json!({ "CrdtEdit": { "epoch": identity(msg, 1)?, "ops": [] } });
+    client2.send(&msg).await;
+    client2.recv_closing("invalid_operation").await?;
+
+    // Edits need to match the engine of the document.
+    let mut client = connect_crdt(&filter, "crdt").await?;
+    let epoch = recv_crdt_identity(&mut client, 0).await?;
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 0, "operation": ["a"] } });
+    client.send(&msg).await;
+    client.recv_closing("invalid_operation").await?;
+
+    // So do the characters that operations refer to.
+    let mut client = connect_crdt(&filter, "crdt").await?;
+    let epoch = recv_crdt_identity(&mut client, 1).await?;
+    let mut replica = Sequence::new(1);
+    replica.apply_remote(Sequence::from("unseen").state())?;
+    let ops = replica.apply_local(&insert(6, "!", 0))?;
+    client
+        .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+        .await;
+    client.recv_closing("invalid_operation").await?;
+    expect_text(&filter, "crdt", "").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crdt_too_large() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect_crdt(&filter, "large").await?;
+    let epoch = recv_crdt_identity(&mut client, 0).await?;
+    let mut replica = Sequence::new(0);
+    let ops = replica.apply_local(&insert(0, &"a".repeat(300_000), 0))?;
+    client
+        .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+        .await;
+    client.recv_closing("edit_too_large").await?;
+    expect_text(&filter, "large", "").await;
+
+    // Deleted characters are kept as tombstones, which don't count toward the
+    // length of the text.
+    let mut client = connect_crdt(&filter, "large").await?;
+    let epoch = recv_crdt_identity(&mut client, 1).await?;
+    let mut replica = Sequence::new(1);
+    let mut ops = replica.apply_local(&insert(0, &"a".repeat(200_000), 0))?;
+    let mut operation = OperationSeq::default();
+    operation.delete(200_000);
+    ops.extend(replica.apply_local(&operation)?);
+    client
+        .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+        .await;
+    crdt_edit(&client.recv().await?, 1)?;
+    let ops = replica.apply_local(&insert(0, &"b".repeat(100_000), 0))?;
+    client
+        .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+        .await;
+    crdt_edit(&client.recv().await?, 1)?;
+    expect_text(&filter, "large", &"b".repeat(100_000)).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crdt_foreign_site() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect_crdt(&filter, "sites").await?;
+    let (epoch, token) = recv_crdt_session(&mut client, 0).await?;
+    let mut watcher = connect_crdt(&filter, "sites").await?;
+    recv_crdt_identity(&mut watcher, 1).await?;
+
+    // Clients can't insert characters as another client, or as the server.
+    for site in [0, u64::MAX] {
+        let mut other = connect_crdt(&filter, "sites").await?;
+        other.recv().await?;
+        let ops = Sequence::new(site).apply_local(&insert(0, "x", 0))?;
+        other
+            .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+            .await;
+        other.recv_closing("invalid_operation").await?;
+    }
+    drop(client);
+    let left = json!({ "UserInfo": { "id": 0, "info": null } });
+    while watcher.recv().await? != left {}
+
+    // Once a client has left, its site is only free for the client that
+    // resumes its connection, and only once.
+    let mut replica = Sequence::new(0);
+    let ops = replica.apply_local(&insert(0, "x", 0))?;
+    let ops2 = replica.apply_local(&insert(1, "y", 0))?;
+    for (token, ops, accepted) in [
+        ("wrong", ops.clone(), false),
+        (&token, ops, true),
+        (&token, ops2, false),
+    ] {
+        let mut other = connect_crdt(&filter, "sites").await?;
+        other.recv().await?;
+        other.send(&json!({ "Resume": { "token": token } })).await;
+        other
+            .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+            .await;
+        if accepted {
+            while watcher.recv().await?.get("CrdtEdit").is_none() {}
+        } else {
+            let closing = json!({ "Closing": { "reason": "invalid_operation" } });
+            while other.recv().await? != closing {}
+        }
+    }
+    expect_text(&filter, "sites", "x").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crdt_resume_timeout() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        expiry_days: 7,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect_crdt(&filter, "late").await?;
+    let (epoch, token) = recv_crdt_session(&mut client, 0).await?;
+    let mut watcher = connect_crdt(&filter, "late").await?;
+    recv_crdt_identity(&mut watcher, 1).await?;
+    drop(client);
+    let left = json!({ "UserInfo": { "id": 0, "info": null } });
+    while watcher.recv().await? != left {}
+
+    // Connections can't be resumed after a day.
+    time::pause();
+    time::advance(Duration::from_secs(25 * 3600)).await;
+    time::resume();
+    let mut client = connect_crdt(&filter, "late").await?;
+    assert_eq!(recv_crdt_identity(&mut client, 2).await?, epoch);
+    let ops = Sequence::new(0).apply_local(&insert(0, "x", 0))?;
+    client.send(&json!({ "Resume": { "token": token } })).await;
+    client
+        .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+        .await;
+    client.recv_closing("invalid_operation").await?;
+    expect_text(&filter, "late", "").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crdt_text_routes() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // New documents from the text routes can use the CRDT engine.
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/put?engine=crdt")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let mut client = connect(&filter, "put").await?;
+    recv_crdt_identity(&mut client, 0).await?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/text?engine=crdt")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    let id = body["id"].as_str().unwrap();
+    let mut client = connect(&filter, id).await?;
+    recv_crdt_identity(&mut client, 0).await?;
+    let mut replica = Sequence::new(0);
+    replica.apply_remote(crdt_edit(&client.recv().await?, u64::MAX)?)?;
+
+    // Forks keep the engine, and merging edits the parent through it.
+    let fork_id = fork(&filter, id).await?;
+    let mut fork_client = connect(&filter, &fork_id).await?;
+    let epoch = recv_crdt_identity(&mut fork_client, 0).await?;
+    let mut fork_replica = Sequence::new(0);
+    fork_replica.apply_remote(crdt_edit(&fork_client.recv().await?, u64::MAX)?)?;
+    let ops = fork_replica.apply_local(&insert(5, "!", 0))?;
+    fork_client
+        .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+        .await;
+    crdt_edit(&fork_client.recv().await?, 0)?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/merge/{}", fork_id))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    replica.apply_remote(crdt_edit(&client.recv().await?, u64::MAX)?)?;
+    assert_eq!(replica.text(), "hello!");
+    expect_text(&filter, id, "hello!").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crdt_utf16_cursors() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect_crdt(&filter, "unicode").await?;
+    let epoch = recv_crdt_identity(&mut client, 0).await?;
+    let mut replica = Sequence::new(0);
+    let ops = replica.apply_local(&insert(0, "🎉a", 0))?;
+    client
+        .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+        .await;
+    crdt_edit(&client.recv().await?, 0)?;
+
+    // Cursors are converted using the text of the sequence as the client last
+    // saw it.
+    let mut client2 = connect_utf16(&filter, "unicode").await?;
+    recv_crdt_identity(&mut client2, 1).await?;
+    crdt_edit(&client2.recv().await?, u64::MAX)?;
+    let cursor = json!({ "CursorData": { "cursors": [3], "selections": [] } });
+    client2.send(&cursor).await;
+
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({ "UserCursor": { "id": 1, "data": { "cursors": [2], "selections": [] } } })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crdt_no_checksums() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        checksum_interval: Some(1),
+        ..ServerConfig::default()
+    });
+
+    // Clients of the CRDT engine don't track revisions, so they aren't sent
+    // checksums to compare against.
+    let mut client = connect_crdt(&filter, "checksums").await?;
+    let epoch = recv_crdt_identity(&mut client, 0).await?;
+    let mut replica = Sequence::new(0);
+    for text in ["a", "b"] {
+        let ops = replica.apply_local(&insert(0, text, replica.len() as u64))?;
+        client
+            .send(&json!({ "CrdtEdit": { "epoch": epoch, "ops": ops } }))
+            .await;
+        crdt_edit(&client.recv().await?, 0)?;
+    }
+    let cursors = json!({ "cursors": [0], "selections": [] });
+    client.send(&json!({ "CursorData": cursors })).await;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserCursor": { "id": 0, "data": cursors } })
+    );
+    Ok(())
+}
@@ -0,0 +1,391 @@
+//! Tests for forking documents.
+
+use anyhow::{anyhow, Result};
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_core::protocol::Engine;
+use rustpad_server::{
+    database::{Database, Lineage, PersistedDocument},
+    server, ServerConfig,
+};
+use serde_json::{json, Value};
+use tempfile::NamedTempFile;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_fork() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "parent").await?;
+    let epoch = client.recv_identity(0).await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({
+        "Edit": {
+            "epoch": epoch,
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
+    let id = fork(&filter, "parent").await?;
+    assert_ne!(id, "parent");
+    expect_text(&filter, &id, "hello").await;
+
+    // The fork is a new instance of the document, with its own history.
+    let mut client2 = connect(&filter, &id).await?;
+    let epoch2 = client2.recv_identity(0).await?;
+    assert_ne!(epoch2, epoch);
+    assert_eq!(
+        client2.recv().await?,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [
+                    { "id": u64::MAX, "operation": ["hello"] }
+                ]
+            }
+        })
+    );
+    assert_eq!(client2.recv().await?, json!({ "Language": "rust" }));
+
+    // Edits to the fork should not affect the parent.
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    let msg = json!({
+        "Edit": {
+            "epoch": epoch2,
+            "revision": 1,
+            "operation": operation
+        }
+    });
+    client2.send(&msg).await;
+    client2.recv().await?;
+
+    expect_text(&filter, &id, "hello world").await;
+    expect_text(&filter, "parent", "hello").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fork_lineage() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let uri = format!(
+        "sqlite://{}",
+        NamedTempFile::new()?
+            .into_temp_path()
+            .as_os_str()
+            .to_str()
+            .expect("failed to get name of tempfile as &str")
+    );
+    let database = Database::new(&uri).await?;
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        ..ServerConfig::default()
+    });
+
+    let doc = PersistedDocument {
+        text: "print('hi')".into(),
+        language: Some("python".into()),
+        engine: Engine::Ot,
+    };
+    database.store("parent", &doc).await?;
+
+    let id = fork(&filter, "parent").await?;
+    expect_text(&filter, &id, "print('hi')").await;
+    assert_eq!(database.load(&id).await?, doc);
+    assert_eq!(
+        database.load_lineage(&id).await?,
+        Lineage {
+            parent_id: "parent".into(),
+            parent_revision: 1,
+            base: "print('hi')".into(),
+        }
+    );
+    assert!(database.load_lineage("parent").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fork_missing() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/fork/missing")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+    expect_text(&filter, "missing", "").await;
+
+    let stats = warp::test::request()
+        .path("/api/stats")
+        .reply(&filter)
+        .await;
+    let stats: Value = serde_json::from_slice(stats.body())?;
+    assert_eq!(stats["num_documents"], json!(0));
+
+    Ok(())
+}
+
+/// Send an edit from a client and wait for the server to acknowledge it.
+async fn edit(
+    client: &mut JsonSocket,
+    epoch: &str,
+    revision: usize,
+    operation: OperationSeq,
+) -> Result<()> {
+    let msg = json!({
+        "Edit": {
+            "epoch": epoch,
+            "revision": revision,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+    Ok(())
+}
+
+/// Merge a fork through the merge route, returning the response body.
+async fn merge(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str) -> Result<Value> {
+    let resp = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/merge/{}", id))
+        .reply(filter)
+        .await;
+    if resp.status() != 200 {
+        return Err(anyhow!("merge failed with status {}", resp.status()));
+    }
+    Ok(serde_json::from_slice(resp.body())?)
+}
+
+#[tokio::test]
+async fn test_merge() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "parent").await?;
+    let epoch = client.recv_identity(0).await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("hello world");
+    edit(&mut client, &epoch, 0, operation).await?;
+
+    let id = fork(&filter, "parent").await?;
+    let mut client2 = connect(&filter, &id).await?;
+    let epoch2 = client2.recv_identity(0).await?;
+    client2.recv().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.delete(6);
+    operation.retain(5);
+    edit(&mut client, &epoch, 1, operation).await?;
+
+    let mut operation = OperationSeq::default();
+    operation.retain(11);
+    operation.insert("!");
+    edit(&mut client2, &epoch2, 1, operation).await?;
+
+    let resp = merge(&filter, &id).await?;
+    assert_eq!(
+        resp,
+        json!({ "parent_id": "parent", "parent_revision": 3, "conflicts": [] })
+    );
+    expect_text(&filter, "parent", "world!").await;
+    expect_text(&filter, &id, "hello world!").await;
+
+    // The parent's clients should see the merge as a single operation.
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "History": {
+                "start": 2,
+                "operations": [
+                    { "id": u64::MAX, "operation": [5, "!"] }
+                ]
+            }
+        })
+    );
+
+    // Merging again should only apply changes made since the last merge.
+    let mut operation = OperationSeq::default();
+    operation.insert(">> ");
+    operation.retain(12);
+    edit(&mut client2, &epoch2, 2, operation).await?;
+
+    merge(&filter, &id).await?;
+    expect_text(&filter, "parent", ">> world!").await;
+    expect_text(&filter, &id, ">> hello world!").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_merge_conflict() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "parent").await?;
+    let epoch = client.recv_identity(0).await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("hello world");
+    edit(&mut client, &epoch, 0, operation).await?;
+
+    let id = fork(&filter, "parent").await?;
+    let mut client2 = connect(&filter, &id).await?;
+    let epoch2 = client2.recv_identity(0).await?;
+    client2.recv().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.retain(6);
+    operation.delete(5);
+    operation.insert("earth");
+    edit(&mut client, &epoch, 1, operation).await?;
+
+    let mut operation = OperationSeq::default();
+    operation.retain(1);
+    operation.delete(1);
+    operation.insert("a");
+    operation.retain(4);
+    operation.delete(5);
+    operation.insert("there");
+    edit(&mut client2, &epoch2, 1, operation).await?;
+
+    let resp = merge(&filter, &id).await?;
+    assert_eq!(resp["conflicts"], json!([[6, 11]]));
+    expect_text(&filter, "parent", "hallo thereearth").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_merge_not_fork() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "parent").await?;
+    client.recv_identity(0).await?;
+
+    assert!(merge(&filter, "parent").await.is_err());
+    assert!(merge(&filter, "missing").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_merge_after_reload() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let uri = format!(
+        "sqlite://{}",
+        NamedTempFile::new()?
+            .into_temp_path()
+            .as_os_str()
+            .to_str()
+            .expect("failed to get name of tempfile as &str")
+    );
+    let database = Database::new(&uri).await?;
+    let config = ServerConfig {
+        database: Some(database.clone()),
+        ..ServerConfig::default()
+    };
+    let document = |text: &str| PersistedDocument {
+        text: text.into(),
+        language: None,
+        engine: Engine::Ot,
+    };
+    database.store("parent", &document("hello world")).await?;
+    let id = fork(&server(config.clone()), "parent").await?;
+
+    // Both documents change while the server is down, and are loaded again by
+    // a new server with no history from before.
+    database.store("parent", &document("hello, world")).await?;
+    database.store(&id, &document("hello world!")).await?;
+    let filter = server(config);
+
+    let resp = merge(&filter, &id).await?;
+    assert_eq!(resp["conflicts"], json!([]));
+    expect_text(&filter, "parent", "hello, world!").await;
+    assert_eq!(
+        database.load_lineage(&id).await?,
+        Lineage {
+            parent_id: "parent".into(),
+            parent_revision: 2,
+            base: "hello world!".into(),
+        }
+    );
+
+    // Later merges go back to using the history.
+    set_text(&filter, &id, ">> hello world!").await;
+    merge(&filter, &id).await?;
+    expect_text(&filter, "parent", ">> hello, world!").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_merge_errors() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let uri = format!(
+        "sqlite://{}",
+        NamedTempFile::new()?
+            .into_temp_path()
+            .as_os_str()
+            .to_str()
+            .expect("failed to get name of tempfile as &str")
+    );
+    let database = Database::new(&uri).await?;
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        ..ServerConfig::default()
+    });
+    let status = |id: &str| {
+        let request = warp::test::request()
+            .method("POST")
+            .path(&format!("/api/merge/{}", id));
+        async { request.reply(&filter).await.status() }
+    };
+
+    assert_eq!(status("missing").await, 404);
+    set_text(&filter, "parent", "hello").await;
+    assert_eq!(status("parent").await, 404);
+
+    // A document can't be merged into itself.
+    let document = PersistedDocument {
+        text: "loop".into(),
+        language: None,
+        engine: Engine::Ot,
+    };
+    database.store("loop", &document).await?;
+    let lineage = Lineage {
+        parent_id: "loop".into(),
+        parent_revision: 0,
+        base: "loop".into(),
+    };
+    database.store_lineage("loop", &lineage).await?;
+    assert_eq!(status("loop").await, 400);
+
+    // Changes that can't be applied together leave the parent unchanged.
+    let id = fork(&filter, "parent").await?;
+    set_text(&filter, &id, &"a".repeat(200_000)).await;
+    set_text(&filter, "parent", &"b".repeat(200_000)).await;
+    assert_eq!(status(&id).await, 409);
+    expect_text(&filter, "parent", &"b".repeat(200_000)).await;
+
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! Tests for detecting unresponsive and idle clients.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use futures::StreamExt;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+use tokio::time::{self, Instant};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_ping_timeout() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        ping_interval: Duration::from_millis(50),
+        ping_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    });
+    let (addr, serve) = warp::serve(filter.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let mut client = connect(&filter, "foobar").await?;
+    client.recv_identity(0).await?;
+
+    // A client that completes the handshake, then never reads from the socket
+    // or answers pings, like a half-open connection.
+    let mut ghost = connect_raw(addr, "foobar").await?;
+
+    let start = Instant::now();
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserInfo": { "id": 1, "info": null } })
+    );
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // The server closes the ghost's socket.
+    let closed = async { while let Some(Ok(_)) = ghost.next().await {} };
+    time::timeout(Duration::from_secs(1), closed).await?;
+
+    // Clients that answer pings stay connected.
+    time::sleep(Duration::from_millis(300)).await;
+    let cursors = json!({ "cursors": [0], "selections": [] });
+    client.send(&json!({ "CursorData": cursors })).await;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserCursor": { "id": 0, "data": cursors } })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_away_status() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        away_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    client.recv_identity(0).await?;
+
+    let alice = json!({
+        "name": "Alice",
+        "hue": 42
+    });
+    client.send(&json!({ "ClientInfo": alice })).await;
+    let alice_info = json!({ "UserInfo": { "id": 0, "info": alice } });
+    assert_eq!(client.recv().await?, alice_info);
+
+    let start = Instant::now();
+    let alice_away = json!({ "UserStatus": { "id": 0, "status": "away" } });
+    assert_eq!(client.recv().await?, alice_away);
+    assert!(start.elapsed() >= Duration::from_millis(150));
+
+    // New clients are told who is away when they join.
+    let mut client2 = connect(&filter, "foobar").await?;
+    client2.recv_identity(1).await?;
+    assert_eq!(client2.recv().await?, alice_info);
+    assert_eq!(client2.recv().await?, alice_away);
+
+    // Any message from the user makes them active again.
+    let cursors = json!({ "cursors": [0], "selections": [] });
+    client.send(&json!({ "CursorData": cursors })).await;
+    assert_eq!(
+        client2.recv().await?,
+        json!({ "UserStatus": { "id": 0, "status": "active" } })
+    );
+    assert_eq!(
+        client2.recv().await?,
+        json!({ "UserCursor": { "id": 0, "data": cursors } })
+    );
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! Tests for fetching ranges of lines from a document.
+
+pub mod common;
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+use warp::{filters::BoxedFilter, Reply};
+
+async fn expect_lines(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    lines: &str,
+    text: &str,
+) {
+    let resp = warp::test::request()
+        .path(&format!("/api/text/{}?lines={}", id, lines))
+        .reply(filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), text);
+}
+
+#[tokio::test]
+async fn test_text_lines() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    expect_lines(&filter, "lines", "1-3", "").await;
+    set_text(&filter, "lines", "one\ntwo\nthree\nfour").await;
+    expect_lines(&filter, "lines", "2-3", "two\nthree\n").await;
+    expect_lines(&filter, "lines", "4", "four").await;
+    expect_lines(&filter, "lines", "3-100", "three\nfour").await;
+    expect_lines(&filter, "lines", "7-8", "").await;
+
+    let mut client = connect(&filter, "lines").await?;
+    let epoch = client.recv_identity(0).await?;
+    client.recv().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.retain(4);
+    operation.insert("🎉\n");
+    operation.delete(4);
+    operation.retain(10);
+    let msg = json!({
+        "Edit": {
+            "epoch": epoch,
+            "revision": 1,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    expect_text(&filter, "lines", "one\n🎉\nthree\nfour").await;
+    expect_lines(&filter, "lines", "2", "🎉\n").await;
+    expect_lines(&filter, "lines", "3-4", "three\nfour").await;
+
+    // Only newlines separate lines, not carriage returns on their own.
+    set_text(&filter, "lines", "one\rtwo\r\nthree").await;
+    expect_lines(&filter, "lines", "1", "one\rtwo\r\n").await;
+    expect_lines(&filter, "lines", "2", "three").await;
+
+    for lines in ["0-2", "3-2", "a-b", "-", "99999999999"] {
+        let resp = warp::test::request()
+            .path(&format!("/api/text/lines?lines={}", lines))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 400);
+        let body = std::str::from_utf8(resp.body())?;
+        assert_eq!(body, format!("invalid range of lines: {}", lines));
+    }
+
+    Ok(())
+}
@@ -5,13 +5,14 @@ use std::time::Duration;
 use anyhow::Result;
 use common::*;
 use operational_transform::OperationSeq;
+use rustpad_core::protocol::Engine;
 use rustpad_server::{
     database::{Database, PersistedDocument},
-    server, ServerConfig,
+    server, server_with_shutdown, ServerConfig,
 };
 use serde_json::json;
 use tempfile::NamedTempFile;
-use tokio::time;
+use tokio::{sync::watch, time};
 
 pub mod common;
 
@@ -38,6 +39,7 @@ async fn test_database() -> Result<()> {
     let doc1 = PersistedDocument {
         text: "Hello Text".into(),
         language: None,
+        engine: Engine::Ot,
     };
 
     assert!(database.store("hello", &doc1).await.is_ok());
@@ -47,6 +49,7 @@ async fn test_database() -> Result<()> {
     let doc2 = PersistedDocument {
         text: "print('World Text :)')".into(),
         language: Some("python".into()),
+        engine: Engine::Crdt,
     };
 
     assert!(database.store("world", &doc2).await.is_ok());
@@ -56,6 +59,11 @@ async fn test_database() -> Result<()> {
     assert!(database.store("hello", &doc2).await.is_ok());
     assert_eq!(database.load("hello").await?, doc2);
 
+    assert!(database.delete("hello").await?);
+    assert!(!database.delete("hello").await?);
+    assert!(database.load("hello").await.is_err());
+    assert_eq!(database.load("world").await?, doc2);
+
     Ok(())
 }
 
@@ -66,18 +74,19 @@ async fn test_persist() -> Result<()> {
     let filter = server(ServerConfig {
         expiry_days: 2,
         database: Some(Database::new(&temp_sqlite_uri()?).await?),
+        ..ServerConfig::default()
     });
 
     expect_text(&filter, "persist", "").await;
 
     let mut client = connect(&filter, "persist").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("hello");
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
@@ -104,3 +113,122 @@ async fn test_persist() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_shutdown() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let (shutdown, signal) = watch::channel(());
+    let (filter, shutdown_task) = server_with_shutdown(ServerConfig {
+        database: Some(database.clone()),
+        shutdown: Some(signal),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "shutdown").await?;
+    let epoch = client.recv_identity(0).await?;
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 0, "operation": ["hello"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    // The edit is persisted right away, rather than lost before the next
+    // snapshot would have been taken.
+    shutdown.send(())?;
+    shutdown_task.await?;
+    assert_eq!(database.load("shutdown").await?.text, "hello");
+    client.recv_closing("shutdown").await?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_shutdown_signal() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    // Run the server binary, and stop it the way a process manager would.
+    let uri = temp_sqlite_uri()?;
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let mut server = tokio::process::Command::new(env!("CARGO_BIN_EXE_rustpad-server"))
+        .env("PORT", addr.port().to_string())
+        .env("SQLITE_URI", &uri)
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut client = loop {
+        match connect_raw(addr, "signal").await {
+            Ok(client) => break client,
+            Err(_) => time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    let epoch = identity(recv_raw(&mut client).await?, 0)?;
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 0, "operation": ["hello"] } });
+    send_raw(&mut client, &msg).await?;
+    recv_raw(&mut client).await?;
+
+    let pid = server.id().expect("server should be running").to_string();
+    let status = std::process::Command::new("kill")
+        .args(["-TERM", &pid])
+        .status()?;
+    assert!(status.success());
+    assert!(server.wait().await?.success());
+
+    // The client was told why before the process exited.
+    while recv_raw(&mut client).await?.get("Closing").is_none() {}
+    assert_eq!(recv_close_raw(&mut client).await?, 1001);
+    let database = Database::new(&uri).await?;
+    assert_eq!(database.load("signal").await?.text, "hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        admin_token: Some("secret".into()),
+        ..ServerConfig::default()
+    });
+    set_text(&filter, "doomed", "hello").await;
+    let mut client = connect(&filter, "doomed").await?;
+    client.recv_identity(0).await?;
+    client.recv().await?;
+    let document = PersistedDocument {
+        text: "hello".into(),
+        language: None,
+        engine: Engine::Ot,
+    };
+    database.store("doomed", &document).await?;
+
+    let delete = |authorization: &str| {
+        warp::test::request()
+            .method("DELETE")
+            .path("/api/text/doomed")
+            .header("authorization", authorization)
+            .reply(&filter)
+    };
+    for authorization in ["Bearer wrong", "Bearer secre", "Bearer secrets", "secret"] {
+        assert_eq!(delete(authorization).await.status(), 401);
+    }
+    assert_eq!(delete("Bearer secret").await.status(), 204);
+    client.recv_closing("killed").await?;
+    assert!(database.load("doomed").await.is_err());
+    assert_eq!(delete("Bearer secret").await.status(), 404);
+
+    // Documents can't be deleted at all without an admin token.
+    let filter = server(ServerConfig::default());
+    set_text(&filter, "safe", "hello").await;
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path("/api/text/safe")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    expect_text(&filter, "safe", "hello").await;
+
+    Ok(())
+}
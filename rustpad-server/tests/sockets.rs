@@ -20,13 +20,13 @@ async fn test_single_operation() -> Result<()> {
     expect_text(&filter, "foobar", "").await;
 
     let mut client = connect(&filter, "foobar").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("hello");
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
@@ -59,13 +59,13 @@ async fn test_invalid_operation() -> Result<()> {
     expect_text(&filter, "foobar", "").await;
 
     let mut client = connect(&filter, "foobar").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("hello");
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 1,
             "operation": operation
         }
@@ -73,7 +73,70 @@ async fn test_invalid_operation() -> Result<()> {
     info!("sending ClientMsg {}", msg);
     client.send(&msg).await;
 
-    client.recv_closed().await?;
+    client.recv_closing("invalid_operation").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rate_limit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_message_rate: Some(5),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "limited").await?;
+    client.recv_identity(0).await?;
+    for i in 0..100 {
+        let msg = json!({ "CursorData": { "cursors": [i], "selections": [] } });
+        client.send(&msg).await;
+    }
+    client.recv_closing("rate_limited").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stale_epoch() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    let epoch = client.recv_identity(0).await?;
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 0, "operation": ["hello"] } });
+    client.send(&msg).await;
+    let history = json!({
+        "History": {
+            "start": 0,
+            "operations": [
+                { "id": 0, "operation": ["hello"] }
+            ]
+        }
+    });
+    assert_eq!(client.recv().await?, history);
+
+    // Edits against another instance of the document are not applied, and
+    // the client is sent its identity and the whole document again.
+    let msg = json!({ "Edit": { "epoch": "stale", "revision": 0, "operation": ["hi"] } });
+    client.send(&msg).await;
+    assert_eq!(client.recv_identity(0).await?, epoch);
+    assert_eq!(client.recv().await?, history);
+    expect_text(&filter, "foobar", "hello").await;
+
+    // Clients counting in UTF-16 can keep editing after starting over.
+    let mut client = connect_utf16(&filter, "foobar").await?;
+    client.recv_identity(1).await?;
+    assert_eq!(client.recv().await?, history);
+    let msg = json!({ "Edit": { "epoch": "stale", "revision": 1, "operation": [5, "!"] } });
+    client.send(&msg).await;
+    client.recv_identity(1).await?;
+    assert_eq!(client.recv().await?, history);
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 1, "operation": [5, "🎉"] } });
+    client.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["History"]["start"], 1);
+    expect_text(&filter, "foobar", "hello🎉").await;
+
     Ok(())
 }
 
@@ -84,14 +147,14 @@ async fn test_concurrent_transform() -> Result<()> {
 
     // Connect the first client
     let mut client = connect(&filter, "foobar").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     // Insert the first operation
     let mut operation = OperationSeq::default();
     operation.insert("hello");
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
@@ -120,6 +183,7 @@ async fn test_concurrent_transform() -> Result<()> {
     operation.retain(2);
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 1,
             "operation": operation
         }
@@ -143,8 +207,7 @@ async fn test_concurrent_transform() -> Result<()> {
 
     // Connect the second client
     let mut client2 = connect(&filter, "foobar").await?;
-    let msg = client2.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 1 }));
+    client2.recv_identity(1).await?;
 
     // Insert a concurrent operation before seeing the existing history
     time::sleep(Duration::from_millis(50)).await;
@@ -152,6 +215,7 @@ async fn test_concurrent_transform() -> Result<()> {
     operation.insert("~rust~");
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
@@ -202,8 +266,7 @@ async fn test_set_language() -> Result<()> {
     let filter = server(ServerConfig::default());
 
     let mut client = connect(&filter, "foobar").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_identity(0).await?;
 
     let msg = json!({ "SetLanguage": "javascript" });
     client.send(&msg).await;
@@ -212,8 +275,7 @@ async fn test_set_language() -> Result<()> {
     assert_eq!(msg, json!({ "Language": "javascript" }));
 
     let mut client2 = connect(&filter, "foobar").await?;
-    let msg = client2.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 1 }));
+    client2.recv_identity(1).await?;
     let msg = client2.recv().await?;
     assert_eq!(msg, json!({ "Language": "javascript" }));
 
@@ -228,3 +290,91 @@ async fn test_set_language() -> Result<()> {
     expect_text(&filter, "foobar", "").await;
     Ok(())
 }
+
+#[tokio::test]
+async fn test_set_text() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    client.recv_identity(0).await?;
+
+    set_text(&filter, "foobar", "hello world").await;
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [
+                    { "id": u64::MAX, "operation": ["hello world"] }
+                ]
+            }
+        })
+    );
+
+    // Replacing the text should only change the parts that differ.
+    set_text(&filter, "foobar", "help, world!").await;
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 1,
+                "operations": [
+                    { "id": u64::MAX, "operation": [3, "p,", -2, 6, "!"] }
+                ]
+            }
+        })
+    );
+    expect_text(&filter, "foobar", "help, world!").await;
+
+    // Setting the same text should not create a new revision.
+    set_text(&filter, "foobar", "help, world!").await;
+    set_text(&filter, "foobar", "").await;
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 2,
+                "operations": [
+                    { "id": u64::MAX, "operation": [-12] }
+                ]
+            }
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_new_text() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let id = new_text(&filter, "hello world").await?;
+    assert_eq!(id.len(), 6);
+    expect_text(&filter, &id, "hello world").await;
+
+    // Each new document gets its own ID, even with the same text.
+    let other = new_text(&filter, "hello world").await?;
+    assert_ne!(id, other);
+
+    let mut client = connect(&filter, &id).await?;
+    client.recv_identity(0).await?;
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [
+                    { "id": u64::MAX, "operation": ["hello world"] }
+                ]
+            }
+        })
+    );
+
+    Ok(())
+}
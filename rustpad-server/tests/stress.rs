@@ -1,5 +1,6 @@
 //! Stress tests for liveness and consistency properties.
 
+use std::collections::HashMap;
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
@@ -20,12 +21,10 @@ async fn test_lost_wakeups() -> Result<()> {
     expect_text(&filter, "stress", "").await;
 
     let mut client = connect(&filter, "stress").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let mut client2 = connect(&filter, "stress").await?;
-    let msg = client2.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 1 }));
+    client2.recv_identity(1).await?;
 
     let mut revision = 0;
     for i in 0..100 {
@@ -36,6 +35,7 @@ async fn test_lost_wakeups() -> Result<()> {
             operation.insert("a");
             let msg = json!({
                 "Edit": {
+                    "epoch": epoch,
                     "revision": revision,
                     "operation": operation
                 }
@@ -53,12 +53,18 @@ async fn test_lost_wakeups() -> Result<()> {
         let mut total = 0;
         while total < num_edits {
             let msg = client.recv().await?;
+            if msg.get("Checksum").is_some() {
+                continue;
+            }
             total += num_ops(&msg).ok_or_else(|| anyhow!("missing json key"))?;
         }
 
         let mut total2 = 0;
         while total2 < num_edits {
             let msg = client2.recv().await?;
+            if msg.get("Checksum").is_some() {
+                continue;
+            }
             total2 += num_ops(&msg).ok_or_else(|| anyhow!("missing json key"))?;
         }
 
@@ -79,13 +85,13 @@ async fn test_large_document() -> Result<()> {
     expect_text(&filter, "stress", "").await;
 
     let mut client = connect(&filter, "stress").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let mut operation = OperationSeq::default();
     operation.insert(&"a".repeat(5000));
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
@@ -97,12 +103,67 @@ async fn test_large_document() -> Result<()> {
     operation.insert(&"a".repeat(500000));
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
     });
     client.send(&msg).await;
-    client.recv_closed().await?;
+    client.recv_closing("edit_too_large").await?;
 
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_cursor_storm() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut watcher = connect(&filter, "stress").await?;
+    watcher.recv_identity(0).await?;
+
+    let num_users = 20;
+    let num_moves = 200;
+    let mut tasks = Vec::new();
+    for i in 0..num_users {
+        let mut client = connect(&filter, "stress").await?;
+        tasks.push(tokio::spawn(async move {
+            let info = json!({ "ClientInfo": { "name": format!("user{}", i), "hue": i } });
+            client.send(&info).await;
+            for j in 0..num_moves {
+                let cursor = json!({ "CursorData": { "cursors": [j], "selections": [] } });
+                client.send(&cursor).await;
+            }
+            client
+        }));
+    }
+    // Keep the clients connected until the watcher has caught up.
+    let mut clients = Vec::new();
+    for task in tasks {
+        clients.push(task.await?);
+    }
+
+    // The watcher hasn't read anything yet, but it should not be dropped, and
+    // it should eventually see the final information and cursors of each user.
+    let mut names = HashMap::new();
+    let mut cursors = HashMap::new();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while names.len() < num_users as usize
+        || cursors.values().filter(|&&c| c == num_moves - 1).count() < num_users as usize
+    {
+        let msg = tokio::time::timeout_at(deadline, watcher.recv()).await??;
+        if let Some(info) = msg.get("UserInfo") {
+            let id = info["id"].as_u64().unwrap();
+            names.insert(id, info["info"]["name"].clone());
+        } else if let Some(cursor) = msg.get("UserCursor") {
+            let id = cursor["id"].as_u64().unwrap();
+            cursors.insert(id, cursor["data"]["cursors"][0].as_u64().unwrap());
+        }
+    }
+    for (id, name) in names {
+        assert_eq!(name, json!(format!("user{}", id - 1)));
+    }
+
+    drop(clients);
+    Ok(())
+}
@@ -17,13 +17,13 @@ async fn test_unicode_length() -> Result<()> {
     expect_text(&filter, "unicode", "").await;
 
     let mut client = connect(&filter, "unicode").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("h🎉e🎉l👨‍👨‍👦‍👦lo");
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
@@ -49,6 +49,7 @@ async fn test_unicode_length() -> Result<()> {
     operation.delete(14);
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 1,
             "operation": operation
         }
@@ -82,13 +83,13 @@ async fn test_multiple_operations() -> Result<()> {
     expect_text(&filter, "unicode", "").await;
 
     let mut client = connect(&filter, "unicode").await?;
-    let msg = client.recv().await?;
-    assert_eq!(msg, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("🎉😍𒀇👨‍👨‍👦‍👦"); // Emoticons and Cuneiform
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
@@ -116,6 +117,7 @@ async fn test_multiple_operations() -> Result<()> {
     operation.retain(7);
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 1,
             "operation": operation
         }
@@ -144,6 +146,7 @@ async fn test_multiple_operations() -> Result<()> {
     operation.retain(8);
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 1,
             "operation": operation
         }
@@ -175,12 +178,13 @@ async fn test_unicode_cursors() -> Result<()> {
     let filter = server(ServerConfig::default());
 
     let mut client = connect(&filter, "unicode").await?;
-    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("🎉🎉🎉");
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": operation
         }
@@ -204,12 +208,13 @@ async fn test_unicode_cursors() -> Result<()> {
     assert_eq!(client.recv().await?, cursors_resp);
 
     let mut client2 = connect(&filter, "unicode").await?;
-    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_identity(1).await?;
     client2.recv().await?;
     assert_eq!(client2.recv().await?, cursors_resp);
 
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": ["🎉"]
         }
@@ -217,7 +222,7 @@ async fn test_unicode_cursors() -> Result<()> {
     client2.send(&msg).await;
 
     let mut client3 = connect(&filter, "unicode").await?;
-    assert_eq!(client3.recv().await?, json!({ "Identity": 2 }));
+    client3.recv_identity(2).await?;
     client3.recv().await?;
 
     let transformed_cursors_resp = json!({
@@ -233,3 +238,151 @@ async fn test_unicode_cursors() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_utf16_encoding() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect_utf16(&filter, "utf16").await?;
+    let epoch = client.recv_identity(0).await?;
+
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 0, "operation": ["🎉a"] } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    info!("sending an edit with UTF-16 offsets...");
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 1, "operation": [2, "b", 1] } });
+    client.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 1,
+                "operations": [
+                    { "id": 0, "operation": [2, "b", 1] }
+                ]
+            }
+        })
+    );
+    expect_text(&filter, "utf16", "🎉ba").await;
+
+    info!("checking that other clients still see code points...");
+    let mut client2 = connect(&filter, "utf16").await?;
+    client2.recv_identity(1).await?;
+    let msg = client2.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [
+                    { "id": 0, "operation": ["🎉a"] },
+                    { "id": 0, "operation": [1, "b", 1] }
+                ]
+            }
+        })
+    );
+
+    let cursors = json!({ "cursors": [2], "selections": [[0, 4]] });
+    client.send(&json!({ "CursorData": cursors })).await;
+    assert_eq!(
+        client2.recv().await?,
+        json!({
+            "UserCursor": {
+                "id": 0,
+                "data": { "cursors": [1], "selections": [[0, 3]] }
+            }
+        })
+    );
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserCursor": { "id": 0, "data": cursors } })
+    );
+
+    info!("sending an edit based on an older revision...");
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 2, "operation": [1, "😍", 2] } });
+    client2.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 2,
+                "operations": [
+                    { "id": 1, "operation": [2, "😍", 2] }
+                ]
+            }
+        })
+    );
+
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 2, "operation": [4, "c"] } });
+    client.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({
+            "History": {
+                "start": 3,
+                "operations": [
+                    { "id": 0, "operation": [6, "c"] }
+                ]
+            }
+        })
+    );
+    expect_text(&filter, "utf16", "🎉😍bac").await;
+
+    info!("checking that splitting a surrogate pair is rejected...");
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 4, "operation": [1, "x", 6] } });
+    client.send(&msg).await;
+    client.recv_closing("invalid_operation").await?;
+    expect_text(&filter, "utf16", "🎉😍bac").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_utf16_old_revision() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_backlog: 10_000,
+        ..ServerConfig::default()
+    });
+
+    let mut writer = connect(&filter, "old").await?;
+    let epoch = writer.recv_identity(0).await?;
+    let mut recent = connect_utf16(&filter, "old").await?;
+    recent.recv_identity(1).await?;
+    let mut stale = connect_utf16(&filter, "old").await?;
+    stale.recv_identity(2).await?;
+
+    info!("making more edits than are kept for UTF-16 clients...");
+    for revision in 0..1001 {
+        let msg = json!({ "Edit": { "epoch": epoch, "revision": revision, "operation": ["🎉", revision] } });
+        writer.send(&msg).await;
+    }
+    for client in [&mut recent, &mut stale] {
+        let mut revision = 0;
+        while revision < 1001 {
+            let msg = client.recv().await?;
+            revision += msg["History"]["operations"].as_array().map_or(0, Vec::len);
+        }
+    }
+
+    // The text at revision 1 is still kept, but not at revision 0.
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 1, "operation": [2, "a"] } });
+    recent.send(&msg).await;
+    let msg = recent.recv().await?;
+    assert_eq!(msg["History"]["start"], 1001);
+
+    let msg = json!({ "Edit": { "epoch": epoch, "revision": 0, "operation": ["a"] } });
+    stale.send(&msg).await;
+    let mut msg = stale.recv().await?;
+    while msg.get("History").is_some() {
+        msg = stale.recv().await?;
+    }
+    assert_eq!(msg, json!({ "Closing": { "reason": "invalid_operation" } }));
+
+    Ok(())
+}
@@ -1,5 +1,7 @@
 //! Tests for synchronization of user presence.
 
+use std::time::Duration;
+
 use anyhow::Result;
 use common::*;
 use rustpad_server::{server, ServerConfig};
@@ -13,7 +15,7 @@ async fn test_two_users() -> Result<()> {
     let filter = server(ServerConfig::default());
 
     let mut client = connect(&filter, "foobar").await?;
-    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_identity(0).await?;
 
     let alice = json!({
         "name": "Alice",
@@ -30,7 +32,7 @@ async fn test_two_users() -> Result<()> {
     assert_eq!(client.recv().await?, alice_info);
 
     let mut client2 = connect(&filter, "foobar").await?;
-    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_identity(1).await?;
     assert_eq!(client2.recv().await?, alice_info);
 
     let bob = json!({
@@ -57,7 +59,7 @@ async fn test_invalid_user() -> Result<()> {
     let filter = server(ServerConfig::default());
 
     let mut client = connect(&filter, "foobar").await?;
-    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_identity(0).await?;
 
     let alice = json!({ "name": "Alice" }); // no hue
     client.send(&json!({ "ClientInfo": alice })).await;
@@ -72,7 +74,7 @@ async fn test_leave_rejoin() -> Result<()> {
     let filter = server(ServerConfig::default());
 
     let mut client = connect(&filter, "foobar").await?;
-    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_identity(0).await?;
 
     let alice = json!({
         "name": "Alice",
@@ -92,7 +94,7 @@ async fn test_leave_rejoin() -> Result<()> {
     client.recv_closed().await?;
 
     let mut client2 = connect(&filter, "foobar").await?;
-    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_identity(1).await?;
 
     let bob = json!({
         "name": "Bob",
@@ -117,7 +119,7 @@ async fn test_cursors() -> Result<()> {
     let filter = server(ServerConfig::default());
 
     let mut client = connect(&filter, "foobar").await?;
-    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let epoch = client.recv_identity(0).await?;
 
     let cursors = json!({
         "cursors": [4, 6, 7],
@@ -134,7 +136,7 @@ async fn test_cursors() -> Result<()> {
     assert_eq!(client.recv().await?, cursors_resp);
 
     let mut client2 = connect(&filter, "foobar").await?;
-    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_identity(1).await?;
     assert_eq!(client2.recv().await?, cursors_resp);
 
     let cursors2 = json!({
@@ -157,6 +159,7 @@ async fn test_cursors() -> Result<()> {
 
     let msg = json!({
         "Edit": {
+            "epoch": epoch,
             "revision": 0,
             "operation": ["a"]
         }
@@ -164,7 +167,7 @@ async fn test_cursors() -> Result<()> {
     client2.send(&msg).await;
 
     let mut client3 = connect(&filter, "foobar").await?;
-    assert_eq!(client3.recv().await?, json!({ "Identity": 2 }));
+    client3.recv_identity(2).await?;
     client3.recv().await?;
 
     let transformed_cursors2_resp = json!({
@@ -180,3 +183,116 @@ async fn test_cursors() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_cursors_set_text() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    set_text(&filter, "foobar", "fn main() {}").await;
+
+    let mut client = connect(&filter, "foobar").await?;
+    client.recv_identity(0).await?;
+    client.recv().await?;
+
+    let cursors = json!({
+        "cursors": [9],
+        "selections": [[3, 7]]
+    });
+    client.send(&json!({ "CursorData": cursors })).await;
+    client.recv().await?;
+
+    set_text(&filter, "foobar", "pub fn main() { todo!() }").await;
+
+    let mut client2 = connect(&filter, "foobar").await?;
+    client2.recv_identity(1).await?;
+    client2.recv().await?;
+
+    let transformed_cursors_resp = json!({
+        "UserCursor": {
+            "id": 0,
+            "data": {
+                "cursors": [13],
+                "selections": [[7, 11]]
+            }
+        }
+    });
+    assert_eq!(client2.recv().await?, transformed_cursors_resp);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cursor_batching() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        cursor_interval: Some(Duration::from_millis(200)),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    let epoch = client.recv_identity(0).await?;
+    let mut client2 = connect(&filter, "foobar").await?;
+    client2.recv_identity(1).await?;
+
+    for i in 0..10 {
+        let cursors = json!({ "cursors": [i], "selections": [] });
+        client.send(&json!({ "CursorData": cursors })).await;
+    }
+    let cursors2 = json!({ "cursors": [0], "selections": [[0, 0]] });
+    client2.send(&json!({ "CursorData": cursors2 })).await;
+
+    // Only the latest position of each user is sent, in one message.
+    let batch = json!({
+        "UserCursors": [
+            { "id": 0, "data": { "cursors": [9], "selections": [] } },
+            { "id": 1, "data": cursors2 }
+        ]
+    });
+    assert_eq!(client.recv().await?, batch);
+    assert_eq!(client2.recv().await?, batch);
+
+    // Edits are sent right away, and pending cursors are transformed by them.
+    client.send(&json!({ "CursorData": cursors2 })).await;
+    let msg = json!({
+        "Edit": {
+            "epoch": epoch,
+            "revision": 0,
+            "operation": ["ab"]
+        }
+    });
+    client2.send(&msg).await;
+    let history = json!({
+        "History": {
+            "start": 0,
+            "operations": [{ "id": 1, "operation": ["ab"] }]
+        }
+    });
+    let batch = json!({
+        "UserCursors": [{ "id": 0, "data": { "cursors": [2], "selections": [[2, 2]] } }]
+    });
+    assert_eq!(client.recv().await?, history);
+    assert_eq!(client.recv().await?, batch);
+    assert_eq!(client2.recv().await?, history);
+    assert_eq!(client2.recv().await?, batch);
+
+    // Users who disconnect before their batch is sent are left out of it.
+    client2.send(&json!({ "CursorData": cursors2 })).await;
+    client2.send(&json!({ "Invalid": "please close" })).await;
+    client2.recv_closed().await?;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserInfo": { "id": 1, "info": null } })
+    );
+
+    let cursors = json!({ "cursors": [1], "selections": [] });
+    client.send(&json!({ "CursorData": cursors })).await;
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "UserCursors": [{ "id": 0, "data": cursors }]
+        })
+    );
+
+    Ok(())
+}
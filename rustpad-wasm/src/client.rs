@@ -0,0 +1,147 @@
+//! Client-side synchronization state, exported to JavaScript.
+
+use js_sys::{Array, Object, Reflect};
+use rustpad_core::client::Action;
+use rustpad_core::protocol::{ClientMsg, ServerMsg, UserOperation};
+use wasm_bindgen::prelude::*;
+
+use crate::OpSeq;
+
+/// This is a wrapper around `rustpad_core::client::OtClient`, which tracks
+/// local edits that the server has not yet acknowledged.
+#[wasm_bindgen]
+#[derive(Default, Clone, Debug)]
+pub struct OtClient(rustpad_core::client::OtClient);
+
+#[wasm_bindgen]
+impl OtClient {
+    /// Creates a client that has not received any messages yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of operations received from the server.
+    pub fn revision(&self) -> usize {
+        self.0.revision()
+    }
+
+    /// Checks if a local edit is waiting to be acknowledged by the server.
+    pub fn has_outstanding(&self) -> bool {
+        self.0.outstanding().is_some()
+    }
+
+    /// Checks if local edits are waiting to be sent to the server.
+    pub fn has_buffer(&self) -> bool {
+        self.0.buffer().is_some()
+    }
+
+    /// Records an edit made to the local text, returning a JSON message to
+    /// send to the server if no other edit is in flight.
+    pub fn apply_client(&mut self, operation: &OpSeq) -> Result<Option<String>, JsValue> {
+        let msg = self.0.apply_client(operation.0.clone()).map_err(to_js)?;
+        Ok(msg.as_ref().map(to_json))
+    }
+
+    /// Returns a JSON message that resends the outstanding edit, which should
+    /// be sent after reconnecting to the server.
+    pub fn resend(&self) -> Option<String> {
+        self.0.resend().as_ref().map(to_json)
+    }
+
+    /// Compares the local text with a checksum sent by the server, returning a
+    /// JSON message to report a mismatch, if any.
+    pub fn check(&self, revision: usize, checksum: u32, text: &str) -> Option<String> {
+        self.0.check(revision, checksum, text).as_ref().map(to_json)
+    }
+
+    /// Processes a JSON message from the server, returning an array of steps
+    /// to take in order. Each step is either `{ apply: OpSeq }`, an operation
+    /// to apply to the local text, `{ send: string }`, a message to send, or
+    /// `{ reset: true }`, when the local text should be cleared before it is
+    /// rebuilt by the steps and history that follow.
+    pub fn handle_message(&mut self, msg: &str) -> Result<Array, JsValue> {
+        let actions = match serde_json::from_str(msg).map_err(to_js)? {
+            ServerMsg::Identity { id, epoch, .. } => self.0.apply_identity(id, epoch),
+            ServerMsg::History { start, operations } => {
+                self.0.apply_history(start, operations).map_err(to_js)?
+            }
+            ServerMsg::Catchup {
+                start,
+                end,
+                operation,
+            } => self.0.apply_catchup(start, end, operation).map_err(to_js)?,
+            ServerMsg::Snapshot { revision, text } => {
+                self.0.apply_snapshot(revision, &text).map_err(to_js)?
+            }
+            _ => Vec::new(),
+        };
+        Ok(to_steps(actions))
+    }
+}
+
+/// This is a wrapper around `rustpad_core::crdt::CrdtClient`, which keeps a
+/// replica of the sequence for documents that use the CRDT engine.
+#[wasm_bindgen]
+#[derive(Default, Clone, Debug)]
+pub struct CrdtClient(rustpad_core::crdt::CrdtClient);
+
+#[wasm_bindgen]
+impl CrdtClient {
+    /// Creates a client that has not received any messages yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the text of the local replica, which matches the editor once
+    /// every step from the server has been applied.
+    pub fn text(&self) -> String {
+        self.0.sequence().text()
+    }
+
+    /// Records an edit made to the local text, returning a JSON message to
+    /// send to the server once the client has been identified.
+    pub fn apply_client(&mut self, operation: &OpSeq) -> Result<Option<String>, JsValue> {
+        let msg = self.0.apply_client(operation.0.clone()).map_err(to_js)?;
+        Ok(msg.as_ref().map(to_json))
+    }
+
+    /// Processes a JSON message from the server, returning an array of steps
+    /// to take in order, in the same format as `OtClient.handle_message()`.
+    pub fn handle_message(&mut self, msg: &str) -> Result<Array, JsValue> {
+        let actions = match serde_json::from_str(msg).map_err(to_js)? {
+            ServerMsg::Identity {
+                id, epoch, token, ..
+            } => self.0.apply_identity(id, epoch, token).map_err(to_js)?,
+            ServerMsg::CrdtEdit { id, ops } => self.0.apply_edit(id, ops).map_err(to_js)?,
+            _ => Vec::new(),
+        };
+        Ok(to_steps(actions))
+    }
+}
+
+/// Converts actions into the steps returned by `handle_message()`.
+fn to_steps(actions: Vec<Action>) -> Array {
+    let steps = Array::new();
+    for action in actions {
+        let step = Object::new();
+        match action {
+            Action::Apply(UserOperation { operation, .. }) => {
+                Reflect::set(&step, &"apply".into(), &OpSeq(operation).into())
+            }
+            Action::Send(msg) => Reflect::set(&step, &"send".into(), &to_json(&msg).into()),
+            Action::Reset => Reflect::set(&step, &"reset".into(), &true.into()),
+            Action::Ack => continue,
+        }
+        .expect("setting property on plain object");
+        steps.push(&step);
+    }
+    steps
+}
+
+fn to_json(msg: &ClientMsg) -> String {
+    serde_json::to_string(msg).expect("json serialization failure")
+}
+
+fn to_js(err: impl std::fmt::Display) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
@@ -2,12 +2,53 @@
 
 #![warn(missing_docs)]
 
-use operational_transform::OperationSeq;
+use js_sys::{Array, Object, Reflect};
+use operational_transform::{Operation, OperationSeq};
 use serde::{Deserialize, Serialize};
-use wasm_bindgen::prelude::*;
+use wasm_bindgen::{prelude::*, JsCast};
 
+pub mod client;
+pub mod lines;
+pub mod undo;
+pub mod utf16;
 pub mod utils;
 
+#[wasm_bindgen(typescript_custom_section)]
+const TS_TYPES: &str = r#"
+/** A single component of an operation. */
+export type OpComponent = { retain: number } | { insert: string } | { delete: number };
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    /// An array of `OpComponent` objects, typed for TypeScript.
+    #[wasm_bindgen(typescript_type = "OpComponent[]")]
+    pub type OpComponents;
+
+    /// An array of `[start, end]` pairs, typed for TypeScript.
+    #[wasm_bindgen(typescript_type = "[number, number][]")]
+    pub type Ranges;
+}
+
+/// Which side of a position text inserted exactly at it ends up on.
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Affinity {
+    /// The position sticks to the text before it, so inserted text goes after.
+    Left,
+    /// The position sticks to the text after it, so inserted text goes before.
+    Right,
+}
+
+impl From<Affinity> for rustpad_core::protocol::Affinity {
+    fn from(affinity: Affinity) -> Self {
+        match affinity {
+            Affinity::Left => Self::Left,
+            Affinity::Right => Self::Right,
+        }
+    }
+}
+
 /// This is an wrapper around `operational_transform::OperationSeq`, which is
 /// necessary for Wasm compatibility through `wasm-bindgen`.
 #[wasm_bindgen]
@@ -52,6 +93,13 @@ impl OpSeq {
         Self(OperationSeq::with_capacity(capacity))
     }
 
+    /// Creates an operation that transforms `old` into `new`, touching as few
+    /// characters as it can find. This is useful for turning whole-buffer
+    /// changes, like running a code formatter, into small edits.
+    pub fn from_diff(old: &str, new: &str) -> Self {
+        Self(rustpad_core::ot::diff(old, new))
+    }
+
     /// Merges the operation with `other` into one operation while preserving
     /// the changes of both. Or, in other words, for each input string S and a
     /// pair of consecutive operations A and B.
@@ -135,23 +183,93 @@ impl OpSeq {
 
     /// Return the new index of a position in the string.
     pub fn transform_index(&self, position: u32) -> u32 {
-        let mut index = position as i32;
-        let mut new_index = index;
+        rustpad_core::ot::transform_index(&self.0, position)
+    }
+
+    /// Return the new index of a position in the string, where `affinity`
+    /// decides which side of the position text inserted exactly at it goes on.
+    pub fn transform_index_with_affinity(&self, position: u32, affinity: Affinity) -> u32 {
+        rustpad_core::ot::transform_index_with_affinity(&self.0, position, affinity.into())
+    }
+
+    /// Returns the components of this operation as an array of objects, each
+    /// of the form `{ retain: n }`, `{ insert: s }`, or `{ delete: n }`.
+    pub fn components(&self) -> OpComponents {
+        let components = Array::new();
         for op in self.0.ops() {
-            use operational_transform::Operation::*;
-            match op {
-                &Retain(n) => index -= n as i32,
-                Insert(s) => new_index += bytecount::num_chars(s.as_bytes()) as i32,
-                &Delete(n) => {
-                    new_index -= std::cmp::min(index, n as i32);
-                    index -= n as i32;
-                }
-            }
-            if index < 0 {
-                break;
+            let (key, value) = match op {
+                &Operation::Retain(n) => ("retain", JsValue::from(n as f64)),
+                Operation::Insert(s) => ("insert", JsValue::from(s.as_str())),
+                &Operation::Delete(n) => ("delete", JsValue::from(n as f64)),
+            };
+            let component = Object::new();
+            Reflect::set(&component, &key.into(), &value)
+                .expect("setting property on plain object");
+            components.push(&component);
+        }
+        components.unchecked_into()
+    }
+
+    /// Attempts to construct an `OpSeq` from an array of components, in the
+    /// same format as returned by `components()`.
+    pub fn from_components(components: OpComponents) -> Option<OpSeq> {
+        let mut operation = OperationSeq::default();
+        for component in Array::from(&components).iter() {
+            let count = |key: &str| {
+                let n = Reflect::get(&component, &key.into()).ok()?.as_f64()?;
+                (n >= 0.0 && n.fract() == 0.0).then_some(n as u64)
+            };
+            if let Some(n) = count("retain") {
+                operation.retain(n);
+            } else if let Some(n) = count("delete") {
+                operation.delete(n);
+            } else {
+                let s = Reflect::get(&component, &"insert".into())
+                    .ok()?
+                    .as_string()?;
+                operation.insert(&s);
             }
         }
-        new_index as u32
+        Some(Self(operation))
+    }
+
+    /// Returns the ranges of the original string changed by this operation.
+    /// Deletions produce the range of deleted characters, while insertions
+    /// produce an empty range where text was inserted.
+    pub fn changed_ranges(&self) -> Ranges {
+        to_ranges(rustpad_core::ot::changed_ranges(&self.0))
+    }
+
+    /// Returns the ranges of the resulting string affected by this operation.
+    /// Insertions produce the range of inserted characters, while deletions
+    /// produce an empty range where text was deleted.
+    pub fn affected_ranges(&self) -> Ranges {
+        to_ranges(rustpad_core::ot::affected_ranges(&self.0))
+    }
+
+    /// Converts an operation on `text` from code points to UTF-16 code units,
+    /// for use with connections opened with `?encoding=utf16`. The result
+    /// should only be serialized, not applied or transformed.
+    ///
+    /// # Error
+    ///
+    /// Returns `None` if the operation does not match the length of `text`.
+    pub fn to_utf16(&self, text: &str) -> Option<OpSeq> {
+        rustpad_core::utf16::operation_to_utf16(&self.0, text)
+            .ok()
+            .map(Self)
+    }
+
+    /// Converts an operation on `text` from UTF-16 code units to code points.
+    ///
+    /// # Error
+    ///
+    /// Returns `None` if the operation does not match the length of `text`, or
+    /// if it splits a surrogate pair.
+    pub fn from_utf16(&self, text: &str) -> Option<OpSeq> {
+        rustpad_core::utf16::operation_from_utf16(&self.0, text)
+            .ok()
+            .map(Self)
     }
 
     /// Attempts to deserialize an `OpSeq` from a JSON string.
@@ -179,3 +297,11 @@ impl OpSeqPair {
         self.1.clone()
     }
 }
+
+fn to_ranges(ranges: Vec<(u32, u32)>) -> Ranges {
+    let array = Array::new();
+    for (start, end) in ranges {
+        array.push(&Array::of2(&start.into(), &end.into()));
+    }
+    array.unchecked_into()
+}
@@ -0,0 +1,59 @@
+//! Line and column positions, exported to JavaScript.
+
+use js_sys::Array;
+use wasm_bindgen::{prelude::*, JsCast};
+
+use crate::OpSeq;
+
+#[wasm_bindgen]
+extern "C" {
+    /// A `[line, column]` pair, typed for TypeScript.
+    #[wasm_bindgen(typescript_type = "[number, number]")]
+    pub type Position;
+}
+
+/// This is a wrapper around `rustpad_core::lines::LineIndex`, which converts
+/// between character offsets and zero-based (line, column) positions.
+#[wasm_bindgen]
+#[derive(Default, Clone, Debug)]
+pub struct LineIndex(rustpad_core::lines::LineIndex);
+
+#[wasm_bindgen]
+impl LineIndex {
+    /// Creates an index of the lines in a text.
+    pub fn new(text: &str) -> Self {
+        Self(rustpad_core::lines::LineIndex::new(text))
+    }
+
+    /// Returns the number of lines, which is always at least one.
+    pub fn len_lines(&self) -> u32 {
+        self.0.len_lines()
+    }
+
+    /// Returns the length of the text in characters.
+    pub fn len_chars(&self) -> u32 {
+        self.0.len_chars()
+    }
+
+    /// Returns the offset of the start of a line, if it exists.
+    pub fn line_start(&self, line: u32) -> Option<u32> {
+        self.0.line_start(line)
+    }
+
+    /// Returns the `[line, column]` position of an offset, clamped to the text.
+    pub fn position(&self, offset: u32) -> Position {
+        let (line, column) = self.0.position(offset);
+        Array::of2(&line.into(), &column.into()).unchecked_into()
+    }
+
+    /// Returns the offset of a line and column, clamped to the end of the line
+    /// and to the last line.
+    pub fn offset(&self, line: u32, column: u32) -> u32 {
+        self.0.offset(line, column)
+    }
+
+    /// Updates the index for an operation applied to the text.
+    pub fn apply(&mut self, operation: &OpSeq) {
+        self.0.apply(&operation.0)
+    }
+}
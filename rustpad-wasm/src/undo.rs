@@ -0,0 +1,59 @@
+//! Collaborative undo history, exported to JavaScript.
+
+use wasm_bindgen::prelude::*;
+
+use crate::OpSeq;
+
+/// This is a wrapper around `rustpad_core::undo::UndoManager`, which records
+/// local edits and transforms them against remote ones, so that undo only
+/// reverts changes made by the local user.
+#[wasm_bindgen]
+#[derive(Default, Clone, Debug)]
+pub struct UndoManager(rustpad_core::undo::UndoManager);
+
+#[wasm_bindgen]
+impl UndoManager {
+    /// Creates an undo manager with empty history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks if there is a change that can be undone.
+    pub fn can_undo(&self) -> bool {
+        self.0.can_undo()
+    }
+
+    /// Checks if there is a change that can be redone.
+    pub fn can_redo(&self) -> bool {
+        self.0.can_redo()
+    }
+
+    /// Forgets all undo and redo history.
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    /// Records a local edit, given the text before the edit was applied.
+    pub fn add(&mut self, operation: &OpSeq, text: &str) {
+        self.0.add(&operation.0, text)
+    }
+
+    /// Transforms the history by a remote operation applied to the text.
+    pub fn transform(&mut self, operation: &OpSeq) {
+        self.0.transform(&operation.0)
+    }
+
+    /// Returns an operation that reverts the last local change, given the
+    /// current text. This should be applied and sent as a local edit, but not
+    /// passed to `add()`.
+    pub fn undo(&mut self, text: &str) -> Option<OpSeq> {
+        self.0.undo(text).map(OpSeq)
+    }
+
+    /// Returns an operation that reapplies the last undone change, given the
+    /// current text. This should be applied and sent as a local edit, but not
+    /// passed to `add()`.
+    pub fn redo(&mut self, text: &str) -> Option<OpSeq> {
+        self.0.redo(text).map(OpSeq)
+    }
+}
@@ -0,0 +1,16 @@
+//! Conversions between Unicode code point offsets and UTF-16 code units.
+
+use wasm_bindgen::prelude::*;
+
+/// Returns the UTF-16 offset of a code point index in the text.
+#[wasm_bindgen]
+pub fn index_to_utf16(text: &str, index: u32) -> u32 {
+    rustpad_core::utf16::index_to_utf16(text, index)
+}
+
+/// Returns the code point index of a UTF-16 offset in the text, rounding down
+/// in the middle of a surrogate pair.
+#[wasm_bindgen]
+pub fn index_from_utf16(text: &str, offset: u32) -> u32 {
+    rustpad_core::utf16::index_from_utf16(text, offset)
+}
@@ -1,13 +1,16 @@
-//! Test suite for the Web and headless browsers.
+//! Test suite for the WebAssembly bindings, run with Node.js.
 
 #![cfg(target_arch = "wasm32")]
 
-use rustpad_wasm::OpSeq;
+use js_sys::{Array, Object, Reflect};
+use rustpad_wasm::client::{CrdtClient, OtClient};
+use rustpad_wasm::lines::LineIndex;
+use rustpad_wasm::utf16::{index_from_utf16, index_to_utf16};
+use rustpad_wasm::{undo::UndoManager, Affinity, OpSeq};
+use wasm_bindgen::{JsCast, JsValue};
 
 use wasm_bindgen_test::*;
 
-wasm_bindgen_test_configure!(run_in_browser);
-
 #[wasm_bindgen_test]
 fn compose_operations() {
     let mut a = OpSeq::default();
@@ -63,3 +66,230 @@ fn transform_index() {
     assert_eq!(o.transform_index(5), 8);
     assert_eq!(o.transform_index(7), 13);
 }
+
+#[wasm_bindgen_test]
+fn transform_index_affinity() {
+    let mut o = OpSeq::default();
+    o.retain(3);
+    o.insert("def");
+    o.retain(3);
+    assert_eq!(o.transform_index_with_affinity(3, Affinity::Left), 3);
+    assert_eq!(o.transform_index_with_affinity(3, Affinity::Right), 6);
+    assert_eq!(o.transform_index_with_affinity(4, Affinity::Left), 7);
+}
+
+#[wasm_bindgen_test]
+fn diff_operations() {
+    let old = "fn main() {}";
+    let new = "pub fn main() { todo!() }";
+    let o = OpSeq::from_diff(old, new);
+    assert_eq!(o.apply(old).unwrap(), new);
+    assert_eq!(o.to_string(), r#"["pub ",11," todo!() ",1]"#);
+    assert_eq!(o.transform_index(9), 13);
+
+    let o = OpSeq::from_diff("h🎉llo", "h🎉lo!");
+    assert_eq!(o.to_string(), r#"[3,-1,1,"!"]"#);
+    assert!(OpSeq::from_diff("same", "same").is_noop());
+}
+
+#[wasm_bindgen_test]
+fn client_state_machine() {
+    let mut client = OtClient::new();
+    client
+        .handle_message(r#"{"Identity":{"id":1,"epoch":"e1"}}"#)
+        .unwrap();
+
+    let mut o = OpSeq::default();
+    o.insert("abc");
+    let msg = client.apply_client(&o).unwrap();
+    assert_eq!(
+        msg.unwrap(),
+        r#"{"Edit":{"epoch":"e1","revision":0,"operation":["abc"]}}"#
+    );
+    let mut p = OpSeq::default();
+    p.retain(3);
+    p.insert("d");
+    assert_eq!(client.apply_client(&p).unwrap(), None);
+    assert!(client.has_outstanding() && client.has_buffer());
+
+    // The server puts the edit it receives first when both insert at the
+    // same position, and so does the client.
+    let history = r#"{"History":{"start":0,"operations":[
+        {"id":0,"operation":["xy"]},
+        {"id":1,"operation":["abc",2]}
+    ]}}"#;
+    let steps = client.handle_message(history).unwrap();
+    assert_eq!(steps.length(), 2);
+    let apply = Reflect::get(&steps.get(0), &"apply".into()).unwrap();
+    assert!(apply.is_object());
+    let send = Reflect::get(&steps.get(1), &"send".into()).unwrap();
+    assert_eq!(
+        send.as_string().unwrap(),
+        r#"{"Edit":{"epoch":"e1","revision":2,"operation":[3,"d",2]}}"#
+    );
+    assert_eq!(client.revision(), 2);
+    assert!(client.has_outstanding() && !client.has_buffer());
+
+    let catchup = r#"{"Catchup":{"start":2,"end":5,"operation":["!",5]}}"#;
+    let steps = client.handle_message(catchup).unwrap();
+    assert_eq!(steps.length(), 1);
+    assert_eq!(client.revision(), 5);
+
+    assert!(client
+        .handle_message(r#"{"History":{"start":8,"operations":[]}}"#)
+        .is_err());
+
+    let steps = client
+        .handle_message(r#"{"Identity":{"id":0,"epoch":"e2"}}"#)
+        .unwrap();
+    assert_eq!(steps.length(), 1);
+    let reset = Reflect::get(&steps.get(0), &"reset".into()).unwrap();
+    assert_eq!(reset.as_bool(), Some(true));
+    assert_eq!(client.revision(), 0);
+    assert!(!client.has_outstanding());
+
+    let history = r#"{"History":{"start":0,"operations":[{"id":2,"operation":["abc"]}]}}"#;
+    client.handle_message(history).unwrap();
+    assert_eq!(client.check(1, 440920331, "abc"), None);
+    assert_eq!(
+        client.check(1, 440920331, "abd").unwrap(),
+        r#"{"ChecksumMismatch":{"revision":1}}"#
+    );
+    let steps = client
+        .handle_message(r#"{"Snapshot":{"revision":1,"text":"abc"}}"#)
+        .unwrap();
+    assert_eq!(steps.length(), 2);
+}
+
+#[wasm_bindgen_test]
+fn crdt_client_convergence() {
+    // Pass on an edit from one client to the others, like the server does.
+    fn relay(msg: &str, id: u64) -> String {
+        let msg: serde_json::Value = serde_json::from_str(msg).unwrap();
+        let ops = &msg["CrdtEdit"]["ops"];
+        format!(r#"{{"CrdtEdit":{{"id":{},"ops":{}}}}}"#, id, ops)
+    }
+
+    let mut a = CrdtClient::new();
+    let mut b = CrdtClient::new();
+    let mut o = OpSeq::default();
+    o.insert("hello");
+    assert_eq!(b.apply_client(&o).unwrap(), None);
+
+    a.handle_message(r#"{"Identity":{"id":0,"epoch":"e1","engine":"crdt"}}"#)
+        .unwrap();
+    let steps = b
+        .handle_message(r#"{"Identity":{"id":1,"epoch":"e1","engine":"crdt"}}"#)
+        .unwrap();
+    assert_eq!(steps.length(), 1);
+    let from_b = Reflect::get(&steps.get(0), &"send".into()).unwrap();
+    let mut p = OpSeq::default();
+    p.insert("world");
+    let from_a = a.apply_client(&p).unwrap().unwrap();
+
+    let steps = a
+        .handle_message(&relay(&from_b.as_string().unwrap(), 1))
+        .unwrap();
+    assert_eq!(steps.length(), 1);
+    let apply = Reflect::get(&steps.get(0), &"apply".into()).unwrap();
+    assert!(apply.is_object());
+    b.handle_message(&relay(&from_a, 0)).unwrap();
+    assert_eq!(a.text(), b.text());
+    assert_eq!(a.text(), "helloworld");
+
+    // Echoes of a client's own edits change nothing.
+    let steps = a.handle_message(&relay(&from_a, 0)).unwrap();
+    assert_eq!(steps.length(), 0);
+}
+
+#[wasm_bindgen_test]
+fn undo_remote_operations() {
+    let mut undo = UndoManager::new();
+    let mut o = OpSeq::default();
+    o.retain(5);
+    o.insert(" world");
+    undo.add(&o, "hello");
+
+    let mut remote = OpSeq::default();
+    remote.insert(">> ");
+    remote.retain(11);
+    undo.transform(&remote);
+
+    let text = ">> hello world";
+    let op = undo.undo(text).unwrap();
+    assert_eq!(op.apply(text).unwrap(), ">> hello");
+    assert!(!undo.can_undo() && undo.can_redo());
+    assert_eq!(
+        undo.redo(">> hello").unwrap().apply(">> hello").unwrap(),
+        text
+    );
+}
+
+#[wasm_bindgen_test]
+fn operation_components() {
+    let mut o = OpSeq::default();
+    o.retain(2);
+    o.delete(3);
+    o.insert("a🎉c");
+    o.retain(1);
+    let components = Array::from(&o.components());
+    assert_eq!(components.length(), 4);
+    let insert = Reflect::get(&components.get(1), &"insert".into()).unwrap();
+    assert_eq!(insert.as_string().unwrap(), "a🎉c");
+    let delete = Reflect::get(&components.get(2), &"delete".into()).unwrap();
+    assert_eq!(delete.as_f64(), Some(3.0));
+    assert_eq!(OpSeq::from_components(o.components()), Some(o.clone()));
+
+    // The insert comes before the delete, since operations are normalized.
+    let ranges = Array::from(&o.affected_ranges());
+    assert_eq!(ranges.length(), 2);
+    assert_eq!(range(ranges.get(0)), (2.0, 5.0));
+    assert_eq!(range(ranges.get(1)), (5.0, 5.0));
+    let ranges = Array::from(&o.changed_ranges());
+    assert_eq!(ranges.length(), 2);
+    assert_eq!(range(ranges.get(0)), (2.0, 2.0));
+    assert_eq!(range(ranges.get(1)), (2.0, 5.0));
+
+    let invalid = Array::of1(&Object::new());
+    assert_eq!(OpSeq::from_components(invalid.unchecked_into()), None);
+}
+
+#[wasm_bindgen_test]
+fn utf16_offsets() {
+    let text = "🎉a";
+    let mut o = OpSeq::default();
+    o.retain(1);
+    o.insert("😍");
+    o.retain(1);
+    let converted = o.to_utf16(text).unwrap();
+    assert_eq!(converted.to_string(), r#"[2,"😍",1]"#);
+    assert_eq!(converted.from_utf16(text), Some(o.clone()));
+    assert_eq!(o.to_utf16("a"), None);
+
+    let split = OpSeq::from_str(r#"[1,"x",2]"#).unwrap();
+    assert_eq!(split.from_utf16(text), None);
+
+    assert_eq!(index_to_utf16(text, 1), 2);
+    assert_eq!(index_from_utf16(text, 3), 2);
+    assert_eq!(index_from_utf16(text, 1), 0);
+}
+
+#[wasm_bindgen_test]
+fn line_positions() {
+    let mut index = LineIndex::new("ab\ncd");
+    assert_eq!(index.len_lines(), 2);
+    assert_eq!(range(index.position(4).into()), (1.0, 1.0));
+    assert_eq!(index.offset(1, 5), 5);
+
+    let o = OpSeq::from_str(r#"[1,"\n",4]"#).unwrap();
+    index.apply(&o);
+    assert_eq!(index.len_lines(), 3);
+    assert_eq!(index.line_start(1), Some(2));
+    assert_eq!(range(index.position(4).into()), (2.0, 0.0));
+    assert_eq!(range(index.position(5).into()), (2.0, 1.0));
+}
+
+fn range(value: JsValue) -> (f64, f64) {
+    let pair = Array::from(&value);
+    (pair.get(0).as_f64().unwrap(), pair.get(1).as_f64().unwrap())
+}
@@ -11,7 +11,7 @@ import ReadCodeConfirm from "./ReadCodeConfirm";
 import Sidebar from "./Sidebar";
 import animals from "./animals.json";
 import languages from "./languages.json";
-import Rustpad, { UserInfo } from "./rustpad";
+import Rustpad, { CloseReason, UserInfo } from "./rustpad";
 import useHash from "./useHash";
 
 function getWsUri(id: string) {
@@ -20,6 +20,16 @@ function getWsUri(id: string) {
   return url.href;
 }
 
+const closeMessages: Record<CloseReason, string> = {
+  evicted: "This document expired after a period of inactivity.",
+  killed: "This document was deleted.",
+  shutdown: "The server is shutting down.",
+  edit_too_large: "Your edit would make the document too large.",
+  invalid_operation: "Your edit could not be applied to the document.",
+  rate_limited: "You are sending changes too quickly.",
+  slow_client: "Your connection fell too far behind the document.",
+};
+
 function generateName() {
   return "Anonymous " + animals[Math.floor(Math.random() * animals.length)];
 }
@@ -35,6 +45,7 @@ function App() {
     "connected" | "disconnected" | "desynchronized"
   >("disconnected");
   const [users, setUsers] = useState<Record<number, UserInfo>>({});
+  const [away, setAway] = useState<Set<number>>(new Set());
   const [name, setName] = useLocalStorageState("name", {
     defaultValue: generateName,
   });
@@ -69,12 +80,21 @@ function App() {
             duration: null,
           });
         },
+        onClosed: (reason) => {
+          toast({
+            title: "Disconnected from server",
+            description: `${closeMessages[reason]} Please save your work and refresh the page.`,
+            status: "error",
+            duration: null,
+          });
+        },
         onChangeLanguage: (language) => {
           if (languages.includes(language)) {
             setLanguage(language);
           }
         },
         onChangeUsers: setUsers,
+        onChangeAway: setAway,
       });
       return () => {
         rustpad.current?.dispose();
@@ -163,6 +183,7 @@ function App() {
           language={language}
           currentUser={{ name, hue }}
           users={users}
+          away={away}
           onDarkModeChange={handleDarkModeChange}
           onLanguageChange={handleLanguageChange}
           onLoadSample={() => handleLoadSample(false)}
@@ -27,6 +27,7 @@ export type SidebarProps = {
   language: string;
   currentUser: UserInfo;
   users: Record<number, UserInfo>;
+  away: Set<number>;
   onDarkModeChange: () => void;
   onLanguageChange: (language: string) => void;
   onLoadSample: () => void;
@@ -41,6 +42,7 @@ function Sidebar({
   language,
   currentUser,
   users,
+  away,
   onDarkModeChange,
   onLanguageChange,
   onLoadSample,
@@ -135,7 +137,12 @@ function Sidebar({
           darkMode={darkMode}
         />
         {Object.entries(users).map(([id, info]) => (
-          <User key={id} info={info} darkMode={darkMode} />
+          <User
+            key={id}
+            info={info}
+            isAway={away.has(Number(id))}
+            darkMode={darkMode}
+          />
         ))}
       </Stack>
 
@@ -24,6 +24,7 @@ import { UserInfo } from "./rustpad";
 type UserProps = {
   info: UserInfo;
   isMe?: boolean;
+  isAway?: boolean;
   onChangeName?: (name: string) => void;
   onChangeColor?: () => void;
   darkMode: boolean;
@@ -32,6 +33,7 @@ type UserProps = {
 function User({
   info,
   isMe = false,
+  isAway = false,
   onChangeName,
   onChangeColor,
   darkMode,
@@ -55,6 +57,7 @@ function User({
             bgColor: darkMode ? "#464647" : "gray.200",
             cursor: "pointer",
           }}
+          opacity={isAway ? 0.6 : 1}
           onClick={() => isMe && onOpen()}
         >
           <Icon as={VscAccount} />
@@ -62,6 +65,7 @@ function User({
             {info.name}
           </Text>
           {isMe && <Text>(you)</Text>}
+          {isAway && <Text>(away)</Text>}
         </HStack>
       </PopoverTrigger>
       <PopoverContent
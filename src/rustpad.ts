@@ -4,7 +4,13 @@ import type {
   IPosition,
   editor,
 } from "monaco-editor/esm/vs/editor/editor.api";
-import { OpSeq } from "rustpad-wasm";
+import {
+  Affinity,
+  CrdtClient,
+  OpSeq,
+  OtClient,
+  UndoManager,
+} from "rustpad-wasm";
 
 /** Options passed in to the Rustpad constructor. */
 export type RustpadOptions = {
@@ -13,11 +19,33 @@ export type RustpadOptions = {
   readonly onConnected?: () => void;
   readonly onDisconnected?: () => void;
   readonly onDesynchronized?: () => void;
+  readonly onClosed?: (reason: CloseReason) => void;
   readonly onChangeLanguage?: (language: string) => void;
   readonly onChangeUsers?: (users: Record<number, UserInfo>) => void;
+  readonly onChangeAway?: (away: Set<number>) => void;
   readonly reconnectInterval?: number;
 };
 
+/** Why the server closed the connection, if it gave a reason. */
+export type CloseReason =
+  | "evicted"
+  | "killed"
+  | "shutdown"
+  | "edit_too_large"
+  | "invalid_operation"
+  | "rate_limited"
+  | "slow_client";
+
+/**
+ * Close reasons after which the client reconnects on its own, matching
+ * `CloseReason::can_reconnect` on the server.
+ */
+export const reconnectReasons = new Set<CloseReason>([
+  "shutdown",
+  "rate_limited",
+  "slow_client",
+]);
+
 /** A user currently editing the document. */
 export type UserInfo = {
   readonly name: string;
@@ -33,17 +61,21 @@ class Rustpad {
   private readonly onChangeHandle: IDisposable;
   private readonly onCursorHandle: IDisposable;
   private readonly onSelectionHandle: IDisposable;
+  private readonly onKeyDownHandle: IDisposable;
   private readonly beforeUnload: (event: BeforeUnloadEvent) => void;
   private readonly tryConnectId: number;
   private readonly resetFailuresId: number;
 
   // Client-server state
   private me: number = -1;
-  private revision: number = 0;
-  private outstanding?: OpSeq;
-  private buffer?: OpSeq;
+  private readonly ot: OtClient = OtClient.new();
+  /** Replaces `ot` for documents that use the CRDT engine. */
+  private crdt?: CrdtClient;
+  private readonly undoManager: UndoManager = UndoManager.new();
   private users: Record<number, UserInfo> = {};
   private userCursors: Record<number, CursorData> = {};
+  private away: Set<number> = new Set();
+  private closeReason?: CloseReason;
   private myInfo?: UserInfo;
   private cursorData: CursorData = { cursors: [], selections: [] };
 
@@ -66,8 +98,22 @@ class Rustpad {
       this.onSelection(e);
       cursorUpdate();
     });
+    this.onKeyDownHandle = options.editor.onKeyDown((e) => {
+      // Replace Monaco's undo, which would also revert other users' edits.
+      const { key, ctrlKey, metaKey, shiftKey } = e.browserEvent;
+      const lowerKey = key.toLowerCase();
+      if ((ctrlKey || metaKey) && (lowerKey === "z" || lowerKey === "y")) {
+        e.preventDefault();
+        e.stopPropagation();
+        if (lowerKey === "z" && !shiftKey) {
+          this.undo();
+        } else {
+          this.redo();
+        }
+      }
+    });
     this.beforeUnload = (event: BeforeUnloadEvent) => {
-      if (this.outstanding) {
+      if (this.ot.has_outstanding()) {
         event.preventDefault();
         event.returnValue = "";
       } else {
@@ -89,6 +135,7 @@ class Rustpad {
   dispose() {
     window.clearInterval(this.tryConnectId);
     window.clearInterval(this.resetFailuresId);
+    this.onKeyDownHandle.dispose();
     this.onSelectionHandle.dispose();
     this.onCursorHandle.dispose();
     this.onChangeHandle.dispose();
@@ -102,6 +149,16 @@ class Rustpad {
     return this.ws !== undefined;
   }
 
+  /** Undo the user's last change, leaving edits from others intact. */
+  undo() {
+    this.applyLocal(this.undoManager.undo(this.lastValue));
+  }
+
+  /** Redo the user's last undone change. */
+  redo() {
+    this.applyLocal(this.undoManager.redo(this.lastValue));
+  }
+
   /** Set the user's information. */
   setInfo(info: UserInfo) {
     this.myInfo = info;
@@ -129,17 +186,26 @@ class Rustpad {
       this.options.onConnected?.();
       this.users = {};
       this.options.onChangeUsers?.(this.users);
+      this.away = new Set();
+      this.options.onChangeAway?.(this.away);
       this.sendInfo();
       this.sendCursorData();
-      if (this.outstanding) {
-        this.sendOperation(this.outstanding);
+      const resend = this.ot.resend();
+      if (resend) {
+        this.ws?.send(resend);
       }
     };
     ws.onclose = () => {
+      const reason = this.closeReason;
+      this.closeReason = undefined;
       if (this.ws) {
         this.ws = undefined;
         this.options.onDisconnected?.();
-        if (++this.recentFailures >= 5) {
+        if (reason && !reconnectReasons.has(reason)) {
+          // Reconnecting would only find an empty document or the same error.
+          this.dispose();
+          this.options.onClosed?.(reason);
+        } else if (++this.recentFailures >= 5) {
           // If we disconnect 5 times within 15 reconnection intervals, then the
           // client is likely desynchronized and needs to refresh.
           this.dispose();
@@ -151,31 +217,41 @@ class Rustpad {
     };
     ws.onmessage = ({ data }) => {
       if (typeof data === "string") {
-        this.handleMessage(JSON.parse(data));
+        this.handleMessage(data);
       }
     };
   }
 
-  private handleMessage(msg: ServerMsg) {
-    if (msg.Identity !== undefined) {
-      this.me = msg.Identity;
-    } else if (msg.History !== undefined) {
-      const { start, operations } = msg.History;
-      if (start > this.revision) {
-        console.warn("History message has start greater than last operation.");
-        this.ws?.close();
-        return;
+  private handleMessage(data: string) {
+    const msg: ServerMsg = JSON.parse(data);
+    if (msg.Identity?.engine === "crdt" && !this.crdt) {
+      // Edits made before connecting were tracked for OT, so start over.
+      if (this.lastValue !== "") {
+        this.resetModel();
       }
-      for (let i = this.revision - start; i < operations.length; i++) {
-        let { id, operation } = operations[i];
-        this.revision++;
-        if (id === this.me) {
-          this.serverAck();
-        } else {
-          operation = OpSeq.from_str(JSON.stringify(operation));
-          this.applyServer(operation);
-        }
+      this.crdt = CrdtClient.new();
+    }
+
+    let steps: SyncStep[];
+    try {
+      steps = (this.crdt ?? this.ot).handle_message(data);
+    } catch (error) {
+      console.warn(`Failed to synchronize with server: ${error}`);
+      this.ws?.close();
+      return;
+    }
+    for (const step of steps) {
+      if (step.apply) {
+        this.applyOperation(step.apply);
+      } else if (step.send) {
+        this.ws?.send(step.send);
+      } else if (step.reset) {
+        this.resetModel();
       }
+    }
+
+    if (msg.Identity !== undefined) {
+      this.me = msg.Identity.id;
     } else if (msg.Language !== undefined) {
       this.options.onChangeLanguage?.(msg.Language);
     } else if (msg.UserInfo !== undefined) {
@@ -187,6 +263,11 @@ class Rustpad {
         } else {
           delete this.users[id];
           delete this.userCursors[id];
+          if (this.away.has(id)) {
+            this.away = new Set(this.away);
+            this.away.delete(id);
+            this.options.onChangeAway?.(this.away);
+          }
         }
         this.updateCursors();
         this.options.onChangeUsers?.(this.users);
@@ -197,52 +278,43 @@ class Rustpad {
         this.userCursors[id] = data;
         this.updateCursors();
       }
-    }
-  }
-
-  private serverAck() {
-    if (!this.outstanding) {
-      console.warn("Received serverAck with no outstanding operation.");
-      return;
-    }
-    this.outstanding = this.buffer;
-    this.buffer = undefined;
-    if (this.outstanding) {
-      this.sendOperation(this.outstanding);
-    }
-  }
-
-  private applyServer(operation: OpSeq) {
-    if (this.outstanding) {
-      const pair = this.outstanding.transform(operation)!;
-      this.outstanding = pair.first();
-      operation = pair.second();
-      if (this.buffer) {
-        const pair = this.buffer.transform(operation)!;
-        this.buffer = pair.first();
-        operation = pair.second();
+    } else if (msg.UserCursors !== undefined) {
+      for (const { id, data } of msg.UserCursors) {
+        if (id !== this.me) {
+          this.userCursors[id] = data;
+        }
+      }
+      this.updateCursors();
+    } else if (msg.UserStatus !== undefined) {
+      const { id, status } = msg.UserStatus;
+      this.away = new Set(this.away);
+      if (status === "away") {
+        this.away.add(id);
+      } else {
+        this.away.delete(id);
       }
+      this.options.onChangeAway?.(this.away);
+    } else if (msg.Checksum !== undefined && !this.crdt) {
+      // Checksums are only sent for documents that use OT.
+      const { revision, checksum } = msg.Checksum;
+      const report = this.ot.check(revision, checksum, this.lastValue);
+      if (report) {
+        console.warn(`Text diverged from server at revision ${revision}`);
+        this.ws?.send(report);
+      }
+    } else if (msg.Closing !== undefined) {
+      this.closeReason = msg.Closing.reason;
     }
-    this.applyOperation(operation);
   }
 
   private applyClient(operation: OpSeq) {
-    if (!this.outstanding) {
-      this.sendOperation(operation);
-      this.outstanding = operation;
-    } else if (!this.buffer) {
-      this.buffer = operation;
-    } else {
-      this.buffer = this.buffer.compose(operation);
+    const msg = (this.crdt ?? this.ot).apply_client(operation);
+    if (msg) {
+      this.ws?.send(msg);
     }
     this.transformCursors(operation);
   }
 
-  private sendOperation(operation: OpSeq) {
-    const op = operation.to_string();
-    this.ws?.send(`{"Edit":{"revision":${this.revision},"operation":${op}}}`);
-  }
-
   private sendInfo() {
     if (this.myInfo) {
       this.ws?.send(`{"ClientInfo":${JSON.stringify(this.myInfo)}}`);
@@ -250,23 +322,47 @@ class Rustpad {
   }
 
   private sendCursorData() {
-    if (!this.buffer) {
+    if (!this.ot.has_buffer()) {
       this.ws?.send(`{"CursorData":${JSON.stringify(this.cursorData)}}`);
     }
   }
 
+  /**
+   * Clear the editor before its text is rebuilt, after the server reloaded
+   * the document or sent a snapshot because the text diverged.
+   */
+  private resetModel() {
+    console.warn("Resynchronizing text with the server");
+    this.ignoreChanges = true;
+    this.model.setValue("");
+    this.lastValue = "";
+    this.ignoreChanges = false;
+    this.undoManager.clear();
+    this.userCursors = {};
+    this.updateCursors();
+  }
+
   private applyOperation(operation: OpSeq) {
     if (operation.is_noop()) return;
+    this.applyToModel(operation);
+    this.undoManager.transform(operation);
+    this.transformCursors(operation);
+  }
 
+  private applyLocal(operation?: OpSeq) {
+    if (!operation || operation.is_noop()) return;
+    this.applyToModel(operation);
+    this.applyClient(operation);
+  }
+
+  private applyToModel(operation: OpSeq) {
     this.ignoreChanges = true;
-    const ops: (string | number)[] = JSON.parse(operation.to_string());
     let index = 0;
 
-    for (const op of ops) {
-      if (typeof op === "string") {
-        // Insert
+    for (const op of operation.components()) {
+      if ("insert" in op) {
         const pos = unicodePosition(this.model, index);
-        index += unicodeLength(op);
+        index += unicodeLength(op.insert);
         this.model.pushEditOperations(
           this.options.editor.getSelections(),
           [
@@ -277,18 +373,16 @@ class Rustpad {
                 endLineNumber: pos.lineNumber,
                 endColumn: pos.column,
               },
-              text: op,
+              text: op.insert,
               forceMoveMarkers: true,
             },
           ],
           () => null,
         );
-      } else if (op >= 0) {
-        // Retain
-        index += op;
+      } else if ("retain" in op) {
+        index += op.retain;
       } else {
-        // Delete
-        const chars = -op;
+        const chars = op.delete;
         var from = unicodePosition(this.model, index);
         var to = unicodePosition(this.model, index + chars);
         this.model.pushEditOperations(
@@ -312,17 +406,25 @@ class Rustpad {
 
     this.lastValue = this.model.getValue();
     this.ignoreChanges = false;
-
-    this.transformCursors(operation);
   }
 
   private transformCursors(operation: OpSeq) {
     for (const data of Object.values(this.userCursors)) {
-      data.cursors = data.cursors.map((c) => operation.transform_index(c));
-      data.selections = data.selections.map(([s, e]) => [
-        operation.transform_index(s),
-        operation.transform_index(e),
-      ]);
+      const affinity = (a?: AffinityName) =>
+        a === "left" ? Affinity.Left : Affinity.Right;
+      data.cursors = data.cursors.map((c, i) =>
+        operation.transform_index_with_affinity(
+          c,
+          affinity(data.cursor_affinities?.[i]),
+        ),
+      );
+      data.selections = data.selections.map(([s, e], i) => {
+        const [a, b] = data.selection_affinities?.[i] ?? [];
+        return [
+          operation.transform_index_with_affinity(s, affinity(a)),
+          operation.transform_index_with_affinity(e, affinity(b)),
+        ];
+      });
     }
     this.updateCursors();
   }
@@ -408,6 +510,7 @@ class Rustpad {
         operation = operation.compose(changeOp)!;
         offset += changeOp.target_len() - changeOp.base_len();
       }
+      this.undoManager.add(operation, content);
       this.applyClient(operation);
       this.lastValue = this.model.getValue();
     }
@@ -424,6 +527,10 @@ class Rustpad {
       unicodeOffset(this.model, s.getStartPosition()),
       unicodeOffset(this.model, s.getEndPosition()),
     ]);
+    // Keep selections from growing when others type at their edges.
+    this.cursorData.selection_affinities = selections.map((s) =>
+      s.isEmpty() ? ["right", "right"] : ["right", "left"],
+    );
   }
 }
 
@@ -432,17 +539,45 @@ type UserOperation = {
   operation: any;
 };
 
+/** A step returned by `OtClient.handle_message()` or its CRDT counterpart. */
+type SyncStep = {
+  apply?: OpSeq;
+  send?: string;
+  reset?: boolean;
+};
+
+type AffinityName = "left" | "right";
+
 type CursorData = {
   cursors: number[];
   selections: [number, number][];
+  cursor_affinities?: AffinityName[];
+  selection_affinities?: [AffinityName, AffinityName][];
 };
 
 type ServerMsg = {
-  Identity?: number;
+  Identity?: {
+    id: number;
+    epoch: string;
+    engine?: "ot" | "crdt";
+    token?: string;
+  };
   History?: {
     start: number;
     operations: UserOperation[];
   };
+  CrdtEdit?: {
+    id: number;
+    ops: unknown[];
+  };
+  Checksum?: {
+    revision: number;
+    checksum: number;
+  };
+  Snapshot?: {
+    revision: number;
+    text: string;
+  };
   Language?: string;
   UserInfo?: {
     id: number;
@@ -452,6 +587,17 @@ type ServerMsg = {
     id: number;
     data: CursorData;
   };
+  UserCursors?: {
+    id: number;
+    data: CursorData;
+  }[];
+  UserStatus?: {
+    id: number;
+    status: "active" | "away";
+  };
+  Closing?: {
+    reason: CloseReason;
+  };
 };
 
 /** Returns the number of Unicode codepoints in a string. */